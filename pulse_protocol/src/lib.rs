@@ -0,0 +1,558 @@
+//! Messages exchanged between the pulse server and its clients.
+//!
+//! Each message is sent as a little endian u64 length followed by the json encoded message, wrapped
+//! in an `Envelope` that carries the protocol version and a sequence number. A client starts by
+//! sending `Hello` with the range of versions it supports, and the server responds with `Welcome`
+//! and the version it has chosen (or `Error` if there is none). The server does not send anything
+//! else to the client until the handshake has completed.
+//!
+//! The handshake is always encoded as json. The client can ask for a more compact `Encoding` in
+//! `Hello`, and all of the messages after `Welcome` (in both directions) use the encoding chosen by
+//! the server.
+//!
+//! Older clients are still supported: clients that send bare `ClientMessage`s (without an envelope)
+//! are sent bare `ServerMessage`s, and clients that only send bare `Command`s are sent bare
+//! `Pulse`s.
+
+extern crate common;
+extern crate serde;
+extern crate serde_cbor;
+#[macro_use] extern crate serde_derive;
+extern crate serde_json;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use common::{Command, Config};
+use common::signal::Pulse;
+use serde::{Serialize, Deserialize};
+
+/// The newest version of the protocol, and the version used by clients built with this crate
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The oldest version of the protocol that is still supported in an `Envelope`
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// How often the server sends `Heartbeat`s to clients that have completed the handshake. Clients
+/// can assume the connection has been lost if nothing has been received for a few intervals.
+///
+/// Clients must send something (e.g. `TimeRequest` or `GetStatus`) at least once per interval as
+/// well: the server disconnects clients that it hasn't received anything from for a few intervals.
+pub const HEARTBEAT_INTERVAL_SECS: u64 = 5;
+
+/// The encoding of the messages sent after the handshake
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Encoding {
+    Json,
+    /// Concise Binary Object Representation (RFC 7049), with the same structure as the json
+    /// encoding
+    Cbor,
+}
+
+impl Default for Encoding {
+    fn default() -> Encoding {
+        Encoding::Json
+    }
+}
+
+/// Encodes a value, appending it to the buffer
+pub fn encode<T: Serialize>(encoding: Encoding, value: &T, buffer: &mut Vec<u8>)
+    -> Result<(), String>
+{
+    match encoding {
+        Encoding::Json => serde_json::to_writer(buffer, value).map_err(|e| e.to_string()),
+        Encoding::Cbor => serde_cbor::ser::to_writer(buffer, value).map_err(|e| e.to_string()),
+    }
+}
+
+pub fn decode<T: Deserialize>(encoding: Encoding, bytes: &[u8]) -> Result<T, String> {
+    match encoding {
+        Encoding::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+        Encoding::Cbor => serde_cbor::de::from_slice(bytes).map_err(|e| e.to_string()),
+    }
+}
+
+/// Chooses the first of the encodings requested by a client that is supported
+pub fn negotiate_encoding(encodings: &[Encoding]) -> Encoding {
+    encodings.first().cloned().unwrap_or(Encoding::Json)
+}
+
+/// The length of the header at the start of each frame: a little endian u64 containing the length
+/// of the message that follows
+pub const FRAME_HEADER_LEN: usize = 8;
+
+/// Splits a stream of bytes into the messages (frames) that it contains. The bytes can be added in
+/// pieces of any size, e.g. as they are read from a non-blocking socket.
+pub struct FrameDecoder {
+    /// The maximum length of a message
+    max_size: usize,
+    /// Bytes that have been received but are not yet part of a complete frame
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new(max_size: usize) -> FrameDecoder {
+        FrameDecoder {
+            max_size: max_size,
+            buffer: vec![],
+        }
+    }
+
+    /// Adds bytes received from the stream
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Removes the next complete message from the received bytes, or returns `None` if more bytes
+    /// are needed. Returns an error if the length in the header is larger than the maximum, after
+    /// which the rest of the stream can't be decoded.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, String> {
+        if self.buffer.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+
+        let length = self.buffer[..FRAME_HEADER_LEN].iter().rev()
+            .fold(0, |length, &byte| (length << 8) | byte as u64);
+        if length > self.max_size as u64 {
+            return Err(format!("Message too large ({} bytes, the maximum is {} bytes)", length,
+                self.max_size));
+        }
+
+        let end = FRAME_HEADER_LEN + length as usize;
+        if self.buffer.len() < end {
+            return Ok(None);
+        }
+
+        let frame = self.buffer[FRAME_HEADER_LEN..end].to_vec();
+        self.buffer.drain(..end);
+        Ok(Some(frame))
+    }
+
+    /// The number of bytes received that are not yet part of a complete frame
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+/// A message along with the protocol version it was encoded with. The type of the message is the
+/// name of the variant (e.g. `{ "Pulse": { ... } }`).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub version: u32,
+    /// Counts the messages sent by each side of the connection, starting from 0. A gap in the
+    /// sequence means that messages were dropped.
+    pub seq: u64,
+    pub message: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(version: u32, seq: u64, message: T) -> Envelope<T> {
+        Envelope {
+            version: version,
+            seq: seq,
+            message: message,
+        }
+    }
+}
+
+/// Chooses the newest protocol version supported by both the server and a client
+pub fn negotiate_version(min_version: u32, max_version: u32) -> Option<u32> {
+    let version = if max_version < PROTOCOL_VERSION { max_version } else { PROTOCOL_VERSION };
+    if version >= min_version && version >= MIN_PROTOCOL_VERSION {
+        Some(version)
+    }
+    else {
+        None
+    }
+}
+
+/// A message sent from a client to the pulse server
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ClientMessage {
+    /// Starts the handshake, this must be the first message sent in an `Envelope`
+    Hello {
+        /// The range of protocol versions that the client supports
+        min_version: u32,
+        max_version: u32,
+        /// The encodings the client supports, in order of preference. Json is used if this is
+        /// empty.
+        #[serde(default)]
+        encodings: Vec<Encoding>,
+    },
+
+    /// A command for the pulse detection task. Commands are rejected (with an `Error`) unless the
+    /// client is allowed to control the detector, see `Role`.
+    Command(Command),
+
+    /// Changes the role of the client
+    SetRole(Role),
+
+    /// A request for the server's time, used to estimate the offset between the clocks
+    TimeRequest {
+        /// Identifies the request in the response
+        id: u64,
+        /// The time the request was sent, according to the client's clock
+        client_time: f64,
+    },
+
+    /// A request for the config that the server is currently using
+    GetConfig,
+
+    /// A request for the current state of the server
+    GetStatus,
+
+    /// Limits the pulses sent to the client, replacing any previous subscription. Clients receive
+    /// every pulse until they subscribe.
+    Subscribe(PulseFilter),
+}
+
+/// A message sent from the pulse server to a client
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ServerMessage {
+    /// Completes the handshake
+    Welcome {
+        /// The protocol version used for the rest of the connection
+        version: u32,
+        /// The encoding used for the rest of the connection
+        #[serde(default)]
+        encoding: Encoding,
+    },
+
+    /// Sent periodically so that clients can detect a lost connection
+    Heartbeat,
+
+    /// A message from the client could not be handled
+    Error {
+        message: String,
+    },
+
+    Pulse(Pulse),
+
+    TimeResponse {
+        id: u64,
+        /// The `client_time` of the request
+        client_time: f64,
+        /// The time the request was received, according to the server's clock
+        receive_time: f64,
+        /// The time the response was sent, according to the server's clock
+        transmit_time: f64,
+    },
+
+    /// The config that the server is currently using, sent in response to `GetConfig`
+    Config(Config),
+
+    /// The current state of the server, sent in response to `GetStatus`
+    Status(ServerStatus),
+
+    /// The subscription that the server is using for the client, sent in response to `Subscribe`
+    Subscribed(PulseFilter),
+
+    /// The role of the client, sent in response to `SetRole`
+    Role(Role),
+}
+
+/// Decides which clients can send commands to the detector, so that one client can't accidentally
+/// stop detection for the others. At most one client is the controller at a time.
+///
+/// Clients that have not set a role (including older clients) can send commands while there is no
+/// controller.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Role {
+    /// Can send commands, and stops any other client from sending them until it disconnects or
+    /// becomes an observer
+    Controller,
+    /// Can't send commands
+    Observer,
+}
+
+/// The frequency tolerance (in Hz) used by a `PulseFilter` that doesn't specify one
+pub const DEFAULT_FREQ_TOLERANCE: f32 = 1000.0;
+
+/// Selects the pulses that are sent to a client. A pulse is only sent if it meets all of the
+/// conditions that are set.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PulseFilter {
+    /// Only send pulses within `freq_tolerance` Hz of one of these frequencies (e.g. the collar
+    /// that is being tracked). Pulses of any frequency are sent if this is empty.
+    #[serde(default)]
+    pub freqs: Vec<f32>,
+    #[serde(default)]
+    pub freq_tolerance: Option<f32>,
+    #[serde(default)]
+    pub min_signal_strength: Option<f32>,
+    /// The maximum number of pulses sent each second. Pulses that arrive sooner than this allows
+    /// are not sent.
+    #[serde(default)]
+    pub max_rate: Option<f64>,
+}
+
+impl PulseFilter {
+    pub fn validate(&self) -> Result<(), String> {
+        for &freq in &self.freqs {
+            if !(freq > 0.0 && freq.is_finite()) {
+                return Err(format!("Invalid frequency `{}`", freq));
+            }
+        }
+        if let Some(tolerance) = self.freq_tolerance {
+            if !(tolerance >= 0.0 && tolerance.is_finite()) {
+                return Err(format!("Invalid frequency tolerance `{}`", tolerance));
+            }
+        }
+        if let Some(strength) = self.min_signal_strength {
+            if strength.is_nan() {
+                return Err("Invalid minimum signal strength".into());
+            }
+        }
+        if let Some(rate) = self.max_rate {
+            if !(rate > 0.0 && rate.is_finite()) {
+                return Err(format!("Invalid rate `{}`: the rate must be above 0", rate));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if the pulse matches the frequency and signal strength of the filter. The rate
+    /// limit depends on the pulses that have already been sent, so it is applied by the server.
+    pub fn matches(&self, pulse: &Pulse) -> bool {
+        let tolerance = self.freq_tolerance.unwrap_or(DEFAULT_FREQ_TOLERANCE);
+        if !self.freqs.is_empty() &&
+            !self.freqs.iter().any(|&freq| (pulse.freq - freq).abs() <= tolerance)
+        {
+            return false;
+        }
+
+        self.min_signal_strength.map_or(true, |strength| pulse.signal_strength >= strength)
+    }
+}
+
+/// The source of the samples that pulses are detected in
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Source {
+    HackRF,
+    /// Samples read from `signal.bin`, or fake pulses if the file does not exist
+    Test,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerStatus {
+    pub source: Source,
+    /// True while samples are being received and searched for pulses
+    pub streaming: bool,
+    /// The config that the detector is using, including any changes made by the automatic gain
+    /// control
+    pub config: Config,
+    /// The current LNA (IF) gain in dB
+    pub lna_gain: u32,
+    /// The current VGA (baseband) gain in dB
+    pub vga_gain: u32,
+    /// The number of times samples have been dropped by the receiver
+    pub overflow_count: u64,
+    /// The time since the server was started, in seconds
+    pub uptime: f64,
+    /// The number of clients connected to the server
+    pub clients: usize,
+    /// The last error that stopped the detector
+    pub last_error: Option<String>,
+    /// The number of messages that were dropped because a client's backlog was full
+    #[serde(default)]
+    pub dropped_messages: u64,
+    /// The number of clients that were disconnected because their backlog was full
+    #[serde(default)]
+    pub slow_clients: u64,
+    /// True if one of the clients is the controller
+    #[serde(default)]
+    pub controlled: bool,
+}
+
+/// The current time in seconds since the Unix epoch. This is the time base of the time sync
+/// messages.
+pub fn now() -> f64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(time) => time.as_secs() as f64 + time.subsec_nanos() as f64 / 1e9,
+        Err(_) => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{Command, Config};
+    use common::signal::{Pulse, Timestamp};
+    use serde::{Serialize, Deserialize};
+
+    use super::*;
+
+    /// Checks that a value decoded from the encoding has the same json encoding as the original
+    fn assert_round_trip<T: Serialize + Deserialize>(encoding: Encoding, value: &T) {
+        let mut json = vec![];
+        encode(Encoding::Json, value, &mut json).unwrap();
+
+        let mut encoded = vec![];
+        encode(encoding, value, &mut encoded).unwrap();
+        let decoded: T = decode(encoding, &encoded).unwrap();
+
+        let mut decoded_json = vec![];
+        encode(Encoding::Json, &decoded, &mut decoded_json).unwrap();
+        assert_eq!(String::from_utf8(json).unwrap(), String::from_utf8(decoded_json).unwrap());
+    }
+
+    fn server_messages() -> Vec<Envelope<ServerMessage>> {
+        let pulse = Pulse { freq: 150130000.0, signal_strength: 0.25, gain: 16,
+            timestamp: Timestamp::now() };
+
+        vec![
+            ServerMessage::Welcome { version: PROTOCOL_VERSION, encoding: Encoding::Cbor },
+            ServerMessage::Heartbeat,
+            ServerMessage::Error { message: "Invalid message".into() },
+            ServerMessage::Pulse(pulse),
+            ServerMessage::TimeResponse { id: 3, client_time: 1490000000.125,
+                receive_time: 1490000000.5, transmit_time: 1490000000.625 },
+            ServerMessage::Config(Config::default()),
+            ServerMessage::Status(ServerStatus {
+                source: Source::HackRF,
+                streaming: true,
+                config: Config::default(),
+                lna_gain: 16,
+                vga_gain: 20,
+                overflow_count: 2,
+                uptime: 3600.5,
+                clients: 3,
+                last_error: Some("Receiver disconnected".into()),
+                dropped_messages: 12,
+                slow_clients: 1,
+                controlled: true,
+            }),
+            ServerMessage::Subscribed(PulseFilter::default()),
+            ServerMessage::Role(Role::Controller),
+        ].into_iter().enumerate().map(|(i, message)| Envelope::new(1, i as u64, message)).collect()
+    }
+
+    fn client_messages() -> Vec<Envelope<ClientMessage>> {
+        vec![
+            ClientMessage::Hello { min_version: 1, max_version: 1,
+                encodings: vec![Encoding::Cbor, Encoding::Json] },
+            ClientMessage::Command(Command::Start(Some(Config::default()))),
+            ClientMessage::Command(Command::Stop),
+            ClientMessage::TimeRequest { id: 3, client_time: 1490000000.125 },
+            ClientMessage::GetConfig,
+            ClientMessage::GetStatus,
+            ClientMessage::Subscribe(PulseFilter { freqs: vec![150130000.0],
+                freq_tolerance: Some(500.0), min_signal_strength: None, max_rate: Some(2.0) }),
+            ClientMessage::SetRole(Role::Observer),
+        ].into_iter().enumerate().map(|(i, message)| Envelope::new(1, i as u64, message)).collect()
+    }
+
+    #[test]
+    fn cbor_matches_json() {
+        for message in server_messages() {
+            assert_round_trip(Encoding::Cbor, &message);
+        }
+        for message in client_messages() {
+            assert_round_trip(Encoding::Cbor, &message);
+        }
+    }
+
+    #[test]
+    fn cbor_is_smaller() {
+        let pulse = &server_messages()[3];
+
+        let mut json = vec![];
+        encode(Encoding::Json, pulse, &mut json).unwrap();
+        let mut cbor = vec![];
+        encode(Encoding::Cbor, pulse, &mut cbor).unwrap();
+        assert!(cbor.len() < json.len());
+    }
+
+    /// Encodes each message as a frame, returning the stream of bytes and the encoded messages
+    fn frames(messages: &[Envelope<ServerMessage>]) -> (Vec<u8>, Vec<Vec<u8>>) {
+        let mut stream = vec![];
+        let mut encoded = vec![];
+        for message in messages {
+            let mut buffer = vec![];
+            encode(Encoding::Json, message, &mut buffer).unwrap();
+            for i in 0..FRAME_HEADER_LEN {
+                stream.push((buffer.len() >> (8 * i)) as u8);
+            }
+            stream.extend_from_slice(&buffer);
+            encoded.push(buffer);
+        }
+        (stream, encoded)
+    }
+
+    #[test]
+    fn frames_split_into_chunks() {
+        let (stream, expected) = frames(&server_messages());
+
+        for chunk_size in 1..stream.len() + 1 {
+            let mut decoder = FrameDecoder::new(1 << 16);
+            let mut decoded = vec![];
+            for chunk in stream.chunks(chunk_size) {
+                decoder.extend(chunk);
+                while let Some(frame) = decoder.next_frame().unwrap() {
+                    decoded.push(frame);
+                }
+            }
+            assert_eq!(decoded, expected);
+            assert_eq!(decoder.pending(), 0);
+        }
+    }
+
+    #[test]
+    fn frames_split_at_any_point() {
+        let (stream, expected) = frames(&server_messages()[..2]);
+
+        for i in 0..stream.len() + 1 {
+            for j in i..stream.len() + 1 {
+                let mut decoder = FrameDecoder::new(1 << 16);
+                let mut decoded = vec![];
+                for part in &[&stream[..i], &stream[i..j], &stream[j..]] {
+                    decoder.extend(part);
+                    while let Some(frame) = decoder.next_frame().unwrap() {
+                        decoded.push(frame);
+                    }
+                }
+                assert_eq!(decoded, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn frame_too_large() {
+        let (stream, _) = frames(&server_messages());
+        let mut decoder = FrameDecoder::new(16);
+
+        // The header is checked as soon as it is complete, before the message has been received
+        decoder.extend(&stream[..FRAME_HEADER_LEN - 1]);
+        assert_eq!(decoder.next_frame(), Ok(None));
+        decoder.extend(&stream[FRAME_HEADER_LEN - 1..FRAME_HEADER_LEN]);
+        assert!(decoder.next_frame().is_err());
+    }
+
+    #[test]
+    fn pulse_filter() {
+        let pulse = |freq, signal_strength| Pulse { freq: freq, signal_strength: signal_strength,
+            gain: 16, timestamp: Timestamp::now() };
+
+        let all = PulseFilter::default();
+        assert!(all.matches(&pulse(150130000.0, 0.1)));
+
+        let collar = PulseFilter { freqs: vec![150130000.0, 151000000.0],
+            min_signal_strength: Some(0.2), ..PulseFilter::default() };
+        assert!(collar.matches(&pulse(150130500.0, 0.3)));
+        assert!(collar.matches(&pulse(151000000.0, 0.2)));
+        assert!(!collar.matches(&pulse(150130000.0, 0.1)));
+        assert!(!collar.matches(&pulse(150500000.0, 0.3)));
+
+        assert!(collar.validate().is_ok());
+        assert!(PulseFilter { max_rate: Some(0.0), ..PulseFilter::default() }.validate().is_err());
+        assert!(PulseFilter { freqs: vec![-1.0], ..PulseFilter::default() }.validate().is_err());
+    }
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(negotiate_version(1, 1), Some(1));
+        assert_eq!(negotiate_version(1, PROTOCOL_VERSION + 1), Some(PROTOCOL_VERSION));
+        assert_eq!(negotiate_version(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2), None);
+
+        assert_eq!(negotiate_encoding(&[]), Encoding::Json);
+        assert_eq!(negotiate_encoding(&[Encoding::Cbor, Encoding::Json]), Encoding::Cbor);
+    }
+}
@@ -1,6 +1,8 @@
 use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{self, TryRecvError};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use std::io::prelude::*;
 use std::io::{self, ErrorKind};
@@ -11,17 +13,67 @@ use mio::tcp::{TcpListener, TcpStream};
 
 use common::Command;
 use common::signal::Pulse;
+use pulse_protocol::{self, ClientMessage, Encoding, Envelope, FrameDecoder, PulseFilter, Role,
+    ServerMessage, ServerStatus};
 
-use task::TaskHandle;
+use task::{TaskHandle, TaskStatus};
 
+use serde::Serialize;
 use serde_json;
-use byteorder::{ByteOrder, ReadBytesExt, LittleEndian};
+use byteorder::{ByteOrder, LittleEndian};
 
 const SERVER_TOKEN: Token = Token(0);
 const PULSE_READY_EVENT: Token = Token(1);
 
-pub fn start_endpoint(task_handle: TaskHandle<Pulse, Command>) {
-    let TaskHandle { data_receiver, command_sender } = task_handle;
+/// What happens when a message is sent to a client whose backlog is full
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BacklogPolicy {
+    /// Drop the oldest unsent message. Clients that use envelopes can detect this from the gap in
+    /// the sequence numbers.
+    DropOldest,
+    /// Disconnect the client, so that it can reconnect and start again
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EndpointConfig {
+    /// The maximum number of messages waiting to be sent to each client
+    pub max_backlog: usize,
+    pub backlog_policy: BacklogPolicy,
+    /// The maximum length of a message sent by a client, in bytes
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: usize,
+    /// The number of heartbeat intervals that a client can go without sending anything before it
+    /// is disconnected. Only applies to clients that use envelopes, since older clients aren't
+    /// expected to send anything.
+    #[serde(default = "default_idle_heartbeats")]
+    pub idle_heartbeats: u64,
+}
+
+fn default_max_message_size() -> usize {
+    2000
+}
+
+fn default_idle_heartbeats() -> u64 {
+    6
+}
+
+impl Default for EndpointConfig {
+    fn default() -> EndpointConfig {
+        EndpointConfig {
+            max_backlog: 256,
+            backlog_policy: BacklogPolicy::DropOldest,
+            max_message_size: default_max_message_size(),
+            idle_heartbeats: default_idle_heartbeats(),
+        }
+    }
+}
+
+/// The number of bytes read from a client at a time
+const READ_CHUNK_SIZE: usize = 4096;
+
+pub fn start_endpoint(task_handle: TaskHandle<Pulse, Command>, config: EndpointConfig) {
+    let TaskHandle { data_receiver, command_sender, status } = task_handle;
     let poll = Poll::new().unwrap();
 
     // Spawn a thread to handle events from the task and forward them to the event loop.
@@ -43,7 +95,7 @@ pub fn start_endpoint(task_handle: TaskHandle<Pulse, Command>) {
 
     info!(target: "web_server", "Starting server");
 
-    let mut server = PulseServer::new(pulse_receiver, command_sender);
+    let mut server = PulseServer::new(pulse_receiver, command_sender, status, config);
     server.start_loop(poll);
 }
 
@@ -54,12 +106,24 @@ struct PulseServer {
     clients: HashMap<usize, PulseClient>,
     pulse_receiver: Receiver<Pulse>,
     command_sender: mpsc::Sender<Command>,
+    task_status: Arc<Mutex<TaskStatus>>,
+    config: EndpointConfig,
+    started: Instant,
+    last_heartbeat: Instant,
     buffer: Vec<u8>,
+    /// The number of messages dropped for clients that have since disconnected
+    dropped_messages: u64,
+    /// The number of clients that have been disconnected because their backlog was full
+    slow_clients: u64,
+    /// The client with the `Controller` role
+    controller: Option<usize>,
 }
 
 impl PulseServer {
     /// Create a new instance of the pulse server
-    fn new(pulse_receiver: Receiver<Pulse>, command_sender: mpsc::Sender<Command>) -> PulseServer {
+    fn new(pulse_receiver: Receiver<Pulse>, command_sender: mpsc::Sender<Command>,
+        task_status: Arc<Mutex<TaskStatus>>, config: EndpointConfig) -> PulseServer
+    {
         let addr = "0.0.0.0:11000".parse().unwrap();
         let listener = TcpListener::bind(&addr).unwrap();
 
@@ -69,7 +133,14 @@ impl PulseServer {
             clients: HashMap::new(),
             pulse_receiver: pulse_receiver,
             command_sender: command_sender,
+            task_status: task_status,
+            config: config,
+            started: Instant::now(),
+            last_heartbeat: Instant::now(),
             buffer: vec![],
+            dropped_messages: 0,
+            slow_clients: 0,
+            controller: None,
         }
     }
 
@@ -77,13 +148,28 @@ impl PulseServer {
     fn start_loop(&mut self, poll: Poll) {
         poll.register(&self.listener, SERVER_TOKEN, Ready::readable(), PollOpt::edge()).unwrap();
 
+        let heartbeat_interval = Duration::from_secs(pulse_protocol::HEARTBEAT_INTERVAL_SECS);
+
         let mut events = Events::with_capacity(1024);
         loop {
-            poll.poll(&mut events, None).unwrap();
+            let elapsed = self.last_heartbeat.elapsed();
+            let timeout = if elapsed < heartbeat_interval {
+                heartbeat_interval - elapsed
+            }
+            else {
+                Duration::from_secs(0)
+            };
+            poll.poll(&mut events, Some(timeout)).unwrap();
 
             for event in events.iter() {
                 self.handle_event(event, &poll);
             }
+
+            if self.last_heartbeat.elapsed() >= heartbeat_interval {
+                self.last_heartbeat = Instant::now();
+                self.remove_idle_clients(&poll);
+                self.broadcast(&ServerMessage::Heartbeat, &poll);
+            }
         }
     }
 
@@ -108,7 +194,7 @@ impl PulseServer {
 
                 if event.kind().is_hup() {
                     info!(target: "web_server", "Dropped Client: [{}]", id);
-                    self.clients.remove(&id);
+                    self.remove_client(id, poll);
                     return;
                 }
                 else if event.kind().is_readable() {
@@ -143,7 +229,7 @@ impl PulseServer {
         let id = self.next_client_id;
         self.next_client_id += 1;
 
-        self.clients.insert(id, PulseClient::new(socket, Token(id)));
+        self.clients.insert(id, PulseClient::new(socket, Token(id), &self.config));
 
         let result = poll.register(&self.clients[&id].connection, Token(id),
             Ready::readable() | Ready::hup(), PollOpt::edge());
@@ -156,8 +242,228 @@ impl PulseServer {
         }
     }
 
+    /// Removes a client from the event loop
+    fn remove_client(&mut self, id: usize, poll: &Poll) {
+        if let Some(client) = self.clients.remove(&id) {
+            let _ = poll.deregister(&client.connection);
+            self.dropped_messages += client.dropped_messages;
+        }
+        if self.controller == Some(id) {
+            info!(target: "web_server", "Controller [{}] disconnected", id);
+            self.controller = None;
+        }
+    }
+
+    /// Disconnects the clients that could not keep up with the messages sent to them
+    fn remove_slow_clients(&mut self, poll: &Poll) {
+        let slow: Vec<usize> = self.clients.iter()
+            .filter(|&(_, client)| client.slow)
+            .map(|(&id, _)| id)
+            .collect();
+
+        for id in slow {
+            warn!(target: "web_server", "Dropped Client: [{}] (backlog full)", id);
+            self.slow_clients += 1;
+            self.remove_client(id, poll);
+        }
+    }
+
+    /// Disconnects the clients that have not sent anything for too long, which are most likely
+    /// half-open connections
+    fn remove_idle_clients(&mut self, poll: &Poll) {
+        let now = Instant::now();
+        let idle: Vec<usize> = self.clients.iter()
+            .filter(|&(_, client)| client.is_idle(now))
+            .map(|(&id, _)| id)
+            .collect();
+
+        for id in idle {
+            warn!(target: "web_server", "Dropped Client: [{}] (idle)", id);
+            self.remove_client(id, poll);
+        }
+    }
+
+    /// Handle a message sent by a client
+    fn handle_message(&mut self, id: usize, message: ClientMessage, receive_time: f64,
+        poll: &Poll)
+    {
+        let protocol = match self.clients.get(&id) {
+            Some(client) => client.protocol,
+            None => return,
+        };
+
+        let message = match message {
+            ClientMessage::Hello { min_version, max_version, encodings } => {
+                self.handle_hello(id, min_version, max_version, &encodings, poll);
+                return;
+            },
+            message => message,
+        };
+
+        if protocol == Protocol::Handshake {
+            let error = ServerMessage::Error { message: "Expected `Hello`".into() };
+            self.send_message(id, &error, poll);
+            return;
+        }
+
+        match message {
+            ClientMessage::Hello { .. } => unreachable!(),
+
+            ClientMessage::Command(command) => self.handle_command(id, command, poll),
+
+            ClientMessage::SetRole(role) => self.handle_set_role(id, role, poll),
+
+            ClientMessage::TimeRequest { id: request_id, client_time } => {
+                let response = ServerMessage::TimeResponse {
+                    id: request_id,
+                    client_time: client_time,
+                    receive_time: receive_time,
+                    transmit_time: pulse_protocol::now(),
+                };
+                self.send_message(id, &response, poll);
+            },
+
+            ClientMessage::GetConfig => {
+                let config = self.task_status.lock().unwrap().config.clone();
+                self.send_message(id, &ServerMessage::Config(config), poll);
+            },
+
+            ClientMessage::GetStatus => {
+                let status = self.status();
+                self.send_message(id, &ServerMessage::Status(status), poll);
+            },
+
+            ClientMessage::Subscribe(filter) => {
+                let response = match filter.validate() {
+                    Ok(()) => {
+                        info!(target: "web_server", "Client [{}] subscribed to {:?}", id, filter);
+                        if let Some(client) = self.clients.get_mut(&id) {
+                            client.filter = filter.clone();
+                            client.last_pulse = None;
+                        }
+                        ServerMessage::Subscribed(filter)
+                    },
+                    Err(e) => ServerMessage::Error {
+                        message: format!("Invalid subscription: {}", e),
+                    },
+                };
+                self.send_message(id, &response, poll);
+            },
+        }
+    }
+
+    /// Completes the handshake with a client
+    fn handle_hello(&mut self, id: usize, min_version: u32, max_version: u32,
+        encodings: &[Encoding], poll: &Poll)
+    {
+        let version = match pulse_protocol::negotiate_version(min_version, max_version) {
+            Some(version) => version,
+            None => {
+                let error = ServerMessage::Error {
+                    message: format!("Unsupported protocol versions {} to {}, the server supports \
+                        {} to {}", min_version, max_version, pulse_protocol::MIN_PROTOCOL_VERSION,
+                        pulse_protocol::PROTOCOL_VERSION),
+                };
+                self.send_message(id, &error, poll);
+                return;
+            },
+        };
+        let encoding = pulse_protocol::negotiate_encoding(encodings);
+
+        info!(target: "web_server", "Client [{}] using protocol version {} ({:?})", id, version,
+            encoding);
+
+        if let Some(client) = self.clients.get_mut(&id) {
+            // The welcome is encoded as json, later messages use the chosen encoding
+            client.protocol = Protocol::Versioned(version);
+            client.encoding = Encoding::Json;
+
+            let welcome = ServerMessage::Welcome { version: version, encoding: encoding };
+            if client.queue(&welcome, &mut self.buffer) {
+                client.register_writable(poll);
+            }
+            client.encoding = encoding;
+        }
+    }
+
+    /// Returns the current state of the server
+    fn status(&self) -> ServerStatus {
+        let task_status = self.task_status.lock().unwrap().clone();
+        let uptime = self.started.elapsed();
+
+        ServerStatus {
+            source: task_status.source,
+            streaming: task_status.streaming,
+            lna_gain: task_status.config.hackrf_config.lna_gain,
+            vga_gain: task_status.config.hackrf_config.vga_gain,
+            config: task_status.config,
+            overflow_count: task_status.overflow_count,
+            uptime: uptime.as_secs() as f64 + uptime.subsec_nanos() as f64 / 1e9,
+            clients: self.clients.len(),
+            last_error: task_status.last_error,
+            dropped_messages: self.clients.values()
+                .fold(self.dropped_messages, |total, client| total + client.dropped_messages),
+            slow_clients: self.slow_clients,
+            controlled: self.controller.is_some(),
+        }
+    }
+
+    /// Adds a message to the backlog of a client
+    fn send_message(&mut self, id: usize, message: &ServerMessage, poll: &Poll) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            if client.queue(message, &mut self.buffer) {
+                client.register_writable(poll);
+            }
+        }
+        self.remove_slow_clients(poll);
+    }
+
+    /// Adds a message to the backlog of each of the clients that it can be sent to
+    fn broadcast(&mut self, message: &ServerMessage, poll: &Poll) {
+        for (_, client) in &mut self.clients {
+            if client.queue(message, &mut self.buffer) {
+                client.register_writable(poll);
+            }
+        }
+        self.remove_slow_clients(poll);
+    }
+
+    /// Changes the role of a client. Only one client can be the controller at a time.
+    fn handle_set_role(&mut self, id: usize, role: Role, poll: &Poll) {
+        let response = match (role, self.controller) {
+            (Role::Controller, Some(controller)) if controller != id => {
+                warn!(target: "web_server", "Client [{}] can't become the controller, client [{}] \
+                    is the controller", id, controller);
+                ServerMessage::Error {
+                    message: format!("Client [{}] is already the controller", controller),
+                }
+            },
+            _ => {
+                info!(target: "web_server", "Client [{}] is now a {:?}", id, role);
+                match role {
+                    Role::Controller => self.controller = Some(id),
+                    Role::Observer if self.controller == Some(id) => self.controller = None,
+                    Role::Observer => {},
+                }
+                if let Some(client) = self.clients.get_mut(&id) {
+                    client.role = Some(role);
+                }
+                ServerMessage::Role(role)
+            },
+        };
+        self.send_message(id, &response, poll);
+    }
+
     /// Handle a command sent by a client
-    fn handle_command(&mut self, command: Command) {
+    fn handle_command(&mut self, id: usize, command: Command, poll: &Poll) {
+        let role = self.clients.get(&id).and_then(|client| client.role);
+        if let Err(reason) = check_control(id, role, self.controller) {
+            warn!(target: "web_server", "Rejected command from client [{}]: {}", id, reason);
+            let error = ServerMessage::Error { message: format!("Command rejected: {}", reason) };
+            self.send_message(id, &error, poll);
+            return;
+        }
+
         let result = self.command_sender.send(command);
 
         if let Err(e) = result {
@@ -174,31 +480,21 @@ impl PulseServer {
             return;
         }
 
-        // Write pulse to internal buffer, reserving the first 8 bytes for the length of the
-        // encoded pulse.
-        self.buffer.resize(8, 0);
-        serde_json::to_writer(&mut self.buffer, &pulse).unwrap();
-
-        let length = self.buffer.len() as u64 - 8;
-        LittleEndian::write_u64(&mut self.buffer[..8], length);
-
-        // Add the pulse to the backlog of each of the clients and register them for write events.
+        // Add the pulse to the backlog of each of the clients that subscribed to it and register
+        // them for write events.
+        let message = ServerMessage::Pulse(pulse.clone());
         for (_, client) in &mut self.clients {
-            client.backlog.push_back(self.buffer.clone());
-
-            let result = poll.reregister(&client.connection, client.token,
-                Ready::writable() | Ready::readable() | Ready::hup(), PollOpt::edge());
-
-            if let Err(e) = result {
-                error!(target: "web_server", "Failed to reregister client for events: {}", e);
+            if client.wants_pulse(&pulse) && client.queue(&message, &mut self.buffer) {
+                client.register_writable(poll);
             }
         }
+        self.remove_slow_clients(poll);
     }
 
     /// Read something from the client
     fn read_from_client(&mut self, id: usize, poll: &Poll) {
         loop {
-            let command = match self.clients.get_mut(&id) {
+            let message = match self.clients.get_mut(&id) {
                 Some(client) => client.read_event(),
                 None => {
                     error!(target: "web_server", "Tried to read from missing client: [{}]", id);
@@ -206,62 +502,315 @@ impl PulseServer {
                 }
             };
 
-            match command {
-                Ok(Some(command)) => self.handle_command(command),
+            match message {
+                Ok(Some(message)) => {
+                    let receive_time = pulse_protocol::now();
+                    self.handle_message(id, message, receive_time, poll);
+                },
                 Ok(None) => {},
 
-                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    // Send any errors queued while reading
+                    if let Some(client) = self.clients.get_mut(&id) {
+                        if client.backlog.len() > 0 {
+                            client.register_writable(poll);
+                        }
+                    }
+                    break;
+                },
+                Err(ref e) if e.kind() == ErrorKind::InvalidData => {
+                    error!(target: "web_server", "Invalid frame from client [{}]: {}", id, e);
+                    self.close_client(id, &format!("Invalid frame: {}", e), poll);
+                    break;
+                },
                 Err(e) => {
                     error!(target: "web_server", "Error reading from client: {}", e);
 
                     // Kill bad client
                     warn!(target: "web_server", "Dropped Client: [{}]", id);
-                    let client = self.clients.remove(&id).unwrap();
-                    let _ = poll.deregister(&client.connection);
-                    drop(client);
+                    self.remove_client(id, poll);
+                    break;
                 }
             }
         }
+        self.remove_slow_clients(poll);
+    }
+
+    /// Sends an error to a client that can no longer be read from, and disconnects it once the
+    /// error has been sent
+    fn close_client(&mut self, id: usize, reason: &str, poll: &Poll) {
+        let result = match self.clients.get_mut(&id) {
+            Some(client) => {
+                client.closing = true;
+                client.queue(&ServerMessage::Error { message: reason.into() }, &mut self.buffer);
+                client.write_event(poll)
+            },
+            None => return,
+        };
+
+        let sent = self.clients.get(&id).map_or(true, |client| client.backlog.is_empty());
+        if result.is_err() || sent {
+            warn!(target: "web_server", "Dropped Client: [{}]", id);
+            self.remove_client(id, poll);
+        }
     }
 
     /// Write something to the client
     fn write_to_client(&mut self, id: usize, poll: &Poll) {
-        match self.clients.get_mut(&id) {
+        let result = match self.clients.get_mut(&id) {
             Some(client) => client.write_event(poll),
             None => {
                 error!(target: "web_server", "Tried to write event to missing client: [{}]", id);
+                return;
             }
+        };
+
+        if let Err(e) = result {
+            error!(target: "web_server", "Error writing to client [{}]: {}", id, e);
+            warn!(target: "web_server", "Dropped Client: [{}]", id);
+            self.remove_client(id, poll);
+            return;
         }
+
+        let closed = self.clients.get(&id)
+            .map_or(false, |client| client.closing && client.backlog.is_empty());
+        if closed {
+            warn!(target: "web_server", "Dropped Client: [{}]", id);
+            self.remove_client(id, poll);
+        }
+    }
+}
+
+/// Checks whether a client with a role is allowed to send commands while `controller` is the
+/// controller
+fn check_control(id: usize, role: Option<Role>, controller: Option<usize>) -> Result<(), String> {
+    match (role, controller) {
+        (Some(Role::Observer), _) => Err("observers can't send commands".into()),
+        (_, Some(controller)) if controller != id => {
+            Err(format!("client [{}] is the controller", controller))
+        },
+        _ => Ok(()),
     }
 }
 
+/// Writes a value to the buffer, preceded by the length of the encoded value
+fn encode<T: Serialize>(buffer: &mut Vec<u8>, encoding: Encoding, value: &T) {
+    // Reserve the first 8 bytes for the length of the encoded value
+    buffer.resize(8, 0);
+    pulse_protocol::encode(encoding, value, buffer).unwrap();
+
+    let length = buffer.len() as u64 - 8;
+    LittleEndian::write_u64(&mut buffer[..8], length);
+}
+
+/// The format of the messages exchanged with a client, determined by the first message the client
+/// sends.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Protocol {
+    /// Sends bare `Command`s and is sent bare `Pulse`s
+    Legacy,
+    /// Sends bare `ClientMessage`s and is sent bare `ServerMessage`s
+    Unversioned,
+    /// Has sent an `Envelope`, but has not completed the handshake
+    Handshake,
+    /// Has completed the handshake, and uses envelopes with the negotiated version
+    Versioned(u32),
+}
+
 struct PulseClient {
     connection: TcpStream,
     token: Token,
     backlog: VecDeque<Vec<u8>>,
+    /// The number of bytes of the first message in the backlog that have already been written
+    write_offset: usize,
+    max_backlog: usize,
+    backlog_policy: BacklogPolicy,
+    /// The number of messages dropped because the backlog was full
+    dropped_messages: u64,
+    /// Set when the backlog is full and the policy is to disconnect the client
+    slow: bool,
+    /// Set when the client is disconnected once its backlog has been sent
+    closing: bool,
+    decoder: FrameDecoder,
     buffer: Vec<u8>,
-    bytes_read: usize,
+    protocol: Protocol,
+    /// The encoding of the messages exchanged after the handshake
+    encoding: Encoding,
+    /// The sequence number of the next message sent in an envelope
+    next_seq: u64,
+    /// The role set by the client, if any
+    role: Option<Role>,
+    /// Selects the pulses sent to the client
+    filter: PulseFilter,
+    /// The time that the last pulse was sent to the client, used for the filter's rate limit
+    last_pulse: Option<Instant>,
+    /// The time that anything was last received from the client
+    last_receive: Instant,
+    /// The time without receiving anything before the client is disconnected
+    idle_timeout: Duration,
 }
 
 impl PulseClient {
-    fn new(connection: TcpStream, token: Token) -> PulseClient {
+    fn new(connection: TcpStream, token: Token, config: &EndpointConfig) -> PulseClient {
         PulseClient {
             connection: connection,
             token: token,
             backlog: VecDeque::new(),
-            buffer: vec![],
-            bytes_read: 0,
+            write_offset: 0,
+            max_backlog: config.max_backlog,
+            backlog_policy: config.backlog_policy,
+            dropped_messages: 0,
+            slow: false,
+            closing: false,
+            decoder: FrameDecoder::new(config.max_message_size),
+            buffer: vec![0; READ_CHUNK_SIZE],
+            protocol: Protocol::Legacy,
+            encoding: Encoding::Json,
+            next_seq: 0,
+            role: None,
+            filter: PulseFilter::default(),
+            last_pulse: None,
+            last_receive: Instant::now(),
+            idle_timeout: Duration::from_secs(
+                config.idle_heartbeats * pulse_protocol::HEARTBEAT_INTERVAL_SECS),
         }
     }
 
-    /// Reads a command from the current buffer as a json encoded string
-    fn read_buffer_json(&mut self) -> Option<Command> {
+    /// Returns true if the client should have sent something by now. Clients that don't use
+    /// envelopes are never idle.
+    fn is_idle(&self, now: Instant) -> bool {
+        match self.protocol {
+            Protocol::Handshake | Protocol::Versioned(_) => {
+                now.duration_since(self.last_receive) >= self.idle_timeout
+            },
+            Protocol::Legacy | Protocol::Unversioned => false,
+        }
+    }
+
+    /// Returns true if the pulse should be sent to the client according to its subscription
+    fn wants_pulse(&mut self, pulse: &Pulse) -> bool {
+        if !self.filter.matches(pulse) {
+            return false;
+        }
+
+        if let Some(max_rate) = self.filter.max_rate {
+            let now = Instant::now();
+            if let Some(last_pulse) = self.last_pulse {
+                let elapsed = now.duration_since(last_pulse);
+                let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+                if elapsed < 1.0 / max_rate {
+                    return false;
+                }
+            }
+            self.last_pulse = Some(now);
+        }
+
+        true
+    }
+
+    /// Adds a message to the client's backlog, encoded in the format the client understands.
+    /// Returns false if the message can not be sent to the client.
+    fn queue(&mut self, message: &ServerMessage, buffer: &mut Vec<u8>) -> bool {
+        if self.slow {
+            return false;
+        }
+
+        match (self.protocol, message) {
+            (Protocol::Legacy, &ServerMessage::Pulse(ref pulse)) => {
+                encode(buffer, Encoding::Json, pulse)
+            },
+            (Protocol::Legacy, _) => return false,
+
+            // These messages were added with the envelope, so older clients can't decode them
+            (Protocol::Unversioned, &ServerMessage::Welcome { .. }) |
+            (Protocol::Unversioned, &ServerMessage::Heartbeat) |
+            (Protocol::Unversioned, &ServerMessage::Error { .. }) => return false,
+            (Protocol::Unversioned, message) => encode(buffer, Encoding::Json, message),
+
+            // Nothing is sent until the handshake is complete, other than the reason it failed
+            (Protocol::Handshake, &ServerMessage::Error { .. }) => {
+                let envelope = Envelope::new(pulse_protocol::PROTOCOL_VERSION, self.next_seq,
+                    message);
+                encode(buffer, Encoding::Json, &envelope);
+                self.next_seq += 1;
+            },
+            (Protocol::Handshake, _) => return false,
+
+            (Protocol::Versioned(version), message) => {
+                encode(buffer, self.encoding, &Envelope::new(version, self.next_seq, message));
+                self.next_seq += 1;
+            },
+        }
+
+        if self.backlog.len() >= self.max_backlog {
+            self.dropped_messages += 1;
+            match self.backlog_policy {
+                BacklogPolicy::DropOldest => {
+                    // A message that has been partially written must be finished, otherwise the
+                    // client would lose track of where the next message starts
+                    let index = if self.write_offset > 0 { 1 } else { 0 };
+                    self.backlog.remove(index);
+                    warn!(target: "web_server", "Backlog of client [{}] is full, dropped a message",
+                        self.token.0);
+                },
+                BacklogPolicy::Disconnect => {
+                    self.slow = true;
+                    return false;
+                },
+            }
+        }
+
+        self.backlog.push_back(buffer.clone());
+        true
+    }
+
+    /// Registers the client for write events, so that its backlog is sent
+    fn register_writable(&self, poll: &Poll) {
+        let result = poll.reregister(&self.connection, self.token,
+            Ready::writable() | Ready::readable() | Ready::hup(), PollOpt::edge());
+
+        if let Err(e) = result {
+            error!(target: "web_server", "Failed to reregister client for events: {}", e);
+        }
+    }
+
+    /// Reads a message from a frame sent by the client, using the client's encoding
+    fn read_frame(&mut self, frame: &[u8]) -> Option<ClientMessage> {
         let id = self.token.0;
 
-        match serde_json::from_slice(&self.buffer) {
+        let envelope: Result<Envelope<ClientMessage>, _> =
+            pulse_protocol::decode(self.encoding, frame);
+        match envelope {
+            Ok(envelope) => {
+                info!(target: "web_server", "Read message from client [{}]: {:?}", id, envelope);
+                if let Protocol::Legacy = self.protocol {
+                    self.protocol = Protocol::Handshake;
+                }
+                return Some(envelope.message);
+            },
+            // Clients that have sent an envelope are expected to keep using them
+            Err(e) => match self.protocol {
+                Protocol::Handshake | Protocol::Versioned(_) => {
+                    error!(target: "web_server", "Failed to parse message from client [{}]: {}",
+                        id, e);
+                    let error = ServerMessage::Error { message: format!("Invalid message: {}", e) };
+                    self.queue(&error, &mut vec![]);
+                    return None;
+                },
+                Protocol::Legacy | Protocol::Unversioned => {},
+            },
+        }
+
+        if let Ok(message) = serde_json::from_slice(frame) {
+            info!(target: "web_server", "Read message from client [{}]: {:?}", id, message);
+            self.protocol = Protocol::Unversioned;
+            return Some(message);
+        }
+
+        match serde_json::from_slice(frame) {
             Ok(command) => {
                 info!(target: "web_server", "Read command from client [{}]: {:?}", id, command);
-                Some(command)
+                Some(ClientMessage::Command(command))
             },
             Err(e) => {
                 error!(target: "web_server", "Failed to parse command from client [{}]: {}", id, e);
@@ -270,62 +819,301 @@ impl PulseClient {
         }
     }
 
-    /// Handles read events for the client
-    fn read_event(&mut self) -> io::Result<Option<Command>> {
-        if self.buffer.len() == 0 {
-            // The first u64 in the stream contains the length of the encoded command
-            let length = try!(self.connection.read_u64::<LittleEndian>()) as usize;
+    /// Handles read events for the client, reading until a complete message has been received.
+    /// Returns `WouldBlock` once there is nothing left to read, and `InvalidData` if the stream
+    /// can't be split into messages.
+    fn read_event(&mut self) -> io::Result<Option<ClientMessage>> {
+        if self.closing {
+            return Err(io::Error::new(ErrorKind::WouldBlock, "Client is closing"));
+        }
 
-            if length > 2000 {
-                // Limit maximum message size to 2000 bytes
-                return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                    "Bad Request (Invalid Length)"));
+        loop {
+            match self.decoder.next_frame() {
+                Ok(Some(frame)) => return Ok(self.read_frame(&frame)),
+                Ok(None) => {},
+                Err(e) => return Err(io::Error::new(ErrorKind::InvalidData, e)),
             }
 
-            self.buffer.resize(length, 0);
-            self.bytes_read = 0;
+            let length = try!(self.connection.read(&mut self.buffer));
+            if length == 0 {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "Connection closed"));
+            }
+            self.last_receive = Instant::now();
+            self.decoder.extend(&self.buffer[..length]);
         }
+    }
+
+    /// Handles write events for the client, writing as much of the backlog as the connection will
+    /// accept
+    fn write_event(&mut self, poll: &Poll) -> io::Result<()> {
+        loop {
+            let written = match self.backlog.front() {
+                Some(message) => match self.connection.write(&message[self.write_offset..]) {
+                    Ok(0) => {
+                        return Err(io::Error::new(ErrorKind::WriteZero, "Failed to write message"));
+                    },
+                    Ok(written) => written,
 
-        // Read the encoded data
-        self.bytes_read += try!(self.connection.read(&mut self.buffer[self.bytes_read..]));
+                    // The stream is not ready to be written to yet, the rest of the backlog is
+                    // written on the next write event
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => 0,
+                    Err(e) => return Err(e),
+                },
+                None => break,
+            };
 
-        // If all the data has been read, then parse the result
-        if self.bytes_read == self.buffer.len() {
-            let result = Ok(self.read_buffer_json());
-            self.buffer.clear();
-            result
+            // Messages are only removed from the backlog once all of their bytes have been written
+            self.write_offset += written;
+            if self.write_offset == self.backlog.front().map_or(0, |message| message.len()) {
+                self.backlog.pop_front();
+                self.write_offset = 0;
+            }
         }
-        else {
-            Ok(None)
+
+        // We have finished writing our backlog, so unregister for write events.
+        poll.reregister(&self.connection, self.token, Ready::readable() | Ready::hup(),
+            PollOpt::edge())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::io::ErrorKind;
+    use std::net;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+    use mio::*;
+    use mio::tcp::TcpStream;
+    use pulse_protocol::{self, ClientMessage, Encoding, Envelope, Role, ServerMessage};
+
+    use super::{BacklogPolicy, EndpointConfig, PulseClient, Protocol, check_control};
+
+    /// Returns a client that has completed the handshake, and the other end of its connection
+    fn connect(max_backlog: usize, policy: BacklogPolicy) -> (PulseClient, net::TcpStream) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let connection = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+
+        let config = EndpointConfig {
+            max_backlog: max_backlog,
+            backlog_policy: policy,
+            ..EndpointConfig::default()
+        };
+        let mut client = PulseClient::new(connection, Token(2), &config);
+        client.protocol = Protocol::Versioned(pulse_protocol::PROTOCOL_VERSION);
+        (client, peer)
+    }
+
+    /// Reads messages from the connection until it is closed, a few bytes at a time so that the
+    /// sender's writes are only partially accepted
+    fn read_throttled(peer: net::TcpStream) -> thread::JoinHandle<Vec<Envelope<ServerMessage>>> {
+        thread::spawn(move || {
+            let mut reader = ThrottledReader { inner: peer };
+            let mut messages = vec![];
+            while let Ok(length) = reader.read_u64::<LittleEndian>() {
+                let mut buffer = vec![0; length as usize];
+                reader.read_exact(&mut buffer).unwrap();
+                messages.push(pulse_protocol::decode(Encoding::Json, &buffer).unwrap());
+            }
+            messages
+        })
+    }
+
+    struct ThrottledReader {
+        inner: net::TcpStream,
+    }
+
+    impl Read for ThrottledReader {
+        fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+            thread::sleep(Duration::from_millis(1));
+            let length = if buf.len() < 16384 { buf.len() } else { 16384 };
+            self.inner.read(&mut buf[..length])
+        }
+    }
+
+    /// Writes the client's backlog to its connection, then closes the connection
+    fn write_backlog(mut client: PulseClient) {
+        let poll = Poll::new().unwrap();
+        poll.register(&client.connection, client.token, Ready::writable(), PollOpt::edge())
+            .unwrap();
+
+        let mut events = Events::with_capacity(16);
+        while !client.backlog.is_empty() {
+            poll.poll(&mut events, Some(Duration::from_secs(5))).unwrap();
+            client.write_event(&poll).unwrap();
         }
     }
 
-    /// Handles write events for the client
-    fn write_event(&mut self, poll: &Poll) {
-        while self.backlog.len() > 0 {
-            match self.connection.write(&self.backlog.front().unwrap()) {
-                // Successfully wrote the message to the client stream, so we can remove it from our
-                // backlog.
-                Ok(_) => { self.backlog.pop_front(); }
+    fn error(size: usize) -> ServerMessage {
+        ServerMessage::Error { message: (0..size).map(|_| 'x').collect() }
+    }
 
-                // The stream is not read to be written to yet, so stop any subsequent writes
-                Err(ref e) if e.kind() == ErrorKind::WouldBlock => { break },
+    #[test]
+    fn partial_writes() {
+        let (mut client, peer) = connect(1000, BacklogPolicy::DropOldest);
+        let reader = read_throttled(peer);
 
-                // Some other error occured.
-                Err(e) => error!("An unhandled error occured when writing to a client [{}]: {}",
-                    self.token.0, e),
+        // Much more than the socket buffers can hold, so most writes are partial
+        let mut buffer = vec![];
+        for _ in 0..128 {
+            assert!(client.queue(&error(65536), &mut buffer));
+        }
+        write_backlog(client);
+
+        let messages = reader.join().unwrap();
+        assert_eq!(messages.len(), 128);
+        for (i, envelope) in messages.iter().enumerate() {
+            assert_eq!(envelope.seq, i as u64);
+            match envelope.message {
+                ServerMessage::Error { ref message } => assert_eq!(message.len(), 65536),
+                ref message => panic!("Unexpected message: {:?}", message),
             }
         }
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let (mut client, peer) = connect(4, BacklogPolicy::DropOldest);
+        let reader = read_throttled(peer);
+
+        let mut buffer = vec![];
+        for _ in 0..10 {
+            assert!(client.queue(&ServerMessage::Heartbeat, &mut buffer));
+        }
+        assert_eq!(client.backlog.len(), 4);
+        assert_eq!(client.dropped_messages, 6);
+        assert!(!client.slow);
+        write_backlog(client);
+
+        let seqs: Vec<u64> = reader.join().unwrap().iter().map(|envelope| envelope.seq).collect();
+        assert_eq!(seqs, vec![6, 7, 8, 9]);
+    }
 
-        if self.backlog.len() > 0 {
-            // We have finished writing our backlog, so unregister for write events.
-            let result = poll.reregister(&self.connection, self.token, Ready::readable() |
-                Ready::hup(), PollOpt::edge());
+    #[test]
+    fn drop_oldest_keeps_partial_message() {
+        let (mut client, _peer) = connect(2, BacklogPolicy::DropOldest);
 
-            if let Err(e) = result {
-                error!(target: "web_server", "Failed to reregister client [{}]: {}",
-                    self.token.0, e);
+        // Fill the socket buffers so that the first message can only be partially written
+        let poll = Poll::new().unwrap();
+        poll.register(&client.connection, client.token, Ready::writable(), PollOpt::edge())
+            .unwrap();
+        let mut buffer = vec![];
+        while client.backlog.len() < 2 || client.write_offset == 0 {
+            client.queue(&error(1 << 20), &mut buffer);
+            client.write_event(&poll).unwrap();
+        }
+        let first = client.backlog[0].clone();
+        let dropped = client.dropped_messages;
+
+        client.queue(&ServerMessage::Heartbeat, &mut buffer);
+        assert_eq!(client.backlog.len(), 2);
+        assert_eq!(client.backlog[0], first);
+        assert_eq!(client.dropped_messages, dropped + 1);
+    }
+
+    #[test]
+    fn disconnect_slow_client() {
+        let (mut client, _peer) = connect(4, BacklogPolicy::Disconnect);
+
+        let mut buffer = vec![];
+        for _ in 0..4 {
+            assert!(client.queue(&ServerMessage::Heartbeat, &mut buffer));
+        }
+        assert!(!client.slow);
+
+        assert!(!client.queue(&ServerMessage::Heartbeat, &mut buffer));
+        assert!(client.slow);
+        assert_eq!(client.dropped_messages, 1);
+        assert_eq!(client.backlog.len(), 4);
+    }
+
+    /// Reads from the client until a message has been received, returning the number of reads
+    /// that would have blocked
+    fn read_message(client: &mut PulseClient) -> (Option<ClientMessage>, usize) {
+        let mut blocked = 0;
+        loop {
+            match client.read_event() {
+                Ok(Some(message)) => return (Some(message), blocked),
+                Ok(None) => return (None, blocked),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    blocked += 1;
+                    thread::sleep(Duration::from_millis(10));
+                },
+                Err(e) => panic!("Failed to read from client: {}", e),
             }
         }
     }
+
+    #[test]
+    fn partial_header() {
+        let (mut client, mut peer) = connect(4, BacklogPolicy::DropOldest);
+
+        let mut message = vec![];
+        let envelope = Envelope::new(1, 0, ClientMessage::GetStatus);
+        pulse_protocol::encode(Encoding::Json, &envelope, &mut message).unwrap();
+        let mut frame = vec![];
+        frame.write_u64::<LittleEndian>(message.len() as u64).unwrap();
+        frame.extend_from_slice(&message);
+
+        let writer = thread::spawn(move || {
+            // Send the frame in pieces that split the header
+            for part in &[&frame[..3], &frame[3..10], &frame[10..]] {
+                peer.write_all(part).unwrap();
+                peer.flush().unwrap();
+                thread::sleep(Duration::from_millis(50));
+            }
+            peer
+        });
+
+        match read_message(&mut client) {
+            (Some(ClientMessage::GetStatus), blocked) => assert!(blocked > 0),
+            (message, _) => panic!("Unexpected message: {:?}", message),
+        }
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn message_too_large() {
+        let (mut client, mut peer) = connect(4, BacklogPolicy::DropOldest);
+
+        peer.write_u64::<LittleEndian>(1 << 20).unwrap();
+        peer.flush().unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        match client.read_event() {
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => {},
+            result => panic!("Expected an invalid frame, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn idle_client() {
+        let (mut client, _peer) = connect(10, BacklogPolicy::DropOldest);
+        let now = Instant::now();
+        assert!(!client.is_idle(now));
+
+        client.last_receive = now - client.idle_timeout;
+        assert!(client.is_idle(now));
+
+        // Clients that don't use envelopes aren't expected to send anything
+        client.protocol = Protocol::Legacy;
+        assert!(!client.is_idle(now));
+    }
+
+    #[test]
+    fn command_arbitration() {
+        // Without a controller, only observers are rejected
+        assert!(check_control(2, None, None).is_ok());
+        assert!(check_control(2, Some(Role::Controller), None).is_ok());
+        assert!(check_control(2, Some(Role::Observer), None).is_err());
+
+        // With a controller, only the controller is allowed
+        assert!(check_control(2, Some(Role::Controller), Some(2)).is_ok());
+        assert!(check_control(3, None, Some(2)).is_err());
+        assert!(check_control(3, Some(Role::Observer), Some(2)).is_err());
+    }
 }
@@ -14,10 +14,11 @@ use gain_control::GainControl;
 use animal_detector::Detectors;
 
 use hackrf::{self, HackRF, HackRFResult};
-use task::{init_task, Task, TaskHandle};
+use pulse_protocol::Source;
+use task::{init_task, Task, TaskHandle, TaskStatus};
 
 pub fn start_task(config: Config) -> TaskHandle<Pulse, Command> {
-    let (mut task, task_handle) = init_task();
+    let (mut task, task_handle) = init_task(TaskStatus::new(Source::HackRF, config.clone()));
 
     info!(target: "hackrf_task", "Starting HackRF task");
     thread::spawn(move|| {
@@ -26,6 +27,11 @@ pub fn start_task(config: Config) -> TaskHandle<Pulse, Command> {
 
             if let Err(e) = run_hackrf(&mut task, config.clone()) {
                 error!(target: "hackrf_task", "HackRF task failure: {}", e);
+                {
+                    let mut status = task.status.lock().unwrap();
+                    status.streaming = false;
+                    status.last_error = Some(e.to_string());
+                }
                 thread::sleep(Duration::from_secs(10));
             }
         }
@@ -96,9 +102,11 @@ impl<'a> HackRFTask<'a> {
             self.detectors = Detectors::new(&config);
             self.config = config;
         };
+        self.task.status.lock().unwrap().config = self.config.clone();
 
         try!(self.configure());
         let mut rx_stream = try!(self.device.rx_stream(5));
+        self.task.status.lock().unwrap().streaming = true;
 
         let mut log_file = None;
         if let Some(ref filename) = self.config.hackrf_config.raw_log {
@@ -127,6 +135,7 @@ impl<'a> HackRFTask<'a> {
                     if overflow_count != self.device.overflow_count() {
                         warn!(target: "hackrf_task", "Samples dropped: {}", overflow_count);
                         overflow_count = self.device.overflow_count();
+                        self.task.status.lock().unwrap().overflow_count = overflow_count as u64;
                     }
 
                     // Write to log file (if log file was specified)
@@ -156,6 +165,7 @@ impl<'a> HackRFTask<'a> {
             }
         }
 
+        self.task.status.lock().unwrap().streaming = false;
         try!(rx_stream.stop());
         Ok(output_command)
     }
@@ -7,6 +7,7 @@ extern crate hackrf;
 #[macro_use] extern crate log;
 extern crate log4rs;
 extern crate mio;
+extern crate pulse_protocol;
 extern crate serde;
 #[macro_use] extern crate serde_derive;
 extern crate serde_json;
@@ -25,11 +26,12 @@ fn main() {
 
     log4rs::init_file("config/log_config.json", Default::default()).unwrap();
     let config = util::load_json_or_default("config/hackrf_config.json");
+    let endpoint_config = util::load_json_or_default("config/endpoint_config.json");
 
     if run_test_task {
-        endpoint::start_endpoint(test_task::start_task(config));
+        endpoint::start_endpoint(test_task::start_task(config), endpoint_config);
     }
     else {
-        endpoint::start_endpoint(hackrf_task::start_task(config));
+        endpoint::start_endpoint(hackrf_task::start_task(config), endpoint_config);
     }
 }
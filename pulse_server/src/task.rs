@@ -1,28 +1,58 @@
-
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{channel, Sender, Receiver};
 
+use common::Config;
+use pulse_protocol::Source;
+
+/// The state of a task, shared with the endpoint so that it can be reported to clients
+#[derive(Clone)]
+pub struct TaskStatus {
+    pub source: Source,
+    pub streaming: bool,
+    pub config: Config,
+    pub overflow_count: u64,
+    pub last_error: Option<String>,
+}
+
+impl TaskStatus {
+    pub fn new(source: Source, config: Config) -> TaskStatus {
+        TaskStatus {
+            source: source,
+            streaming: false,
+            config: config,
+            overflow_count: 0,
+            last_error: None,
+        }
+    }
+}
+
 pub struct TaskHandle<T, C> {
     pub data_receiver: Receiver<T>,
     pub command_sender: Sender<C>,
+    pub status: Arc<Mutex<TaskStatus>>,
 }
 
 pub struct Task<T, C> {
     pub data_sender: Sender<T>,
     pub command_receiver: Receiver<C>,
+    pub status: Arc<Mutex<TaskStatus>>,
 }
 
-pub fn init_task<T, C>() -> (Task<T, C>, TaskHandle<T, C>) {
+pub fn init_task<T, C>(status: TaskStatus) -> (Task<T, C>, TaskHandle<T, C>) {
     let (data_sender, data_receiver) = channel();
     let (command_sender, command_receiver) = channel();
+    let status = Arc::new(Mutex::new(status));
 
     let task = Task {
         data_sender: data_sender,
         command_receiver: command_receiver,
+        status: status.clone(),
     };
 
     let task_handle = TaskHandle {
         data_receiver: data_receiver,
         command_sender: command_sender,
+        status: status,
     };
 
     (task, task_handle)
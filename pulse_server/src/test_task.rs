@@ -11,12 +11,13 @@ use std::io::{Read, BufReader};
 use common::{Config, Command};
 use common::signal::*;
 
-use task::{init_task, Task, TaskHandle};
+use pulse_protocol::Source;
+use task::{init_task, Task, TaskHandle, TaskStatus};
 
 use animal_detector::Detectors;
 
 pub fn start_task(config: Config) -> TaskHandle<Pulse, Command> {
-    let (mut task, task_handle) = init_task();
+    let (mut task, task_handle) = init_task(TaskStatus::new(Source::Test, config.clone()));
 
     info!(target: "hackrf_task", "Starting test task");
     thread::spawn(move|| {
@@ -25,6 +26,11 @@ pub fn start_task(config: Config) -> TaskHandle<Pulse, Command> {
 
             if let Err(e) = run_test(&mut task, config.clone()) {
                 error!(target: "hackrf_task", "Test task failure: {}", e);
+                {
+                    let mut status = task.status.lock().unwrap();
+                    status.streaming = false;
+                    status.last_error = Some(e.to_string());
+                }
                 thread::sleep(Duration::from_secs(10));
             }
         }
@@ -59,8 +65,16 @@ impl<'a> TestTask<'a> {
     fn receiver_loop(&mut self, config: Option<Config>) -> Result<Command, Box<Error>> {
         if let Some(config) = config {
             self.detectors = Detectors::new(&config);
+            self.task.status.lock().unwrap().config = config;
         }
 
+        self.task.status.lock().unwrap().streaming = true;
+        let result = self.receive();
+        self.task.status.lock().unwrap().streaming = false;
+        result
+    }
+
+    fn receive(&mut self) -> Result<Command, Box<Error>> {
         let command_receiver = &mut self.task.command_receiver;
 
         if let Ok(file) = File::open("signal.bin") {
@@ -0,0 +1,33 @@
+//! Helpers for reporting errors from the REST API
+
+use rocket::http::Status;
+use rocket::response::status;
+use rocket_contrib::JSON;
+
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+/// The result of a REST request that may fail with a reason
+pub type ApiResult<T> = Result<JSON<T>, status::Custom<JSON<ApiError>>>;
+
+pub fn error<T, E: Into<String>>(status: Status, error: E) -> ApiResult<T> {
+    Err(status::Custom(status, JSON(ApiError { error: error.into() })))
+}
+
+pub fn bad_request<T, E: Into<String>>(reason: E) -> ApiResult<T> {
+    error(Status::BadRequest, reason)
+}
+
+pub fn not_found<T, E: Into<String>>(reason: E) -> ApiResult<T> {
+    error(Status::NotFound, reason)
+}
+
+pub fn unavailable<T, E: Into<String>>(reason: E) -> ApiResult<T> {
+    error(Status::ServiceUnavailable, reason)
+}
+
+pub fn conflict<T, E: Into<String>>(reason: E) -> ApiResult<T> {
+    error(Status::Conflict, reason)
+}
@@ -0,0 +1,419 @@
+//! Conversion from the clocks of the pulse server and the autopilot to the host's clock. All
+//! times recorded by the telemetry host (telemetry, pulses and tracks) are host times, in seconds
+//! since the Unix epoch.
+//!
+//! The offset to each remote clock is estimated in one of two ways:
+//!
+//!  - Round trip time sync: the host sends a request with its time, and the remote replies with
+//!    its own time. Assuming that the delay is the same in both directions gives the offset, with
+//!    an uncertainty of half the round trip time. The pulse server supports this with
+//!    `TimeRequest` messages, and the autopilot with Mavlink TIMESYNC messages.
+//!
+//!  - One way delay: the difference between the time a message was sent (according to the remote
+//!    clock) and the time it was received. The delay only ever makes this difference larger, so
+//!    the smallest difference over a recent window is used as the estimate. This is used until
+//!    round trip samples are available, e.g. when connected to an older pulse server.
+
+use std::collections::VecDeque;
+use std::f64;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use common::signal::Pulse;
+
+/// The number of round trip samples kept for each remote clock
+const SYNC_WINDOW: usize = 32;
+
+/// The number of one way samples kept for each remote clock
+const DELAY_WINDOW: usize = 50;
+
+/// The minimum time spanned by the round trip samples before the drift between the clocks is
+/// estimated
+const MIN_DRIFT_SPAN_SECS: f64 = 30.0;
+
+/// How far a remote time can go backwards (e.g. from messages arriving out of order) before the
+/// remote clock is assumed to have been reset, e.g. by the autopilot rebooting
+const MAX_BACKWARDS_SECS: f64 = 1.0;
+
+/// How the offset to a remote clock was estimated
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub enum SyncMethod {
+    RoundTrip,
+    OneWayDelay,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RemoteClockStatus {
+    pub method: Option<SyncMethod>,
+    /// The estimated offset (in seconds) to add to a remote time to get the host time
+    pub offset: Option<f64>,
+    /// The rate that the remote clock drifts from the host clock, in parts per million
+    pub drift_ppm: Option<f64>,
+    /// The smallest round trip time (in seconds) of the recent time sync requests
+    pub round_trip: Option<f64>,
+    pub round_trip_samples: usize,
+    pub one_way_samples: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClockStatus {
+    pub pulse_server: RemoteClockStatus,
+    pub autopilot: RemoteClockStatus,
+    /// The difference (in seconds) between the autopilot's Unix time (from SYSTEM_TIME, usually
+    /// derived from GPS) and the host's clock
+    pub autopilot_unix_offset: Option<f64>,
+}
+
+#[derive(Copy, Clone)]
+struct SyncSample {
+    /// The host time of the sample
+    time: f64,
+    /// The offset to add to the remote time to get the host time
+    offset: f64,
+    round_trip: f64,
+}
+
+/// The estimated offset of a remote clock at a reference time, and the rate that it changes
+#[derive(Copy, Clone)]
+struct OffsetFit {
+    time: f64,
+    offset: f64,
+    drift: f64,
+}
+
+impl OffsetFit {
+    fn offset_at(&self, time: f64) -> f64 {
+        self.offset + self.drift * (time - self.time)
+    }
+}
+
+struct RemoteClock {
+    sync_samples: VecDeque<SyncSample>,
+    delay_samples: VecDeque<f64>,
+    fit: Option<OffsetFit>,
+    delay_offset: Option<f64>,
+    /// The latest remote time in any sample
+    latest: Option<f64>,
+}
+
+impl RemoteClock {
+    fn new() -> RemoteClock {
+        RemoteClock {
+            sync_samples: VecDeque::new(),
+            delay_samples: VecDeque::new(),
+            fit: None,
+            delay_offset: None,
+            latest: None,
+        }
+    }
+
+    /// Discards all of the samples if the remote time has gone backwards, since the offset to the
+    /// remote clock has changed. The autopilot's time since boot restarts from 0 when it reboots.
+    fn check_reset(&mut self, remote_time: f64) {
+        if let Some(latest) = self.latest {
+            if remote_time < latest - MAX_BACKWARDS_SECS {
+                println!("Remote clock went back {:.3} seconds, discarding its time samples",
+                    latest - remote_time);
+                *self = RemoteClock::new();
+            }
+        }
+        self.latest = Some(self.latest.map_or(remote_time, |latest| latest.max(remote_time)));
+    }
+
+    /// Adds a round trip sample: the host sent a request at `send_time`, the remote received it at
+    /// `remote_receive_time` and replied at `remote_transmit_time`, and the host received the reply
+    /// at `receive_time`.
+    fn add_round_trip(&mut self, send_time: f64, remote_receive_time: f64,
+        remote_transmit_time: f64, receive_time: f64)
+    {
+        self.check_reset(remote_receive_time);
+
+        let round_trip = (receive_time - send_time) - (remote_transmit_time - remote_receive_time);
+        if !(round_trip >= 0.0) {
+            return;
+        }
+
+        let offset =
+            ((send_time - remote_receive_time) + (receive_time - remote_transmit_time)) / 2.0;
+
+        if self.sync_samples.len() >= SYNC_WINDOW {
+            self.sync_samples.pop_front();
+        }
+        self.sync_samples.push_back(SyncSample {
+            time: receive_time,
+            offset: offset,
+            round_trip: round_trip,
+        });
+        self.fit = self.fit_samples();
+    }
+
+    /// Fits a line to the offsets of the samples with the smallest round trip times (which have
+    /// the least uncertainty).
+    fn fit_samples(&self) -> Option<OffsetFit> {
+        let mut samples: Vec<SyncSample> = self.sync_samples.iter().cloned().collect();
+        samples.sort_by(|a, b| a.round_trip.partial_cmp(&b.round_trip).unwrap());
+        samples.truncate((self.sync_samples.len() + 1) / 2);
+
+        if samples.is_empty() {
+            return None;
+        }
+
+        let n = samples.len() as f64;
+        let mean_time = samples.iter().fold(0.0, |total, s| total + s.time) / n;
+        let mean_offset = samples.iter().fold(0.0, |total, s| total + s.offset) / n;
+
+        let min_time = samples.iter().fold(f64::INFINITY, |min, s| min.min(s.time));
+        let max_time = samples.iter().fold(f64::NEG_INFINITY, |max, s| max.max(s.time));
+
+        let mut drift = 0.0;
+        if max_time - min_time >= MIN_DRIFT_SPAN_SECS {
+            let (covariance, variance) = samples.iter().fold((0.0, 0.0), |(c, v), s| {
+                let dt = s.time - mean_time;
+                (c + dt * (s.offset - mean_offset), v + dt * dt)
+            });
+            drift = covariance / variance;
+        }
+
+        Some(OffsetFit { time: mean_time, offset: mean_offset, drift: drift })
+    }
+
+    /// Adds a one way sample: a message sent at `remote_time` was received at `receive_time`
+    fn add_one_way(&mut self, remote_time: f64, receive_time: f64) {
+        self.check_reset(remote_time);
+
+        if self.delay_samples.len() >= DELAY_WINDOW {
+            self.delay_samples.pop_front();
+        }
+        self.delay_samples.push_back(receive_time - remote_time);
+        let offset = self.delay_samples.iter().fold(f64::INFINITY, |min, &x| min.min(x));
+        self.delay_offset = Some(offset);
+    }
+
+    /// Converts a remote time to a host time
+    fn to_host(&self, remote_time: f64) -> Option<f64> {
+        match (self.fit, self.delay_offset) {
+            (Some(fit), _) => {
+                // The fit is in terms of host time, so use the constant offset to get close first
+                Some(remote_time + fit.offset_at(remote_time + fit.offset))
+            },
+            (None, Some(offset)) => Some(remote_time + offset),
+            (None, None) => None,
+        }
+    }
+
+    fn status(&self) -> RemoteClockStatus {
+        let (method, offset, drift_ppm) = match (self.fit, self.delay_offset) {
+            (Some(fit), _) => {
+                let now = now();
+                (Some(SyncMethod::RoundTrip), Some(fit.offset_at(now)), Some(fit.drift * 1e6))
+            },
+            (None, Some(offset)) => (Some(SyncMethod::OneWayDelay), Some(offset), None),
+            (None, None) => (None, None, None),
+        };
+
+        let round_trip = self.sync_samples.iter().map(|s| s.round_trip)
+            .fold(None, |min: Option<f64>, x| Some(min.map_or(x, |min| min.min(x))));
+
+        RemoteClockStatus {
+            method: method,
+            offset: offset,
+            drift_ppm: drift_ppm,
+            round_trip: round_trip,
+            round_trip_samples: self.sync_samples.len(),
+            one_way_samples: self.delay_samples.len(),
+        }
+    }
+}
+
+lazy_static! {
+    static ref PULSE_SERVER_CLOCK: Mutex<RemoteClock> = Mutex::new(RemoteClock::new());
+    static ref AUTOPILOT_CLOCK: Mutex<RemoteClock> = Mutex::new(RemoteClock::new());
+    static ref AUTOPILOT_UNIX_OFFSET: Mutex<Option<f64>> = Mutex::new(None);
+}
+
+/// The current host time in seconds since the Unix epoch
+pub fn now() -> f64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(time) => time.as_secs() as f64 + time.subsec_nanos() as f64 / 1e9,
+        Err(_) => 0.0,
+    }
+}
+
+/// Returns the time the pulse was detected according to the pulse server's clock, in seconds
+/// since the Unix epoch
+pub fn pulse_time(pulse: &Pulse) -> f64 {
+    pulse.timestamp.sec as f64 + pulse.timestamp.nsec as f64 / 1e9
+}
+
+/// Handles a pulse that was received at `receive_time` (host time) and detected at `pulse_time`
+/// (pulse server time), returning the detection time in host time.
+pub fn observe_pulse(receive_time: f64, pulse_time: f64) -> f64 {
+    let mut clock = PULSE_SERVER_CLOCK.lock().unwrap();
+    clock.add_one_way(pulse_time, receive_time);
+    clock.to_host(pulse_time).unwrap_or(receive_time)
+}
+
+/// Handles the response to a time request sent to the pulse server
+pub fn pulse_server_time_sync(client_time: f64, server_receive_time: f64,
+    server_transmit_time: f64, receive_time: f64)
+{
+    PULSE_SERVER_CLOCK.lock().unwrap()
+        .add_round_trip(client_time, server_receive_time, server_transmit_time, receive_time);
+}
+
+/// Handles a response to a TIMESYNC request sent to the autopilot at `send_time`. The autopilot
+/// only reports a single time, so it is used as both the receive and transmit time.
+pub fn autopilot_time_sync(send_time: f64, autopilot_time: f64, receive_time: f64) {
+    AUTOPILOT_CLOCK.lock().unwrap()
+        .add_round_trip(send_time, autopilot_time, autopilot_time, receive_time);
+}
+
+/// Handles a message from the autopilot that was received at `receive_time` (host time) and sent
+/// at `boot_time` (seconds since the autopilot booted), returning the send time in host time.
+pub fn observe_autopilot(receive_time: f64, boot_time: f64) -> f64 {
+    let mut clock = AUTOPILOT_CLOCK.lock().unwrap();
+    clock.add_one_way(boot_time, receive_time);
+    clock.to_host(boot_time).unwrap_or(receive_time)
+}
+
+/// Handles a SYSTEM_TIME message from the autopilot
+pub fn autopilot_system_time(receive_time: f64, boot_time: f64, unix_time: Option<f64>) {
+    let host_time = observe_autopilot(receive_time, boot_time);
+    *AUTOPILOT_UNIX_OFFSET.lock().unwrap() = unix_time.map(|unix_time| unix_time - host_time);
+}
+
+/// Returns the current estimates of the offsets between the remote clocks and the host's clock
+pub fn get_status() -> ClockStatus {
+    ClockStatus {
+        pulse_server: PULSE_SERVER_CLOCK.lock().unwrap().status(),
+        autopilot: AUTOPILOT_CLOCK.lock().unwrap().status(),
+        autopilot_unix_offset: *AUTOPILOT_UNIX_OFFSET.lock().unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64;
+
+    use super::{MIN_DRIFT_SPAN_SECS, RemoteClock, SyncMethod};
+
+    /// The offset from the remote clock to the host clock in the tests
+    const OFFSET: f64 = 100.0;
+
+    /// Adds a round trip sample sent at `time` (host time) to a remote clock with the offset
+    /// `offset`, with `delay` seconds to reach the remote and `reply_delay` seconds to return
+    fn round_trip(clock: &mut RemoteClock, time: f64, offset: f64, delay: f64, reply_delay: f64) {
+        let remote_time = time + delay - offset;
+        clock.add_round_trip(time, remote_time, remote_time, time + delay + reply_delay);
+    }
+
+    fn assert_close(a: f64, b: f64, tolerance: f64) {
+        assert!((a - b).abs() < tolerance, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn fixed_offset() {
+        let mut clock = RemoteClock::new();
+        assert_eq!(clock.to_host(5.0), None);
+
+        for i in 0..10 {
+            round_trip(&mut clock, 1000.0 + i as f64, OFFSET, 0.01, 0.01);
+        }
+        assert_close(clock.to_host(1005.0).unwrap(), 1005.0 + OFFSET, 1e-9);
+
+        let status = clock.status();
+        assert_eq!(status.method, Some(SyncMethod::RoundTrip));
+        assert_eq!(status.drift_ppm, Some(0.0));
+        assert_close(status.round_trip.unwrap(), 0.02, 1e-9);
+        assert_eq!(status.round_trip_samples, 10);
+    }
+
+    #[test]
+    fn drift() {
+        // The offset grows by 50 ppm, sampled over more than the minimum span
+        let drift = 50e-6;
+        let interval = MIN_DRIFT_SPAN_SECS / 8.0;
+        let mut clock = RemoteClock::new();
+        for i in 0..30 {
+            let time = i as f64 * interval;
+            round_trip(&mut clock, time, OFFSET + drift * time, 0.0, 0.0);
+        }
+        assert_close(clock.status().drift_ppm.unwrap(), 50.0, 1e-3);
+
+        // Times past the samples are extrapolated with the drift
+        let time = 500.0;
+        assert_close(clock.to_host(time - OFFSET - drift * time).unwrap(), time, 1e-5);
+    }
+
+    #[test]
+    fn no_drift_over_short_span() {
+        // A drift estimated over a short span would be dominated by noise
+        let mut clock = RemoteClock::new();
+        for i in 0..4 {
+            let time = i as f64 * MIN_DRIFT_SPAN_SECS / 8.0;
+            round_trip(&mut clock, time, OFFSET + 0.001 * i as f64, 0.0, 0.0);
+        }
+        assert_eq!(clock.status().drift_ppm, Some(0.0));
+    }
+
+    #[test]
+    fn rejected_round_trips() {
+        let mut clock = RemoteClock::new();
+
+        // Samples with a long, one-sided delay are outweighed by the quicker samples
+        for i in 0..3 {
+            round_trip(&mut clock, i as f64, OFFSET, 0.01, 0.01);
+        }
+        for i in 3..5 {
+            round_trip(&mut clock, i as f64, OFFSET, 2.0, 0.0);
+        }
+        assert_eq!(clock.status().round_trip_samples, 5);
+        assert_close(clock.to_host(10.0).unwrap(), 10.0 + OFFSET, 1e-9);
+
+        // The remote can't take longer to reply than the whole round trip
+        clock.add_round_trip(10.0, 10.0 - OFFSET, 12.0 - OFFSET, 11.0);
+        clock.add_round_trip(10.0, f64::NAN, 12.0 - OFFSET, 11.0);
+        assert_eq!(clock.status().round_trip_samples, 5);
+    }
+
+    #[test]
+    fn one_way_fallback() {
+        let mut clock = RemoteClock::new();
+
+        // The smallest delay is the best estimate of the offset
+        for &(remote_time, delay) in &[(1.0, 0.3), (2.0, 0.1), (3.0, 0.2)] {
+            clock.add_one_way(remote_time, remote_time + OFFSET + delay);
+        }
+        assert_close(clock.to_host(5.0).unwrap(), 5.0 + OFFSET + 0.1, 1e-9);
+
+        let status = clock.status();
+        assert_eq!(status.method, Some(SyncMethod::OneWayDelay));
+        assert_eq!(status.one_way_samples, 3);
+
+        // Round trip samples take priority once they are available
+        round_trip(&mut clock, 5.0 + OFFSET, OFFSET, 0.01, 0.01);
+        assert_eq!(clock.status().method, Some(SyncMethod::RoundTrip));
+        assert_close(clock.to_host(6.0).unwrap(), 6.0 + OFFSET, 1e-9);
+    }
+
+    #[test]
+    fn remote_reset() {
+        let mut clock = RemoteClock::new();
+        for i in 0..5 {
+            round_trip(&mut clock, 1000.0 + i as f64, OFFSET, 0.01, 0.01);
+            clock.add_one_way(1000.0 - OFFSET + i as f64, 1000.0 + i as f64 + 0.1);
+        }
+
+        // Messages arriving slightly out of order don't reset the clock
+        clock.add_one_way(1004.0 - OFFSET - 0.5, 1005.0);
+        assert_eq!(clock.status().round_trip_samples, 5);
+        assert_eq!(clock.status().one_way_samples, 6);
+
+        // The remote restarted from 0 (e.g. the autopilot rebooted) 10 seconds later
+        clock.add_one_way(0.0, 1014.1);
+        let status = clock.status();
+        assert_eq!(status.method, Some(SyncMethod::OneWayDelay));
+        assert_eq!((status.round_trip_samples, status.one_way_samples), (0, 1));
+        assert_close(clock.to_host(1.0).unwrap(), 1015.1, 1e-9);
+    }
+}
@@ -0,0 +1,474 @@
+//! Tracking of the COMMAND_LONG messages sent to the vehicle, and the COMMAND_ACK messages sent in
+//! response.
+//!
+//! Commands are sent as soon as they are submitted. If no acknowledgement is received the command
+//! is resent (incrementing the `confirmation` field) until the maximum number of attempts is
+//! reached. Since COMMAND_ACK only identifies the command number, submitting a new command with
+//! the same number supersedes any earlier command that is still waiting for a response.
+
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use mavlink::common::*;
+
+use mavlink_handler;
+
+/// Time to wait for an acknowledgement before resending a command
+const RETRY_INTERVAL_MS: u64 = 1000;
+
+/// The maximum number of times a command is sent before it times out
+const MAX_ATTEMPTS: u8 = 5;
+
+/// Time to wait for a command that the vehicle has reported as in progress
+const IN_PROGRESS_TIMEOUT_SECS: u64 = 30;
+
+/// The number of completed commands that are kept for clients to query
+const COMMAND_HISTORY: usize = 100;
+
+const MAV_RESULT_ACCEPTED: u8 = 0;
+const MAV_RESULT_TEMPORARILY_REJECTED: u8 = 1;
+const MAV_RESULT_DENIED: u8 = 2;
+const MAV_RESULT_UNSUPPORTED: u8 = 3;
+const MAV_RESULT_FAILED: u8 = 4;
+const MAV_RESULT_IN_PROGRESS: u8 = 5;
+
+/// A command to send to the vehicle as a COMMAND_LONG message
+#[derive(Debug, Copy, Clone)]
+pub struct CommandLong {
+    pub command: u16,
+    pub params: [f32; 7],
+}
+
+impl CommandLong {
+    pub fn to_message(&self, confirmation: u8) -> MavMessage {
+        MavMessage::COMMAND_LONG(COMMAND_LONG_DATA {
+            param1: self.params[0],
+            param2: self.params[1],
+            param3: self.params[2],
+            param4: self.params[3],
+            param5: self.params[4],
+            param6: self.params[5],
+            param7: self.params[6],
+            command: self.command,
+            target_system: 1,
+            target_component: 0,
+            confirmation: confirmation,
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub enum CommandStatus {
+    /// The command has been sent, but has not been acknowledged yet
+    Pending,
+    /// The vehicle has acknowledged the command, but has not finished executing it yet
+    InProgress,
+    Accepted,
+    TemporarilyRejected,
+    Denied,
+    Unsupported,
+    Failed,
+    /// No acknowledgement was received after the maximum number of attempts
+    TimedOut,
+    /// A newer command with the same command number was sent before this one was acknowledged
+    Superseded,
+}
+
+impl CommandStatus {
+    fn from_result(result: u8) -> CommandStatus {
+        match result {
+            MAV_RESULT_ACCEPTED => CommandStatus::Accepted,
+            MAV_RESULT_TEMPORARILY_REJECTED => CommandStatus::TemporarilyRejected,
+            MAV_RESULT_DENIED => CommandStatus::Denied,
+            MAV_RESULT_UNSUPPORTED => CommandStatus::Unsupported,
+            MAV_RESULT_IN_PROGRESS => CommandStatus::InProgress,
+            MAV_RESULT_FAILED => CommandStatus::Failed,
+            _ => CommandStatus::Failed,
+        }
+    }
+
+    /// Returns true if the status will not change again
+    pub fn is_complete(&self) -> bool {
+        match *self {
+            CommandStatus::Pending | CommandStatus::InProgress => false,
+            _ => true,
+        }
+    }
+}
+
+/// The state of a command as reported to REST clients
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandState {
+    pub id: u64,
+    pub command: u16,
+    pub status: CommandStatus,
+    pub attempts: u8,
+    /// Seconds since the command was submitted
+    pub age: f32,
+}
+
+struct TrackedCommand {
+    id: u64,
+    command: CommandLong,
+    status: CommandStatus,
+    attempts: u8,
+    created: Instant,
+    last_update: Instant,
+}
+
+impl TrackedCommand {
+    fn state(&self, now: Instant) -> CommandState {
+        let age = now.duration_since(self.created);
+        CommandState {
+            id: self.id,
+            command: self.command.command,
+            status: self.status,
+            attempts: self.attempts,
+            age: age.as_secs() as f32 + age.subsec_nanos() as f32 / 1e9,
+        }
+    }
+}
+
+struct CommandTracker {
+    next_id: u64,
+    commands: VecDeque<TrackedCommand>,
+}
+
+impl CommandTracker {
+    fn new() -> CommandTracker {
+        CommandTracker {
+            next_id: 1,
+            commands: VecDeque::new(),
+        }
+    }
+
+    /// Starts tracking a new command, superseding any earlier command with the same number
+    fn submit(&mut self, command: CommandLong, now: Instant) -> CommandState {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        for tracked in self.commands.iter_mut() {
+            if tracked.command.command == command.command && !tracked.status.is_complete() {
+                tracked.status = CommandStatus::Superseded;
+            }
+        }
+
+        if self.commands.len() >= COMMAND_HISTORY {
+            self.commands.pop_front();
+        }
+
+        self.commands.push_back(TrackedCommand {
+            id: id,
+            command: command,
+            status: CommandStatus::Pending,
+            attempts: 1,
+            created: now,
+            last_update: now,
+        });
+        self.commands.back().unwrap().state(now)
+    }
+
+    fn get(&self, id: u64, now: Instant) -> Option<CommandState> {
+        self.commands.iter().find(|c| c.id == id).map(|c| c.state(now))
+    }
+
+    /// Updates the latest incomplete command with the acknowledged command number
+    fn handle_ack(&mut self, data: &COMMAND_ACK_DATA, now: Instant) {
+        let tracked = self.commands.iter_mut().rev()
+            .find(|c| c.command.command == data.command && !c.status.is_complete());
+
+        if let Some(tracked) = tracked {
+            tracked.status = CommandStatus::from_result(data.result);
+            tracked.last_update = now;
+            println!("Command [{}] acknowledged: {:?}", tracked.id, tracked.status);
+        }
+    }
+
+    /// Times out the commands that have run out of time, returning the ids and messages of the
+    /// commands that need to be resent, and whether the status of any command changed
+    fn check_retries(&mut self, now: Instant) -> (Vec<(u64, MavMessage)>, bool) {
+        let retry_interval = Duration::from_millis(RETRY_INTERVAL_MS);
+        let in_progress_timeout = Duration::from_secs(IN_PROGRESS_TIMEOUT_SECS);
+
+        let mut resend = vec![];
+        let mut updated = false;
+
+        for tracked in self.commands.iter_mut() {
+            let elapsed = now.duration_since(tracked.last_update);
+
+            match tracked.status {
+                CommandStatus::Pending if elapsed >= retry_interval => {
+                    if tracked.attempts >= MAX_ATTEMPTS {
+                        println!("Command [{}] timed out", tracked.id);
+                        tracked.status = CommandStatus::TimedOut;
+                    }
+                    else {
+                        resend.push((tracked.id, tracked.command.to_message(tracked.attempts)));
+                        tracked.attempts += 1;
+                        tracked.last_update = now;
+                    }
+                    updated = true;
+                },
+
+                CommandStatus::InProgress if elapsed >= in_progress_timeout => {
+                    tracked.status = CommandStatus::TimedOut;
+                    updated = true;
+                },
+
+                _ => {},
+            }
+        }
+
+        (resend, updated)
+    }
+}
+
+lazy_static! {
+    static ref COMMANDS: Mutex<CommandTracker> = Mutex::new(CommandTracker::new());
+
+    /// Notified whenever the status of a command changes
+    static ref COMMAND_UPDATED: Condvar = Condvar::new();
+}
+
+/// Sends a command to the vehicle, returning the state of the tracked command
+pub fn submit(command: CommandLong) -> CommandState {
+    let state = COMMANDS.lock().unwrap().submit(command, Instant::now());
+    COMMAND_UPDATED.notify_all();
+
+    println!("Sending command [{}]: {:?}", state.id, command);
+    if let Err(e) = mavlink_handler::send(&command.to_message(0)) {
+        println!("Failed to send command [{}]: {}", state.id, e);
+    }
+
+    state
+}
+
+/// Returns the state of a command
+pub fn get(id: u64) -> Option<CommandState> {
+    COMMANDS.lock().unwrap().get(id, Instant::now())
+}
+
+/// Returns the state of all recent commands
+pub fn get_all() -> Vec<CommandState> {
+    let now = Instant::now();
+    COMMANDS.lock().unwrap().commands.iter().map(|c| c.state(now)).collect()
+}
+
+/// Waits for a command to complete (or for the timeout to expire) and returns its state
+pub fn wait(id: u64, timeout: Duration) -> Option<CommandState> {
+    let deadline = Instant::now() + timeout;
+    let mut tracker = COMMANDS.lock().unwrap();
+
+    loop {
+        let now = Instant::now();
+        let state = match tracker.get(id, now) {
+            Some(state) => state,
+            None => return None,
+        };
+
+        if state.status.is_complete() || now >= deadline {
+            return Some(state);
+        }
+
+        tracker = COMMAND_UPDATED.wait_timeout(tracker, deadline - now).unwrap().0;
+    }
+}
+
+/// Handles a COMMAND_ACK received from the vehicle
+pub fn handle_ack(data: COMMAND_ACK_DATA) {
+    COMMANDS.lock().unwrap().handle_ack(&data, Instant::now());
+    COMMAND_UPDATED.notify_all();
+}
+
+/// Resends any commands that have not been acknowledged in time, and times out commands that have
+/// run out of attempts.
+pub fn check_retries() {
+    let (resend, updated) = COMMANDS.lock().unwrap().check_retries(Instant::now());
+
+    if updated {
+        COMMAND_UPDATED.notify_all();
+    }
+
+    for (id, message) in resend {
+        println!("Resending command [{}]", id);
+        if let Err(e) = mavlink_handler::send(&message) {
+            println!("Failed to resend command [{}]: {}", id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use mavlink::common::*;
+
+    use super::{CommandLong, CommandStatus, CommandTracker, IN_PROGRESS_TIMEOUT_SECS, MAX_ATTEMPTS,
+        MAV_RESULT_ACCEPTED, MAV_RESULT_DENIED, MAV_RESULT_IN_PROGRESS,
+        MAV_RESULT_TEMPORARILY_REJECTED, RETRY_INTERVAL_MS, handle_ack, submit, wait};
+
+    const MAV_CMD_COMPONENT_ARM_DISARM: u16 = 400;
+    const MAV_CMD_NAV_TAKEOFF: u16 = 22;
+
+    fn command(number: u16) -> CommandLong {
+        CommandLong { command: number, params: [0.0; 7] }
+    }
+
+    fn ack(command: u16, result: u8) -> COMMAND_ACK_DATA {
+        COMMAND_ACK_DATA { command: command, result: result }
+    }
+
+    fn status(tracker: &CommandTracker, id: u64, now: Instant) -> CommandStatus {
+        tracker.get(id, now).unwrap().status
+    }
+
+    /// Returns the confirmation number of each resent message
+    fn confirmations(resend: &[(u64, MavMessage)]) -> Vec<u8> {
+        resend.iter().map(|&(_, ref message)| {
+            match *message {
+                MavMessage::COMMAND_LONG(ref data) => data.confirmation,
+                _ => panic!("Expected COMMAND_LONG"),
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn accepted() {
+        let start = Instant::now();
+        let mut tracker = CommandTracker::new();
+        let state = tracker.submit(command(MAV_CMD_COMPONENT_ARM_DISARM), start);
+        assert_eq!((state.status, state.attempts), (CommandStatus::Pending, 1));
+
+        tracker.handle_ack(&ack(MAV_CMD_COMPONENT_ARM_DISARM, MAV_RESULT_ACCEPTED), start);
+        assert_eq!(status(&tracker, state.id, start), CommandStatus::Accepted);
+
+        // Completed commands are never resent
+        let later = start + Duration::from_millis(RETRY_INTERVAL_MS * 10);
+        assert_eq!(tracker.check_retries(later).0.len(), 0);
+        assert_eq!(status(&tracker, state.id, later), CommandStatus::Accepted);
+    }
+
+    #[test]
+    fn in_progress() {
+        let start = Instant::now();
+        let mut tracker = CommandTracker::new();
+        let first = tracker.submit(command(MAV_CMD_NAV_TAKEOFF), start);
+
+        tracker.handle_ack(&ack(MAV_CMD_NAV_TAKEOFF, MAV_RESULT_IN_PROGRESS), start);
+        assert_eq!(status(&tracker, first.id, start), CommandStatus::InProgress);
+        let retry = start + Duration::from_millis(RETRY_INTERVAL_MS);
+        assert_eq!(tracker.check_retries(retry).0.len(), 0);
+
+        tracker.handle_ack(&ack(MAV_CMD_NAV_TAKEOFF, MAV_RESULT_ACCEPTED), retry);
+        assert_eq!(status(&tracker, first.id, retry), CommandStatus::Accepted);
+
+        // Commands that stay in progress eventually time out
+        let second = tracker.submit(command(MAV_CMD_NAV_TAKEOFF), start);
+        tracker.handle_ack(&ack(MAV_CMD_NAV_TAKEOFF, MAV_RESULT_IN_PROGRESS), start);
+        let timeout = start + Duration::from_secs(IN_PROGRESS_TIMEOUT_SECS);
+        let (resend, updated) = tracker.check_retries(timeout);
+        assert!(resend.is_empty() && updated);
+        assert_eq!(status(&tracker, second.id, timeout), CommandStatus::TimedOut);
+    }
+
+    #[test]
+    fn retries_then_timeout() {
+        let start = Instant::now();
+        let mut tracker = CommandTracker::new();
+        let state = tracker.submit(command(MAV_CMD_COMPONENT_ARM_DISARM), start);
+
+        // Nothing is resent before the retry interval
+        let early = start + Duration::from_millis(RETRY_INTERVAL_MS / 2);
+        assert_eq!(tracker.check_retries(early).0.len(), 0);
+
+        // Each attempt increments the confirmation number
+        let mut now = start;
+        for attempt in 1..MAX_ATTEMPTS {
+            now = now + Duration::from_millis(RETRY_INTERVAL_MS);
+            let (resend, updated) = tracker.check_retries(now);
+            assert_eq!(confirmations(&resend), vec![attempt]);
+            assert!(updated);
+            assert_eq!(tracker.get(state.id, now).unwrap().attempts, attempt + 1);
+        }
+        assert_eq!(status(&tracker, state.id, now), CommandStatus::Pending);
+
+        now = now + Duration::from_millis(RETRY_INTERVAL_MS);
+        let (resend, updated) = tracker.check_retries(now);
+        assert!(resend.is_empty() && updated);
+        assert_eq!(status(&tracker, state.id, now), CommandStatus::TimedOut);
+
+        // An ack after the timeout doesn't change the command
+        tracker.handle_ack(&ack(MAV_CMD_COMPONENT_ARM_DISARM, MAV_RESULT_ACCEPTED), now);
+        assert_eq!(status(&tracker, state.id, now), CommandStatus::TimedOut);
+    }
+
+    #[test]
+    fn temporarily_rejected_then_retried() {
+        let start = Instant::now();
+        let mut tracker = CommandTracker::new();
+        let first = tracker.submit(command(MAV_CMD_COMPONENT_ARM_DISARM), start);
+
+        let rejected = ack(MAV_CMD_COMPONENT_ARM_DISARM, MAV_RESULT_TEMPORARILY_REJECTED);
+        tracker.handle_ack(&rejected, start);
+        assert_eq!(status(&tracker, first.id, start), CommandStatus::TemporarilyRejected);
+
+        // The rejection is final for the command, so it is only retried by submitting it again
+        let later = start + Duration::from_millis(RETRY_INTERVAL_MS);
+        assert_eq!(tracker.check_retries(later).0.len(), 0);
+
+        let second = tracker.submit(command(MAV_CMD_COMPONENT_ARM_DISARM), later);
+        assert!(second.id > first.id);
+        tracker.handle_ack(&ack(MAV_CMD_COMPONENT_ARM_DISARM, MAV_RESULT_ACCEPTED), later);
+        assert_eq!(status(&tracker, first.id, later), CommandStatus::TemporarilyRejected);
+        assert_eq!(status(&tracker, second.id, later), CommandStatus::Accepted);
+    }
+
+    #[test]
+    fn superseded() {
+        let start = Instant::now();
+        let mut tracker = CommandTracker::new();
+        let first = tracker.submit(command(MAV_CMD_COMPONENT_ARM_DISARM), start);
+        let other = tracker.submit(command(MAV_CMD_NAV_TAKEOFF), start);
+        let second = tracker.submit(command(MAV_CMD_COMPONENT_ARM_DISARM), start);
+
+        assert_eq!(status(&tracker, first.id, start), CommandStatus::Superseded);
+        assert_eq!(status(&tracker, other.id, start), CommandStatus::Pending);
+
+        tracker.handle_ack(&ack(MAV_CMD_COMPONENT_ARM_DISARM, MAV_RESULT_DENIED), start);
+        assert_eq!(status(&tracker, second.id, start), CommandStatus::Denied);
+        assert_eq!(status(&tracker, other.id, start), CommandStatus::Pending);
+    }
+
+    #[test]
+    fn unexpected_ack() {
+        let start = Instant::now();
+        let mut tracker = CommandTracker::new();
+        let state = tracker.submit(command(MAV_CMD_NAV_TAKEOFF), start);
+
+        // Nothing is waiting for an arm command
+        tracker.handle_ack(&ack(MAV_CMD_COMPONENT_ARM_DISARM, MAV_RESULT_ACCEPTED), start);
+        assert_eq!(status(&tracker, state.id, start), CommandStatus::Pending);
+        assert!(tracker.get(state.id + 1, start).is_none());
+    }
+
+    #[test]
+    fn wait_for_ack() {
+        // A command number that no other test uses, since this uses the shared tracker
+        let number = 31000;
+
+        let state = submit(command(number));
+        let waiter = thread::spawn(move || wait(state.id, Duration::from_secs(10)));
+        thread::sleep(Duration::from_millis(50));
+        handle_ack(ack(number, MAV_RESULT_ACCEPTED));
+        assert_eq!(waiter.join().unwrap().unwrap().status, CommandStatus::Accepted);
+
+        // Waiting returns the pending state once the timeout expires
+        let pending = submit(command(number));
+        let result = wait(pending.id, Duration::from_millis(50)).unwrap();
+        assert_eq!(result.status, CommandStatus::Pending);
+
+        assert!(wait(0, Duration::from_millis(50)).is_none());
+    }
+}
@@ -0,0 +1,368 @@
+//! Configuration for the telemetry host, loaded from a json file and optionally overridden by
+//! command line flags.
+
+use std::env;
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+use pulse_protocol::{Encoding, PulseFilter, Role};
+use serde_json;
+
+use geodetic::Coordinate;
+use geofence::Geofence;
+
+pub const DEFAULT_CONFIG_PATH: &'static str = "config/host_config.json";
+
+/// Rocket 0.1 can only be configured with `Rocket.toml`, which it loads from the working directory
+const ROCKET_CONFIG_PATH: &'static str = "Rocket.toml";
+
+/// The name, address, port and log level of each Rocket environment. The address and port are
+/// replaced by `rest_address` and `rest_port` if they are set.
+const ROCKET_ENVIRONMENTS: [(&'static str, &'static str, u16, &'static str); 3] = [
+    ("development", "localhost", 8000, "normal"),
+    ("staging", "0.0.0.0", 80, "normal"),
+    ("production", "0.0.0.0", 80, "critical"),
+];
+
+const USAGE: &'static str = "\
+Usage: telemetry_host [options]
+
+Options:
+    --config <path>          Path to the config file (default: config/host_config.json)
+    --mavlink <connection>   Mavlink connection string, e.g. udpin:127.0.0.1:14552,
+                             udpout:10.0.0.2:14550, tcp:10.0.0.2:5760 or serial:/dev/ttyUSB0:57600
+    --pulse-server <address> Address of the pulse server, e.g. 192.168.1.10:11000
+    --address <address>      Address to bind the REST API to (default: set by the environment)
+    --port <port>            Port to bind the REST API to (default: set by the environment)
+    --env <environment>      Rocket environment of the REST API: development (localhost:8000),
+                             staging or production (0.0.0.0:80)
+    --stream-address <addr>  Address to bind the event stream to (default: localhost)
+    --stream-port <port>     Port to bind the event stream to (default: 8001)
+    --storage <path>         Directory to store sessions in (default: data)
+    --help                   Print this message";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostConfig {
+    /// The connection string used to connect to the Mavlink telemetry stream
+    pub mavlink: String,
+
+    /// The address of the pulse server's TCP endpoint
+    pub pulse_server: String,
+
+    /// The address the REST API is bound to. If this is not set, the address of the Rocket
+    /// environment is used.
+    #[serde(default)]
+    pub rest_address: Option<String>,
+
+    /// The port the REST API is bound to. If this is not set, the port of the Rocket environment is
+    /// used.
+    #[serde(default)]
+    pub rest_port: Option<u16>,
+
+    /// The Rocket environment used for the REST API, which sets its log level (and its address and
+    /// port if they are not set). If this is not set, the environment is selected with
+    /// `ROCKET_ENV` (default: development).
+    #[serde(default)]
+    pub rest_environment: Option<String>,
+
+    /// Controls the origin of the local coordinate frame
+    #[serde(default)]
+    pub origin: OriginConfig,
+
+    /// The geofence applied to targets sent to the vehicle
+    #[serde(default)]
+    pub geofence: Geofence,
+
+    /// The directory that recorded sessions are stored in
+    #[serde(default = "default_storage_path")]
+    pub storage_path: String,
+
+    /// The address the event stream is bound to
+    #[serde(default = "default_stream_address")]
+    pub stream_address: String,
+
+    /// The port the event stream is bound to
+    #[serde(default = "default_stream_port")]
+    pub stream_port: u16,
+
+    /// The encoding requested for messages from the pulse server. Json is used if the server does
+    /// not support it.
+    #[serde(default = "default_pulse_encoding")]
+    pub pulse_encoding: Encoding,
+
+    /// Selects the pulses that the pulse server sends to the host, e.g. to only receive the collar
+    /// being tracked over a low bandwidth link. All pulses are sent by default.
+    #[serde(default)]
+    pub pulse_filter: PulseFilter,
+
+    /// The role requested from the pulse server: `Controller` to be the only client that can start
+    /// and stop detection, or `Observer` to only receive pulses
+    #[serde(default = "default_pulse_role")]
+    pub pulse_role: Role,
+}
+
+fn default_storage_path() -> String {
+    "data".into()
+}
+
+fn default_stream_address() -> String {
+    "localhost".into()
+}
+
+fn default_stream_port() -> u16 {
+    8001
+}
+
+fn default_pulse_encoding() -> Encoding {
+    Encoding::Cbor
+}
+
+fn default_pulse_role() -> Role {
+    Role::Controller
+}
+
+impl Default for HostConfig {
+    fn default() -> HostConfig {
+        HostConfig {
+            mavlink: "udpin:127.0.0.1:14552".into(),
+            pulse_server: "127.0.0.1:11000".into(),
+            rest_address: None,
+            rest_port: None,
+            rest_environment: None,
+            origin: OriginConfig::default(),
+            geofence: Geofence::default(),
+            storage_path: default_storage_path(),
+            stream_address: default_stream_address(),
+            stream_port: default_stream_port(),
+            pulse_encoding: default_pulse_encoding(),
+            pulse_filter: PulseFilter::default(),
+            pulse_role: default_pulse_role(),
+        }
+    }
+}
+
+impl HostConfig {
+    /// Loads the config from the config file and the command line arguments of the process, with
+    /// the command line taking priority.
+    pub fn from_args() -> Result<HostConfig, String> {
+        let args: Vec<String> = env::args().skip(1).collect();
+
+        if args.iter().any(|arg| arg == "--help") {
+            println!("{}", USAGE);
+            ::std::process::exit(0);
+        }
+
+        let mut path = DEFAULT_CONFIG_PATH.to_string();
+        let mut overrides = vec![];
+
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            let value = match args.next() {
+                Some(value) => value,
+                None => return Err(format!("Missing value for `{}`\n\n{}", flag, USAGE)),
+            };
+
+            match &flag[..] {
+                "--config" => path = value,
+                "--mavlink" | "--pulse-server" | "--address" | "--port" | "--env" | "--storage" |
+                "--stream-address" | "--stream-port" => overrides.push((flag, value)),
+                _ => return Err(format!("Unknown argument `{}`\n\n{}", flag, USAGE)),
+            }
+        }
+
+        let mut config = try!(load_config(&path));
+        for (flag, value) in overrides {
+            match &flag[..] {
+                "--mavlink" => config.mavlink = value,
+                "--pulse-server" => config.pulse_server = value,
+                "--address" => config.rest_address = Some(value),
+                "--port" => {
+                    config.rest_port = Some(try!(value.parse()
+                        .map_err(|_| format!("Invalid REST port `{}`", value))));
+                },
+                "--env" => config.rest_environment = Some(value),
+                "--storage" => config.storage_path = value,
+                "--stream-address" => config.stream_address = value,
+                "--stream-port" => {
+                    config.stream_port = try!(value.parse()
+                        .map_err(|_| format!("Invalid stream port `{}`", value)));
+                },
+                _ => unreachable!(),
+            }
+        }
+
+        try!(config.validate());
+        Ok(config)
+    }
+
+    /// Checks that all of the addresses in the config are well formed.
+    pub fn validate(&self) -> Result<(), String> {
+        try!(validate_mavlink_address(&self.mavlink));
+
+        if try!(resolve(&self.pulse_server, "pulse server address")).is_empty() {
+            return Err(format!("Pulse server address `{}` did not resolve", self.pulse_server));
+        }
+
+        if let Some(ref environment) = self.rest_environment {
+            match &environment[..] {
+                "development" | "dev" | "staging" | "stage" | "production" | "prod" => {},
+                _ => return Err(format!("Invalid Rocket environment `{}`: expected development, \
+                    staging or production", environment)),
+            }
+        }
+
+        if let Some(ref address) = self.rest_address {
+            if address != "localhost" && address.parse::<IpAddr>().is_err() {
+                return Err(format!("Invalid REST address `{}`: expected `localhost` or an IP \
+                    address", address));
+            }
+        }
+
+        if self.rest_port == Some(0) {
+            return Err("Invalid REST port `0`".into());
+        }
+
+        if self.stream_address != "localhost" && self.stream_address.parse::<IpAddr>().is_err() {
+            return Err(format!("Invalid stream address `{}`: expected `localhost` or an IP \
+                address", self.stream_address));
+        }
+
+        if self.stream_port == 0 || Some(self.stream_port) == self.rest_port {
+            return Err(format!("Invalid stream port `{}`: the stream port must be different to the \
+                REST port", self.stream_port));
+        }
+
+        if self.storage_path.is_empty() {
+            return Err("The storage path must not be empty".into());
+        }
+
+        try!(self.pulse_filter.validate().map_err(|e| format!("Invalid pulse filter: {}", e)));
+        try!(self.origin.validate().map_err(|e| format!("Invalid origin: {}", e)));
+        self.geofence.validate().map_err(|e| format!("Invalid geofence: {}", e))
+    }
+
+    /// Writes `Rocket.toml` with the REST API's address and port, and selects the Rocket
+    /// environment. This must be called before Rocket is started, since Rocket 0.1 can only be
+    /// configured with `Rocket.toml` and `ROCKET_ENV`.
+    pub fn configure_rest(&self) -> Result<(), String> {
+        if let Some(ref environment) = self.rest_environment {
+            env::set_var("ROCKET_ENV", environment);
+        }
+
+        let mut contents = String::from("# Generated by telemetry_host from its config at startup, \
+            set `rest_address` and `rest_port` instead of editing this file\n");
+        for &(name, address, port, log) in &ROCKET_ENVIRONMENTS {
+            let address = self.rest_address.as_ref().map(|address| &address[..]).unwrap_or(address);
+            contents.push_str(&format!("\n[{}]\naddress = \"{}\"\nport = {}\nlog = \"{}\"\n", name,
+                address, self.rest_port.unwrap_or(port), log));
+        }
+
+        File::create(ROCKET_CONFIG_PATH)
+            .and_then(|mut file| file.write_all(contents.as_bytes()))
+            .map_err(|e| format!("Failed to write `{}`: {}", ROCKET_CONFIG_PATH, e))
+    }
+}
+
+/// Controls how the origin of the local coordinate frame is chosen
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum OriginMode {
+    /// Use the first position received from the vehicle
+    FirstFix,
+    /// Use the vehicle's HOME_POSITION
+    HomePosition,
+    /// Use the `fixed` coordinate from the config
+    Fixed,
+}
+
+impl Default for OriginMode {
+    fn default() -> OriginMode {
+        OriginMode::FirstFix
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OriginConfig {
+    pub mode: OriginMode,
+    pub fixed: Option<Coordinate>,
+}
+
+impl OriginConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        match self.fixed {
+            Some(coordinate) => validate_coordinate(&coordinate),
+            None if self.mode == OriginMode::Fixed => {
+                Err("Origin mode is `Fixed` but no `fixed` coordinate was specified".into())
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+/// Checks that a coordinate has a valid latitude and longitude
+pub fn validate_coordinate(coordinate: &Coordinate) -> Result<(), String> {
+    if !(coordinate.lat >= -90.0 && coordinate.lat <= 90.0) {
+        return Err(format!("Invalid latitude `{}`", coordinate.lat));
+    }
+    if !(coordinate.lon >= -180.0 && coordinate.lon <= 180.0) {
+        return Err(format!("Invalid longitude `{}`", coordinate.lon));
+    }
+    if !coordinate.alt.is_finite() {
+        return Err(format!("Invalid altitude `{}`", coordinate.alt));
+    }
+    Ok(())
+}
+
+/// Load the config from a file if it exists. If it does not exist then the default config is
+/// returned, and the file is generated.
+fn load_config(path: &str) -> Result<HostConfig, String> {
+    match File::open(path).map(|mut r| serde_json::from_reader(&mut r)) {
+        Ok(Ok(config)) => return Ok(config),
+        Ok(Err(e)) => return Err(format!("Failed to parse `{}`: {}", path, e)),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {},
+        Err(e) => return Err(format!("Unable to access `{}`: {}", path, e)),
+    }
+
+    let config = HostConfig::default();
+    if let Err(e) = File::create(path).map(|mut w| serde_json::to_writer_pretty(&mut w, &config)) {
+        println!("Failed to save default config `{}`: {}", path, e);
+    }
+
+    Ok(config)
+}
+
+fn resolve(address: &str, name: &str) -> Result<Vec<SocketAddr>, String> {
+    address.to_socket_addrs()
+        .map(|addrs| addrs.collect())
+        .map_err(|e| format!("Invalid {} `{}`: {}", name, address, e))
+}
+
+/// Checks that a Mavlink connection string has a supported protocol and a valid address
+fn validate_mavlink_address(address: &str) -> Result<(), String> {
+    let mut split = address.splitn(2, ':');
+    let protocol = split.next().unwrap_or("");
+    let target = match split.next() {
+        Some(target) if !target.is_empty() => target,
+        _ => return Err(format!("Invalid Mavlink connection `{}`: expected <protocol>:<address>",
+            address)),
+    };
+
+    match protocol {
+        "udpin" | "udpout" | "tcp" => {
+            try!(resolve(target, "Mavlink address"));
+            Ok(())
+        },
+
+        "serial" => {
+            // Serial connections are specified as `serial:<port>:<baud>`
+            match target.rfind(':') {
+                Some(i) if i > 0 && target[i + 1..].parse::<u32>().is_ok() => Ok(()),
+                _ => Err(format!("Invalid Mavlink serial connection `{}`: expected \
+                    serial:<port>:<baud>", address)),
+            }
+        },
+
+        _ => Err(format!("Unsupported Mavlink protocol `{}` in `{}`: expected one of udpin, \
+            udpout, tcp or serial", protocol, address)),
+    }
+}
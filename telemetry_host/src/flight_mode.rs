@@ -0,0 +1,51 @@
+//! Decoding and encoding of PX4 flight modes, which are packed into the `custom_mode` field of
+//! HEARTBEAT messages as `[reserved: u16, main_mode: u8, sub_mode: u8]`.
+
+pub const MAV_AUTOPILOT_PX4: u8 = 12;
+/// Sent by components that are not flight controllers (e.g. gimbals, cameras)
+pub const MAV_AUTOPILOT_INVALID: u8 = 8;
+pub const MAV_TYPE_GCS: u8 = 6;
+
+pub const MAV_MODE_FLAG_CUSTOM_MODE_ENABLED: u8 = 1;
+pub const MAV_MODE_FLAG_SAFETY_ARMED: u8 = 128;
+
+const PX4_MAIN_MODE_AUTO: u8 = 4;
+
+/// (name, main mode, sub mode) for each of the PX4 flight modes
+const PX4_MODES: &'static [(&'static str, u8, u8)] = &[
+    ("MANUAL", 1, 0),
+    ("ALTCTL", 2, 0),
+    ("POSCTL", 3, 0),
+    ("AUTO.READY", 4, 1),
+    ("AUTO.TAKEOFF", 4, 2),
+    ("AUTO.LOITER", 4, 3),
+    ("AUTO.MISSION", 4, 4),
+    ("AUTO.RTL", 4, 5),
+    ("AUTO.LAND", 4, 6),
+    ("AUTO.RTGS", 4, 7),
+    ("AUTO.FOLLOW_TARGET", 4, 8),
+    ("ACRO", 5, 0),
+    ("OFFBOARD", 6, 0),
+    ("STABILIZED", 7, 0),
+    ("RATTITUDE", 8, 0),
+];
+
+/// Gets the name of a PX4 flight mode from a HEARTBEAT `custom_mode` field
+pub fn px4_mode_name(custom_mode: u32) -> Option<&'static str> {
+    let main_mode = ((custom_mode >> 16) & 0xFF) as u8;
+    let sub_mode = ((custom_mode >> 24) & 0xFF) as u8;
+
+    PX4_MODES.iter()
+        .find(|&&(_, main, sub)| {
+            main == main_mode && (main != PX4_MAIN_MODE_AUTO || sub == sub_mode)
+        })
+        .map(|&(name, _, _)| name)
+}
+
+/// Gets the (main mode, sub mode) pair for a named PX4 flight mode
+pub fn px4_mode(name: &str) -> Option<(u8, u8)> {
+    let name = name.to_uppercase();
+    PX4_MODES.iter()
+        .find(|&&(mode_name, _, _)| mode_name == name)
+        .map(|&(_, main, sub)| (main, sub))
+}
@@ -0,0 +1,198 @@
+//! Conversions between WGS84 geodetic coordinates and a local East-North-Up (ENU) tangent plane.
+
+/// WGS84 semi-major axis
+const WGS84_A: f64 = 6378137.0;
+
+/// WGS84 flattening
+const WGS84_F: f64 = 1.0 / 298.257223563;
+
+/// WGS84 first eccentricity squared
+const WGS84_E2: f64 = WGS84_F * (2.0 - WGS84_F);
+
+/// A geodetic coordinate, with latitude and longitude in degrees and altitude in meters.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Coordinate {
+    pub lat: f64,
+    pub lon: f64,
+    pub alt: f64,
+}
+
+/// Converts a geodetic coordinate to Earth-Centered, Earth-Fixed coordinates
+pub fn geodetic_to_ecef(coordinate: Coordinate) -> [f64; 3] {
+    let (sin_lat, cos_lat) = coordinate.lat.to_radians().sin_cos();
+    let (sin_lon, cos_lon) = coordinate.lon.to_radians().sin_cos();
+
+    // Prime vertical radius of curvature
+    let n = WGS84_A / (1.0 - WGS84_E2 * sin_lat * sin_lat).sqrt();
+
+    [
+        (n + coordinate.alt) * cos_lat * cos_lon,
+        (n + coordinate.alt) * cos_lat * sin_lon,
+        (n * (1.0 - WGS84_E2) + coordinate.alt) * sin_lat,
+    ]
+}
+
+/// Converts Earth-Centered, Earth-Fixed coordinates to a geodetic coordinate
+pub fn ecef_to_geodetic(ecef: [f64; 3]) -> Coordinate {
+    let (x, y, z) = (ecef[0], ecef[1], ecef[2]);
+    let p = (x * x + y * y).sqrt();
+    let lon = y.atan2(x);
+
+    // Iteratively refine the latitude, this converges to well below a millimeter within a few
+    // iterations for any point near the surface of the earth.
+    let mut lat = z.atan2(p * (1.0 - WGS84_E2));
+    let mut alt = 0.0;
+    for _ in 0..6 {
+        let sin_lat = lat.sin();
+        let n = WGS84_A / (1.0 - WGS84_E2 * sin_lat * sin_lat).sqrt();
+
+        alt = if lat.cos().abs() > 1e-10 {
+            p / lat.cos() - n
+        }
+        else {
+            z.abs() - n * (1.0 - WGS84_E2)
+        };
+        lat = z.atan2(p * (1.0 - WGS84_E2 * n / (n + alt)));
+    }
+
+    Coordinate { lat: lat.to_degrees(), lon: lon.to_degrees(), alt: alt }
+}
+
+/// A local East-North-Up frame tangent to the WGS84 ellipsoid at an origin point
+#[derive(Debug, Copy, Clone)]
+pub struct LocalFrame {
+    origin: Coordinate,
+    origin_ecef: [f64; 3],
+    sin_lat: f64,
+    cos_lat: f64,
+    sin_lon: f64,
+    cos_lon: f64,
+}
+
+impl LocalFrame {
+    pub fn new(origin: Coordinate) -> LocalFrame {
+        let (sin_lat, cos_lat) = origin.lat.to_radians().sin_cos();
+        let (sin_lon, cos_lon) = origin.lon.to_radians().sin_cos();
+
+        LocalFrame {
+            origin: origin,
+            origin_ecef: geodetic_to_ecef(origin),
+            sin_lat: sin_lat,
+            cos_lat: cos_lat,
+            sin_lon: sin_lon,
+            cos_lon: cos_lon,
+        }
+    }
+
+    pub fn origin(&self) -> Coordinate {
+        self.origin
+    }
+
+    /// Converts a geodetic coordinate to [east, north, up] offsets (in meters) from the origin
+    pub fn to_enu(&self, coordinate: Coordinate) -> [f64; 3] {
+        let ecef = geodetic_to_ecef(coordinate);
+        let dx = ecef[0] - self.origin_ecef[0];
+        let dy = ecef[1] - self.origin_ecef[1];
+        let dz = ecef[2] - self.origin_ecef[2];
+
+        let (sin_lat, cos_lat, sin_lon, cos_lon) =
+            (self.sin_lat, self.cos_lat, self.sin_lon, self.cos_lon);
+
+        [
+            -sin_lon * dx + cos_lon * dy,
+            -sin_lat * cos_lon * dx - sin_lat * sin_lon * dy + cos_lat * dz,
+            cos_lat * cos_lon * dx + cos_lat * sin_lon * dy + sin_lat * dz,
+        ]
+    }
+
+    /// Converts [east, north, up] offsets (in meters) from the origin to a geodetic coordinate
+    pub fn from_enu(&self, enu: [f64; 3]) -> Coordinate {
+        let (e, n, u) = (enu[0], enu[1], enu[2]);
+
+        let (sin_lat, cos_lat, sin_lon, cos_lon) =
+            (self.sin_lat, self.cos_lat, self.sin_lon, self.cos_lon);
+
+        let dx = -sin_lon * e - sin_lat * cos_lon * n + cos_lat * cos_lon * u;
+        let dy = cos_lon * e - sin_lat * sin_lon * n + cos_lat * sin_lon * u;
+        let dz = cos_lat * n + sin_lat * u;
+
+        ecef_to_geodetic([
+            self.origin_ecef[0] + dx,
+            self.origin_ecef[1] + dy,
+            self.origin_ecef[2] + dz,
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Coordinate, LocalFrame, ecef_to_geodetic, geodetic_to_ecef};
+
+    const LATITUDES: [f64; 9] = [0.0, 35.0, -35.0, 60.0, -60.0, 89.9, -89.9, 89.9999, -89.9999];
+
+    fn assert_close(a: Coordinate, b: Coordinate) {
+        // 1e-9 degrees is about 0.1 mm
+        assert!((a.lat - b.lat).abs() < 1e-9, "{:?} != {:?}", a, b);
+        assert!((a.lon - b.lon).abs() < 1e-9, "{:?} != {:?}", a, b);
+        assert!((a.alt - b.alt).abs() < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn ecef_round_trip() {
+        for &lat in &LATITUDES {
+            for &lon in &[0.0, 153.0251, -71.5] {
+                for &alt in &[-50.0, 0.0, 120.0, 3000.0] {
+                    let coordinate = Coordinate { lat: lat, lon: lon, alt: alt };
+                    assert_close(ecef_to_geodetic(geodetic_to_ecef(coordinate)), coordinate);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn enu_round_trip() {
+        for &lat in &LATITUDES {
+            let frame = LocalFrame::new(Coordinate { lat: lat, lon: 153.0251, alt: 30.0 });
+
+            for &enu in &[[0.0, 0.0, 0.0], [250.0, -400.0, 50.0], [-1500.0, 2000.0, -20.0]] {
+                let coordinate = frame.from_enu(enu);
+                let result = frame.to_enu(coordinate);
+                for i in 0..3 {
+                    assert!((result[i] - enu[i]).abs() < 1e-4, "{:?} != {:?} at {}", result,
+                        enu, lat);
+                }
+                assert_close(frame.from_enu(result), coordinate);
+            }
+        }
+    }
+
+    #[test]
+    fn origin_is_zero() {
+        for &lat in &LATITUDES {
+            let origin = Coordinate { lat: lat, lon: -71.5, alt: 120.0 };
+            let enu = LocalFrame::new(origin).to_enu(origin);
+            assert!(enu.iter().all(|x| x.abs() < 1e-6), "{:?} at {}", enu, lat);
+        }
+    }
+
+    #[test]
+    fn east_west_scaling() {
+        // One degree of longitude is 111319.49 m at the equator, and 55800.00 m at 60 degrees
+        for &(lat, metres_per_degree) in &[(0.0, 111319.49), (60.0, 55800.00), (-60.0, 55800.00)] {
+            let frame = LocalFrame::new(Coordinate { lat: lat, lon: 10.0, alt: 0.0 });
+            let enu = frame.to_enu(Coordinate { lat: lat, lon: 10.01, alt: 0.0 });
+            assert!((enu[0] - metres_per_degree * 0.01).abs() < 0.01, "{:?} at {}", enu, lat);
+            // The curvature of the earth moves the point slightly down (and north of the origin
+            // away from the equator)
+            assert!(enu[1].abs() < 0.2 && enu[2].abs() < 0.2, "{:?} at {}", enu, lat);
+        }
+    }
+
+    #[test]
+    fn north_south_distance() {
+        // One degree of latitude is 110574.3 m at the equator
+        let frame = LocalFrame::new(Coordinate { lat: 0.0, lon: 10.0, alt: 0.0 });
+        let enu = frame.to_enu(Coordinate { lat: 0.01, lon: 10.0, alt: 0.0 });
+        assert!((enu[1] - 1105.743).abs() < 0.01, "{:?}", enu);
+    }
+}
@@ -0,0 +1,370 @@
+//! Geofence checks for the targets sent to the vehicle. Polygons are specified in the same local
+//! frame as `Location`, and altitudes are the same altitude above mean sea level as `Location.alt`.
+//!
+//! The fence is only enforced by the telemetry host, it is not uploaded to the autopilot.
+
+use std::f32;
+use std::sync::Mutex;
+
+use mavlink_handler::Location;
+use planner;
+
+/// The distance (in meters) that clamped targets are moved inside the permitted area, so that they
+/// do not sit exactly on the boundary of the fence.
+const CLAMP_MARGIN: f32 = 0.5;
+
+/// What to do with a target that is outside the fence
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum FenceAction {
+    /// Refuse to send the target to the vehicle
+    Reject,
+    /// Move the target to the nearest permitted position
+    Clamp,
+}
+
+impl Default for FenceAction {
+    fn default() -> FenceAction {
+        FenceAction::Reject
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Geofence {
+    /// The polygon that targets must be inside of, as [x, y]. No inclusion area is enforced if
+    /// this is empty.
+    #[serde(default)]
+    pub inclusion: Vec<[f32; 2]>,
+
+    /// Polygons that targets must be outside of
+    #[serde(default)]
+    pub exclusions: Vec<Vec<[f32; 2]>>,
+
+    #[serde(default)]
+    pub min_alt: Option<f32>,
+
+    #[serde(default)]
+    pub max_alt: Option<f32>,
+
+    #[serde(default)]
+    pub action: FenceAction,
+}
+
+/// A target that has passed the fence checks
+#[derive(Debug, Clone, Serialize)]
+pub struct FenceCheck {
+    /// The target to send to the vehicle
+    pub target: Location,
+    /// The reason the target was moved, if it was clamped to the fence
+    pub clamped: Option<String>,
+}
+
+impl Geofence {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.inclusion.is_empty() {
+            try!(validate_polygon(&self.inclusion).map_err(|e| format!("Inclusion area: {}", e)));
+        }
+
+        for (i, exclusion) in self.exclusions.iter().enumerate() {
+            try!(validate_polygon(exclusion).map_err(|e| format!("Exclusion zone {}: {}", i, e)));
+        }
+
+        match (self.min_alt, self.max_alt) {
+            (Some(min), _) if !min.is_finite() => {
+                Err(format!("Invalid minimum altitude `{}`", min))
+            },
+            (_, Some(max)) if !max.is_finite() => {
+                Err(format!("Invalid maximum altitude `{}`", max))
+            },
+            (Some(min), Some(max)) if min > max => {
+                Err(format!("Minimum altitude `{}` is above the maximum altitude `{}`", min, max))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the reason the target is outside of the fence, or `None` if it is permitted
+    pub fn violation(&self, target: &Location) -> Option<String> {
+        if !target.x.is_finite() || !target.y.is_finite() || !target.alt.is_finite() {
+            return Some(format!("Invalid target: {:?}", target));
+        }
+
+        if let Some(min) = self.min_alt {
+            if target.alt < min {
+                return Some(format!("Altitude {} is below the minimum altitude of {}",
+                    target.alt, min));
+            }
+        }
+
+        if let Some(max) = self.max_alt {
+            if target.alt > max {
+                return Some(format!("Altitude {} is above the maximum altitude of {}",
+                    target.alt, max));
+            }
+        }
+
+        self.horizontal_violation(target.x, target.y)
+    }
+
+    /// Returns the reason a horizontal position is outside of the fence, ignoring the altitude
+    /// limits, or `None` if it is permitted
+    pub fn horizontal_violation(&self, x: f32, y: f32) -> Option<String> {
+        if !x.is_finite() || !y.is_finite() {
+            return Some(format!("Invalid position: ({}, {})", x, y));
+        }
+
+        let point = [x, y];
+        if !self.inclusion.is_empty() && !planner::contains(&self.inclusion, point) {
+            return Some(format!("({}, {}) is outside of the inclusion area", x, y));
+        }
+
+        for (i, exclusion) in self.exclusions.iter().enumerate() {
+            if planner::contains(exclusion, point) {
+                return Some(format!("({}, {}) is inside exclusion zone {}", x, y, i));
+            }
+        }
+
+        None
+    }
+
+    /// Checks a target against the fence, clamping it to the nearest permitted position if the
+    /// fence action is `Clamp`.
+    pub fn check(&self, target: Location) -> Result<FenceCheck, String> {
+        let reason = match self.violation(&target) {
+            Some(reason) => reason,
+            None => return Ok(FenceCheck { target: target, clamped: None }),
+        };
+
+        let finite = target.x.is_finite() && target.y.is_finite() && target.alt.is_finite();
+        if self.action == FenceAction::Reject || !finite {
+            return Err(reason);
+        }
+
+        let mut clamped = target.clone();
+        if let Some(min) = self.min_alt {
+            clamped.alt = clamped.alt.max(min);
+        }
+        if let Some(max) = self.max_alt {
+            clamped.alt = clamped.alt.min(max);
+        }
+
+        let mut point = [clamped.x, clamped.y];
+        if !self.inclusion.is_empty() && !planner::contains(&self.inclusion, point) {
+            point = clamp_to_boundary(&self.inclusion, point, true);
+        }
+        for exclusion in &self.exclusions {
+            if planner::contains(exclusion, point) {
+                point = clamp_to_boundary(exclusion, point, false);
+            }
+        }
+        clamped.x = point[0];
+        clamped.y = point[1];
+
+        // Clamping to one polygon may have moved the target into another
+        if let Some(e) = self.violation(&clamped) {
+            return Err(format!("{} (no permitted position nearby: {})", reason, e));
+        }
+
+        Ok(FenceCheck { target: clamped, clamped: Some(reason) })
+    }
+}
+
+lazy_static! {
+    static ref GEOFENCE: Mutex<Geofence> = Mutex::new(Geofence::default());
+}
+
+pub fn get() -> Geofence {
+    GEOFENCE.lock().unwrap().clone()
+}
+
+/// Replaces the current geofence
+pub fn set(fence: Geofence) -> Result<(), String> {
+    try!(fence.validate());
+    *GEOFENCE.lock().unwrap() = fence;
+    Ok(())
+}
+
+/// Checks a target against the current geofence
+pub fn check(target: Location) -> Result<FenceCheck, String> {
+    GEOFENCE.lock().unwrap().check(target)
+}
+
+fn validate_polygon(polygon: &[[f32; 2]]) -> Result<(), String> {
+    if polygon.len() < 3 {
+        return Err("Polygons must have at least 3 vertices".into());
+    }
+    if polygon.iter().any(|p| !p[0].is_finite() || !p[1].is_finite()) {
+        return Err("Polygon contains an invalid vertex".into());
+    }
+    Ok(())
+}
+
+/// Moves a point to the nearest point on the boundary of the polygon, offset by `CLAMP_MARGIN`
+/// to the inside (or outside) of the polygon.
+fn clamp_to_boundary(polygon: &[[f32; 2]], point: [f32; 2], inside: bool) -> [f32; 2] {
+    let mut nearest = point;
+    let mut nearest_distance = f32::INFINITY;
+    let mut normal = [0.0, 0.0];
+
+    let mut j = polygon.len() - 1;
+    for i in 0..polygon.len() {
+        let (a, b) = (polygon[j], polygon[i]);
+        let edge = [b[0] - a[0], b[1] - a[1]];
+        let length_sq = edge[0] * edge[0] + edge[1] * edge[1];
+
+        let t = if length_sq > 0.0 {
+            (((point[0] - a[0]) * edge[0] + (point[1] - a[1]) * edge[1]) / length_sq)
+                .max(0.0).min(1.0)
+        }
+        else {
+            0.0
+        };
+
+        let candidate = [a[0] + t * edge[0], a[1] + t * edge[1]];
+        let distance = (candidate[0] - point[0]).hypot(candidate[1] - point[1]);
+        if distance < nearest_distance && length_sq > 0.0 {
+            let length = length_sq.sqrt();
+            nearest = candidate;
+            nearest_distance = distance;
+            normal = [-edge[1] / length, edge[0] / length];
+        }
+        j = i;
+    }
+
+    // The normal may point either way depending on the winding of the polygon
+    let offset = |sign: f32| {
+        [nearest[0] + sign * normal[0] * CLAMP_MARGIN, nearest[1] + sign * normal[1] * CLAMP_MARGIN]
+    };
+
+    if planner::contains(polygon, offset(1.0)) == inside { offset(1.0) } else { offset(-1.0) }
+}
+
+#[cfg(test)]
+mod tests {
+    use mavlink_handler::Location;
+    use planner;
+
+    use super::{CLAMP_MARGIN, FenceAction, Geofence, clamp_to_boundary};
+
+    const SQUARE: [[f32; 2]; 4] =
+        [[-100.0, -100.0], [100.0, -100.0], [100.0, 100.0], [-100.0, 100.0]];
+
+    /// A 200 m square fence around the origin, with an exclusion zone from (20, 20) to (40, 40)
+    /// and altitude limits of 10 m to 120 m
+    fn fence(action: FenceAction) -> Geofence {
+        Geofence {
+            inclusion: SQUARE.to_vec(),
+            exclusions: vec![vec![[20.0, 20.0], [40.0, 20.0], [40.0, 40.0], [20.0, 40.0]]],
+            min_alt: Some(10.0),
+            max_alt: Some(120.0),
+            action: action,
+        }
+    }
+
+    fn location(x: f32, y: f32, alt: f32) -> Location {
+        Location { x: x, y: y, alt: alt }
+    }
+
+    fn assert_close(location: &Location, expected: Location) {
+        let error = (location.x - expected.x).abs() + (location.y - expected.y).abs() +
+            (location.alt - expected.alt).abs();
+        assert!(error < 1e-3, "{:?} != {:?}", location, expected);
+    }
+
+    #[test]
+    fn permitted_targets() {
+        let fence = fence(FenceAction::Reject);
+        assert!(fence.validate().is_ok());
+        assert!(fence.violation(&location(0.0, 0.0, 50.0)).is_none());
+        assert!(fence.violation(&location(50.0, 30.0, 10.0)).is_none());
+
+        let check = fence.check(location(0.0, 0.0, 50.0)).unwrap();
+        assert_close(&check.target, location(0.0, 0.0, 50.0));
+        assert!(check.clamped.is_none());
+
+        // An empty fence permits everything
+        assert!(Geofence::default().violation(&location(1e6, -1e6, 1e4)).is_none());
+    }
+
+    #[test]
+    fn horizontal_violations() {
+        let fence = fence(FenceAction::Reject);
+
+        let outside = fence.violation(&location(150.0, 0.0, 50.0)).unwrap();
+        assert!(outside.contains("inclusion"), "{}", outside);
+        let excluded = fence.violation(&location(30.0, 30.0, 50.0)).unwrap();
+        assert!(excluded.contains("exclusion zone 0"), "{}", excluded);
+
+        // The horizontal check ignores the altitude limits
+        assert!(fence.horizontal_violation(0.0, 0.0).is_none());
+        assert!(fence.horizontal_violation(30.0, 30.0).is_some());
+        assert!(fence.horizontal_violation(150.0, 0.0).is_some());
+    }
+
+    #[test]
+    fn altitude_limits() {
+        let fence = fence(FenceAction::Reject);
+
+        let low = fence.violation(&location(0.0, 0.0, 5.0)).unwrap();
+        assert!(low.contains("below"), "{}", low);
+        let high = fence.violation(&location(0.0, 0.0, 130.0)).unwrap();
+        assert!(high.contains("above"), "{}", high);
+
+        assert!(fence.violation(&location(0.0, 0.0, ::std::f32::NAN)).is_some());
+    }
+
+    #[test]
+    fn reject_action() {
+        let fence = fence(FenceAction::Reject);
+        assert!(fence.check(location(150.0, 0.0, 50.0)).is_err());
+        assert!(fence.check(location(30.0, 30.0, 50.0)).is_err());
+        assert!(fence.check(location(0.0, 0.0, 130.0)).is_err());
+    }
+
+    #[test]
+    fn clamp_action() {
+        let fence = fence(FenceAction::Clamp);
+
+        let outside = fence.check(location(150.0, 0.0, 50.0)).unwrap();
+        assert_close(&outside.target, location(100.0 - CLAMP_MARGIN, 0.0, 50.0));
+        assert!(outside.clamped.is_some());
+
+        // The nearest edge of the exclusion zone is y = 20
+        let excluded = fence.check(location(30.0, 25.0, 50.0)).unwrap();
+        assert_close(&excluded.target, location(30.0, 20.0 - CLAMP_MARGIN, 50.0));
+
+        let high = fence.check(location(0.0, 0.0, 130.0)).unwrap();
+        assert_close(&high.target, location(0.0, 0.0, 120.0));
+        let low = fence.check(location(-150.0, 0.0, 0.0)).unwrap();
+        assert_close(&low.target, location(-100.0 + CLAMP_MARGIN, 0.0, 10.0));
+
+        // Invalid targets can't be clamped
+        assert!(fence.check(location(::std::f32::NAN, 0.0, 50.0)).is_err());
+    }
+
+    #[test]
+    fn clamp_without_permitted_position() {
+        // The exclusion zone covers the whole inclusion area
+        let mut fence = fence(FenceAction::Clamp);
+        fence.exclusions = vec![vec![[-200.0, -200.0], [200.0, -200.0], [200.0, 200.0],
+            [-200.0, 200.0]]];
+        assert!(fence.check(location(0.0, 0.0, 50.0)).is_err());
+    }
+
+    #[test]
+    fn clamp_to_boundary_winding() {
+        let mut reversed = SQUARE.to_vec();
+        reversed.reverse();
+
+        for polygon in &[SQUARE.to_vec(), reversed] {
+            let inside = clamp_to_boundary(polygon, [130.0, 20.0], true);
+            assert!((inside[0] - (100.0 - CLAMP_MARGIN)).abs() < 1e-3, "{:?}", inside);
+            assert!((inside[1] - 20.0).abs() < 1e-3, "{:?}", inside);
+            assert!(planner::contains(polygon, inside));
+
+            let outside = clamp_to_boundary(polygon, [20.0, 90.0], false);
+            assert!((outside[0] - 20.0).abs() < 1e-3, "{:?}", outside);
+            assert!((outside[1] - (100.0 + CLAMP_MARGIN)).abs() < 1e-3, "{:?}", outside);
+            assert!(!planner::contains(polygon, outside));
+        }
+    }
+}
@@ -5,19 +5,53 @@ extern crate byteorder;
 extern crate common;
 #[macro_use] extern crate lazy_static;
 extern crate mavlink;
+extern crate pulse_protocol;
 extern crate rocket;
 extern crate rocket_contrib;
 extern crate serde;
 #[macro_use] extern crate serde_derive;
 extern crate serde_json;
 
+mod api;
+mod clock;
+mod commands;
+mod config;
+mod flight_mode;
+mod geodetic;
+mod geofence;
+mod pulse_config;
 mod pulse_handler;
 mod mavlink_handler;
+mod mission;
+mod planner;
+mod search;
+mod session;
+mod storage;
+mod stream;
+mod vehicle;
 
+use std::io::ErrorKind;
+use std::process;
+use std::time::Duration;
+
+use common::{Config, PulseTarget};
+use pulse_protocol::PulseFilter;
 use rocket_contrib::JSON;
 
-use mavlink_handler::{Telemetry, Location, MavlinkHandle};
-use pulse_handler::{PulseWithTelemetry, PulseHandle};
+use api::ApiResult;
+use clock::ClockStatus;
+use commands::CommandState;
+use config::HostConfig;
+use geodetic::Coordinate;
+use geofence::Geofence;
+use mavlink_handler::{Telemetry, Location, Origin, MavlinkHandle, Reposition};
+use mission::MissionItem;
+use planner::SearchArea;
+use pulse_config::GainSettings;
+use pulse_handler::{PulseWithTelemetry, PulseClientStatus, PulseHandle};
+use search::{SearchExecution, SearchStatus};
+use session::{PulsePage, PulseQuery, SessionInfo, SessionStart, TrackPoint};
+use vehicle::{ModeChange, Takeoff};
 
 #[get("/")]
 fn get_telemetry() -> JSON<Telemetry> {
@@ -25,19 +59,396 @@ fn get_telemetry() -> JSON<Telemetry> {
 }
 
 #[post("/", data = "<location>")]
-fn do_reposition(location: JSON<Location>) {
+fn do_reposition(location: JSON<Location>) -> ApiResult<Reposition> {
     let target = location.unwrap();
-    mavlink_handler::do_reposition(target);
+    match mavlink_handler::do_reposition(target) {
+        Ok(reposition) => Ok(JSON(reposition)),
+        Err(e) => api::conflict(e),
+    }
+}
+
+/// The maximum time a client can wait for a command to complete
+const COMMAND_WAIT_SECS: u64 = 10;
+
+#[get("/commands")]
+fn get_commands() -> JSON<Vec<CommandState>> {
+    JSON(commands::get_all())
+}
+
+#[get("/commands/<id>")]
+fn get_command(id: u64) -> ApiResult<CommandState> {
+    match commands::get(id) {
+        Some(state) => Ok(JSON(state)),
+        None => api::not_found(format!("Unknown command: {}", id)),
+    }
+}
+
+#[get("/commands/<id>/wait")]
+fn wait_for_command(id: u64) -> ApiResult<CommandState> {
+    match commands::wait(id, Duration::from_secs(COMMAND_WAIT_SECS)) {
+        Some(state) => Ok(JSON(state)),
+        None => api::not_found(format!("Unknown command: {}", id)),
+    }
+}
+
+/// Converts the result of a vehicle action into a response, rejected actions are reported as
+/// conflicts with the current state of the vehicle.
+fn vehicle_response(result: Result<CommandState, String>) -> ApiResult<CommandState> {
+    match result {
+        Ok(state) => Ok(JSON(state)),
+        Err(e) => api::conflict(e),
+    }
+}
+
+#[post("/vehicle/arm")]
+fn arm() -> ApiResult<CommandState> {
+    vehicle_response(vehicle::arm())
+}
+
+#[post("/vehicle/disarm")]
+fn disarm() -> ApiResult<CommandState> {
+    vehicle_response(vehicle::disarm(false))
+}
+
+#[post("/vehicle/disarm/force")]
+fn force_disarm() -> ApiResult<CommandState> {
+    vehicle_response(vehicle::disarm(true))
+}
+
+#[post("/vehicle/takeoff", data = "<takeoff>")]
+fn takeoff(takeoff: JSON<Takeoff>) -> ApiResult<CommandState> {
+    vehicle_response(vehicle::takeoff(takeoff.unwrap()))
+}
+
+#[post("/vehicle/land")]
+fn land() -> ApiResult<CommandState> {
+    vehicle_response(vehicle::land())
+}
+
+#[post("/vehicle/rtl")]
+fn return_to_launch() -> ApiResult<CommandState> {
+    vehicle_response(vehicle::return_to_launch())
+}
+
+#[post("/vehicle/hold")]
+fn hold() -> ApiResult<CommandState> {
+    vehicle_response(vehicle::hold())
+}
+
+#[put("/vehicle/mode", data = "<change>")]
+fn set_mode(change: JSON<ModeChange>) -> ApiResult<CommandState> {
+    vehicle_response(vehicle::set_mode(change.unwrap()))
+}
+
+#[get("/mission")]
+fn download_mission() -> ApiResult<Vec<MissionItem>> {
+    match mission::download_mission() {
+        Ok(items) => Ok(JSON(items)),
+        Err(e) => api::unavailable(e),
+    }
+}
+
+#[put("/mission", data = "<items>")]
+fn upload_mission(items: JSON<Vec<MissionItem>>) -> ApiResult<()> {
+    match mission::upload_mission(items.unwrap()) {
+        Ok(()) => Ok(JSON(())),
+        Err(e) => api::unavailable(e),
+    }
+}
+
+#[delete("/mission")]
+fn clear_mission() -> ApiResult<()> {
+    match mission::clear_mission() {
+        Ok(()) => Ok(JSON(())),
+        Err(e) => api::unavailable(e),
+    }
+}
+
+#[post("/search/preview", data = "<area>")]
+fn preview_search(area: JSON<SearchArea>) -> ApiResult<Vec<Location>> {
+    match planner::plan(&area.unwrap()) {
+        Ok(waypoints) => Ok(JSON(waypoints)),
+        Err(e) => api::bad_request(e),
+    }
+}
+
+#[post("/search", data = "<execution>")]
+fn execute_search(execution: JSON<SearchExecution>) -> ApiResult<SearchStatus> {
+    match search::execute(execution.unwrap()) {
+        Ok(status) => Ok(JSON(status)),
+        Err(e) => api::conflict(e),
+    }
+}
+
+#[get("/search")]
+fn get_search() -> JSON<SearchStatus> {
+    JSON(search::get_status())
+}
+
+#[delete("/search")]
+fn cancel_search() -> JSON<SearchStatus> {
+    JSON(search::cancel())
+}
+
+#[get("/geofence")]
+fn get_geofence() -> JSON<Geofence> {
+    JSON(geofence::get())
+}
+
+#[put("/geofence", data = "<fence>")]
+fn set_geofence(fence: JSON<Geofence>) -> ApiResult<Geofence> {
+    match geofence::set(fence.unwrap()) {
+        Ok(()) => Ok(JSON(geofence::get())),
+        Err(e) => api::bad_request(e),
+    }
+}
+
+#[delete("/geofence")]
+fn clear_geofence() -> JSON<Geofence> {
+    geofence::set(Geofence::default()).unwrap();
+    JSON(geofence::get())
+}
+
+#[get("/origin")]
+fn get_origin() -> ApiResult<Origin> {
+    match mavlink_handler::get_origin() {
+        Some(origin) => Ok(JSON(origin)),
+        None => api::not_found("The origin has not been set yet"),
+    }
+}
+
+#[put("/origin", data = "<coordinate>")]
+fn set_origin(coordinate: JSON<Coordinate>) -> ApiResult<Origin> {
+    let coordinate = coordinate.unwrap();
+    if let Err(e) = config::validate_coordinate(&coordinate) {
+        return api::bad_request(e);
+    }
+
+    mavlink_handler::set_origin(coordinate);
+    get_origin()
+}
+
+#[post("/origin/home")]
+fn use_home_origin() {
+    mavlink_handler::use_home_origin();
+}
+
+#[post("/origin/first_fix")]
+fn use_first_fix_origin() {
+    mavlink_handler::use_first_fix_origin();
 }
 
 #[get("pulses/<index>")]
-fn get_pulses(index: usize) -> JSON<Vec<PulseWithTelemetry>> {
-    JSON(pulse_handler::get_pulses_since(index))
+fn get_pulses(index: u64) -> JSON<Vec<PulseWithTelemetry>> {
+    JSON(session::get_active_pulses(index))
+}
+
+#[get("/pulses?<query>")]
+fn query_pulses(query: PulseQuery) -> ApiResult<PulsePage> {
+    if let Err(e) = query.validate() {
+        return api::bad_request(e);
+    }
+
+    match session::query_pulses(&query) {
+        Ok(page) => Ok(JSON(page)),
+        Err(e) => api::not_found(e),
+    }
+}
+
+#[get("/session")]
+fn get_active_session() -> ApiResult<SessionInfo> {
+    match session::active() {
+        Some(info) => Ok(JSON(info)),
+        None => api::not_found("No session is recording"),
+    }
+}
+
+#[post("/session", data = "<request>")]
+fn start_session(request: JSON<SessionStart>) -> ApiResult<SessionInfo> {
+    match session::start(request.unwrap()) {
+        Ok(info) => Ok(JSON(info)),
+        Err(e) => api::conflict(e),
+    }
+}
+
+#[delete("/session")]
+fn stop_session() -> ApiResult<SessionInfo> {
+    match session::stop() {
+        Ok(info) => Ok(JSON(info)),
+        Err(e) => api::conflict(e),
+    }
+}
+
+#[get("/sessions")]
+fn list_sessions() -> ApiResult<Vec<SessionInfo>> {
+    match session::list() {
+        Ok(sessions) => Ok(JSON(sessions)),
+        Err(e) => api::unavailable(e),
+    }
+}
+
+#[get("/sessions/<name>")]
+fn get_session(name: String) -> ApiResult<SessionInfo> {
+    match session::get(&name) {
+        Ok(info) => Ok(JSON(info)),
+        Err(e) => api::not_found(e),
+    }
+}
+
+#[get("/sessions/<name>/pulses/<index>")]
+fn get_session_pulses(name: String, index: u64) -> ApiResult<Vec<PulseWithTelemetry>> {
+    match session::get_pulses(&name, index) {
+        Ok(pulses) => Ok(JSON(pulses)),
+        Err(e) => api::not_found(e),
+    }
+}
+
+#[get("/sessions/<name>/track/<index>")]
+fn get_session_track(name: String, index: u64) -> ApiResult<Vec<TrackPoint>> {
+    match session::get_track(&name, index) {
+        Ok(track) => Ok(JSON(track)),
+        Err(e) => api::not_found(e),
+    }
+}
+
+#[get("/pulse_server/status")]
+fn get_pulse_server_status() -> JSON<PulseClientStatus> {
+    JSON(pulse_handler::get_status())
+}
+
+#[get("/pulse_server/config")]
+fn get_pulse_config() -> ApiResult<Config> {
+    match pulse_handler::active_config() {
+        Some(config) => Ok(JSON(config)),
+        None => api::unavailable("The pulse server's config is not known until it has connected"),
+    }
+}
+
+/// Sends a new config to the pulse server, restarting detection with the new config
+fn update_pulse_config(config: Config) -> ApiResult<Config> {
+    if let Err(e) = pulse_config::validate(&config) {
+        return api::bad_request(e);
+    }
+
+    // The config is kept even if it can't be sent yet, since it is sent to the pulse server
+    // whenever the stream is (re)started. It is discarded if the server won't accept it.
+    let result = pulse_handler::set_config(Some(config.clone()));
+    if let Err(ref e) = result {
+        if e.kind() == ErrorKind::PermissionDenied {
+            return api::conflict(format!("Config not sent to the pulse server: {}", e));
+        }
+    }
+    session::set_pulse_config(Some(config.clone()));
+
+    match result {
+        Ok(()) => Ok(JSON(config)),
+        Err(e) => {
+            api::unavailable(format!("Config saved, but not sent to the pulse server: {}", e))
+        },
+    }
+}
+
+#[put("/pulse_server/config", data = "<config>")]
+fn set_pulse_config(config: JSON<Config>) -> ApiResult<Config> {
+    update_pulse_config(config.unwrap())
+}
+
+#[put("/pulse_server/config/targets", data = "<targets>")]
+fn set_pulse_targets(targets: JSON<Vec<PulseTarget>>) -> ApiResult<Config> {
+    let config = match pulse_handler::current_config() {
+        Some(config) => config,
+        None => return api::unavailable("The pulse server's config is not known until it has \
+            connected"),
+    };
+
+    match pulse_config::with_targets(&config, targets.unwrap()) {
+        Ok(config) => update_pulse_config(config),
+        Err(e) => api::bad_request(e),
+    }
+}
+
+#[put("/pulse_server/config/gain", data = "<gain>")]
+fn set_pulse_gain(gain: JSON<GainSettings>) -> ApiResult<Config> {
+    let config = match pulse_handler::current_config() {
+        Some(config) => config,
+        None => return api::unavailable("The pulse server's config is not known until it has \
+            connected"),
+    };
+
+    match pulse_config::with_gain(&config, &gain.unwrap()) {
+        Ok(config) => update_pulse_config(config),
+        Err(e) => api::bad_request(e),
+    }
+}
+
+#[post("/pulse_server/stop")]
+fn stop_detection() -> ApiResult<PulseClientStatus> {
+    match pulse_handler::stop_detection() {
+        Ok(()) => Ok(JSON(pulse_handler::get_status())),
+        Err(e) => api::unavailable(e.to_string()),
+    }
+}
+
+#[post("/pulse_server/start")]
+fn start_detection() -> ApiResult<PulseClientStatus> {
+    match pulse_handler::start_detection() {
+        Ok(()) => Ok(JSON(pulse_handler::get_status())),
+        Err(e) => api::unavailable(e.to_string()),
+    }
+}
+
+#[get("/pulse_server/subscription")]
+fn get_pulse_subscription() -> JSON<PulseFilter> {
+    JSON(pulse_handler::get_subscription())
+}
+
+#[put("/pulse_server/subscription", data = "<filter>")]
+fn set_pulse_subscription(filter: JSON<PulseFilter>) -> ApiResult<PulseFilter> {
+    let filter = filter.unwrap();
+    if let Err(e) = filter.validate() {
+        return api::bad_request(e);
+    }
+
+    match pulse_handler::set_subscription(filter.clone()) {
+        Ok(()) => Ok(JSON(filter)),
+        Err(e) => {
+            api::unavailable(format!("Subscription saved, but not sent to the pulse server: {}", e))
+        },
+    }
+}
+
+#[get("/clock")]
+fn get_clock() -> JSON<ClockStatus> {
+    JSON(clock::get_status())
 }
 
 fn main() {
-    let _mavlink_handle = MavlinkHandle::new();
-    let _pulse_handle = PulseHandle::new();
+    let config = HostConfig::from_args().unwrap_or_else(|e| exit_with_error(&e));
+    config.configure_rest().unwrap_or_else(|e| exit_with_error(&e));
+    geofence::set(config.geofence.clone()).unwrap_or_else(|e| exit_with_error(&e));
+
+    let _mavlink_handle = MavlinkHandle::new(config.mavlink.clone(), &config.origin);
+    session::init(&config.storage_path).unwrap_or_else(|e| exit_with_error(&e));
+    let _pulse_handle = PulseHandle::new(config.pulse_server.clone(), config.pulse_encoding,
+        config.pulse_filter.clone(), config.pulse_role);
+    stream::init(&config.stream_address, config.stream_port)
+        .unwrap_or_else(|e| exit_with_error(&e));
+
+    rocket::ignite()
+        .mount("/", routes![get_telemetry, get_pulses, query_pulses, do_reposition,
+            get_pulse_server_status, get_origin, set_origin, use_home_origin,
+            use_first_fix_origin, get_commands, get_command, wait_for_command, arm, disarm,
+            force_disarm, takeoff, land, return_to_launch, hold, set_mode, download_mission,
+            upload_mission, clear_mission, preview_search, execute_search, get_search,
+            cancel_search, get_geofence, set_geofence, clear_geofence, get_active_session,
+            start_session, stop_session, list_sessions, get_session, get_session_pulses,
+            get_session_track, get_clock, get_pulse_config, set_pulse_config, set_pulse_targets,
+            set_pulse_gain, stop_detection, start_detection, get_pulse_subscription,
+            set_pulse_subscription])
+        .launch();
+}
 
-    rocket::ignite().mount("/", routes![get_telemetry, get_pulses, do_reposition]).launch();
+fn exit_with_error(message: &str) -> ! {
+    println!("Invalid configuration: {}", message);
+    process::exit(1);
 }
@@ -1,34 +1,236 @@
+use std::collections::VecDeque;
 use std::f32;
+use std::u16;
+use std::io::{self, ErrorKind};
 use std::thread;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant};
 use std::sync::atomic::{ATOMIC_BOOL_INIT, AtomicBool, Ordering};
 
 use mavlink;
 use mavlink::common::*;
 
-#[derive(Debug, Clone, Serialize)]
+use clock;
+use commands::{self, CommandLong, CommandState};
+use config::{OriginConfig, OriginMode};
+use flight_mode::{self, MAV_AUTOPILOT_INVALID, MAV_AUTOPILOT_PX4, MAV_MODE_FLAG_SAFETY_ARMED,
+    MAV_TYPE_GCS};
+use geodetic::{Coordinate, LocalFrame};
+use geofence;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Telemetry {
     pub position: [f32; 3],
+    /// Altitude above the vehicle's home position in meters
+    pub relative_alt: f32,
     pub heading: f32,
+    /// Velocity in meters per second as [north, east, down]
+    pub velocity: [f32; 3],
+    pub attitude: Option<Attitude>,
+    pub battery: Option<Battery>,
+    pub mode: Option<FlightMode>,
+    pub gps: Option<GpsStatus>,
+    pub hud: Option<VfrHud>,
+    pub link: LinkStatus,
+    /// The origin that `position` is relative to
+    pub origin: Option<Origin>,
+    /// The host time that the position, velocity and heading are for, in seconds since the Unix
+    /// epoch
+    #[serde(default)]
+    pub time: Option<f64>,
+}
+
+/// The state of the Mavlink link to the vehicle
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LinkState {
+    /// Heartbeats are being received from the vehicle
+    Connected,
+    /// Heartbeats have stopped, but other messages have been received recently
+    Stale,
+    /// Nothing has been received from the vehicle recently (or ever)
+    Lost,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkStatus {
+    pub state: LinkState,
+    /// Seconds since the last message of any type was received
+    pub last_message_age: Option<f32>,
+    /// Seconds since the last HEARTBEAT was received
+    pub last_heartbeat_age: Option<f32>,
+    /// Seconds since the position was last updated
+    pub position_age: Option<f32>,
+    /// The number of errors encountered while reading from the Mavlink stream
+    pub error_count: u64,
+}
+
+/// The orientation of the vehicle in degrees, from the ATTITUDE message
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Attitude {
+    pub roll: f32,
+    pub pitch: f32,
+    pub yaw: f32,
+}
+
+/// Battery state from the SYS_STATUS or BATTERY_STATUS message
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Battery {
+    /// Battery voltage in volts
+    pub voltage: f32,
+    /// Battery current in amps
+    pub current: Option<f32>,
+    /// Remaining battery capacity in percent
+    pub remaining: Option<u8>,
+    /// Consumed charge in mAh
+    pub consumed: Option<f32>,
+}
+
+/// The flight mode reported in the HEARTBEAT message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlightMode {
+    /// The name of the mode (only available for PX4 autopilots)
+    pub name: Option<String>,
+    pub armed: bool,
+    pub base_mode: u8,
+    pub custom_mode: u32,
+    pub system_status: u8,
+}
+
+/// GPS fix quality from the GPS_RAW_INT message
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct GpsStatus {
+    /// 0-1: no fix, 2: 2D fix, 3: 3D fix, 4: DGPS, 5: RTK float, 6: RTK fixed
+    pub fix_type: u8,
+    pub satellites_visible: u8,
+    /// Horizontal dilution of precision
+    pub hdop: Option<f32>,
+    /// Vertical dilution of precision
+    pub vdop: Option<f32>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Values typically shown on a HUD from the VFR_HUD message
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct VfrHud {
+    pub airspeed: f32,
+    pub groundspeed: f32,
+    pub climb: f32,
+    /// Throttle in percent
+    pub throttle: u16,
+    pub alt: f32,
+}
+
+#[derive(Copy, Clone)]
+pub struct Heartbeat {
+    autopilot: u8,
+    base_mode: u8,
+    custom_mode: u32,
+    system_status: u8,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Location {
     pub x: f32,
     pub y: f32,
     pub alt: f32
 }
 
+/// Where the origin of the local coordinate frame came from
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum OriginSource {
+    /// The first position received from the vehicle
+    FirstFix,
+    /// The vehicle's HOME_POSITION
+    HomePosition,
+    /// The origin specified in the config file
+    Config,
+    /// An origin set through the REST API
+    Api,
+}
+
+/// The origin of the local coordinate frame that all positions are relative to
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Origin {
+    pub lat: f64,
+    pub lon: f64,
+    pub alt: f64,
+    pub source: OriginSource,
+}
+
 #[derive(Copy, Clone, Default)]
 pub struct SharedData {
+    pub gps_base: GpsBase,
     pub position: [f32; 3],
+    pub relative_alt: f32,
     pub velocity: [f32; 3],
     pub heading: f32,
-    pub next_target: Option<[f32; 3]>
+    pub attitude: Option<Attitude>,
+    pub battery: Option<Battery>,
+    pub has_battery_status: bool,
+    pub heartbeat: Option<Heartbeat>,
+    pub gps: Option<GpsStatus>,
+    pub hud: Option<VfrHud>,
+    pub last_message: Option<Instant>,
+    pub last_heartbeat: Option<Instant>,
+    pub last_position: Option<Instant>,
+    pub position_time: Option<f64>,
+    pub error_count: u64,
 }
 
+type Connection = Arc<Box<mavlink::MavConnection + Send + Sync>>;
+
 lazy_static! {
     static ref MAVLINK_DATA: Mutex<SharedData> = Mutex::new(SharedData::default());
+
+    /// The current connection to the vehicle, shared so that messages can be sent without waiting
+    /// for the background thread.
+    static ref CONNECTION: Mutex<Option<Connection>> = Mutex::new(None);
+
+    /// Receives mission protocol messages while a mission transaction is in progress
+    static ref MISSION_SUBSCRIBER: Mutex<Option<Sender<MavMessage>>> = Mutex::new(None);
+}
+
+/// Starts forwarding mission protocol messages received from the vehicle to the returned receiver,
+/// replacing any previous subscriber.
+pub fn subscribe_mission_messages() -> Receiver<MavMessage> {
+    let (sender, receiver) = channel();
+    *MISSION_SUBSCRIBER.lock().unwrap() = Some(sender);
+    receiver
+}
+
+pub fn unsubscribe_mission_messages() {
+    *MISSION_SUBSCRIBER.lock().unwrap() = None;
+}
+
+fn is_mission_message(message: &MavMessage) -> bool {
+    match *message {
+        MavMessage::MISSION_COUNT(_) | MavMessage::MISSION_REQUEST(_) |
+        MavMessage::MISSION_REQUEST_INT(_) | MavMessage::MISSION_ITEM(_) |
+        MavMessage::MISSION_ITEM_INT(_) | MavMessage::MISSION_ACK(_) => true,
+        _ => false,
+    }
+}
+
+fn forward_mission_message(message: MavMessage) {
+    let mut subscriber = MISSION_SUBSCRIBER.lock().unwrap();
+
+    let disconnected = match *subscriber {
+        Some(ref sender) => sender.send(message).is_err(),
+        None => false,
+    };
+
+    if disconnected {
+        *subscriber = None;
+    }
+}
+
+/// Sends a message to the vehicle
+pub fn send(message: &MavMessage) -> io::Result<()> {
+    let connection = CONNECTION.lock().unwrap().clone();
+    match connection {
+        Some(connection) => connection.send(message),
+        None => Err(io::Error::new(ErrorKind::NotConnected, "Not connected to Mavlink stream")),
+    }
 }
 
 pub fn get_telemetry() -> Telemetry {
@@ -36,12 +238,237 @@ pub fn get_telemetry() -> Telemetry {
 
     Telemetry {
         position: mavlink_data.position,
+        relative_alt: mavlink_data.relative_alt,
         heading: mavlink_data.heading,
+        velocity: mavlink_data.velocity,
+        attitude: mavlink_data.attitude,
+        battery: mavlink_data.battery,
+        mode: mavlink_data.heartbeat.map(flight_mode),
+        gps: mavlink_data.gps,
+        hud: mavlink_data.hud,
+        link: link_status(&mavlink_data, Instant::now()),
+        origin: mavlink_data.gps_base.origin,
+        time: mavlink_data.position_time,
     }
 }
 
-pub fn do_reposition(target: Location) {
-    MAVLINK_DATA.lock().unwrap().next_target = Some([target.x, target.y, target.alt]);
+/// The length of the position history kept for looking up the telemetry at a past time
+const HISTORY_SECS: f64 = 60.0;
+
+/// The maximum time past the latest position that the position is extrapolated
+const MAX_EXTRAPOLATION_SECS: f64 = 1.0;
+
+/// The position of the vehicle at a point in time
+#[derive(Copy, Clone)]
+struct PositionSample {
+    time: f64,
+    position: [f32; 3],
+    relative_alt: f32,
+    velocity: [f32; 3],
+    heading: f32,
+}
+
+impl PositionSample {
+    /// Interpolates between this sample and a later sample
+    fn lerp(&self, next: &PositionSample, time: f64) -> PositionSample {
+        let duration = next.time - self.time;
+        let t = if duration > 0.0 { ((time - self.time) / duration) as f32 } else { 0.0 };
+        let lerp = |a: f32, b: f32| a + (b - a) * t;
+
+        // Take the shortest way around the circle
+        let turn = ((next.heading - self.heading) % 360.0 + 540.0) % 360.0 - 180.0;
+
+        PositionSample {
+            time: time,
+            position: [lerp(self.position[0], next.position[0]),
+                lerp(self.position[1], next.position[1]), lerp(self.position[2], next.position[2])],
+            relative_alt: lerp(self.relative_alt, next.relative_alt),
+            velocity: [lerp(self.velocity[0], next.velocity[0]),
+                lerp(self.velocity[1], next.velocity[1]), lerp(self.velocity[2], next.velocity[2])],
+            heading: ((self.heading + turn * t) % 360.0 + 360.0) % 360.0,
+        }
+    }
+
+    /// Extrapolates the position of this sample along its velocity
+    fn extrapolate(&self, time: f64) -> PositionSample {
+        let dt = (time - self.time).min(MAX_EXTRAPOLATION_SECS).max(0.0) as f32;
+        let (north, east, down) = (self.velocity[0], self.velocity[1], self.velocity[2]);
+
+        PositionSample {
+            time: time,
+            position: [self.position[0] + east * dt, self.position[1] + north * dt,
+                self.position[2] - down * dt],
+            relative_alt: self.relative_alt - down * dt,
+            velocity: self.velocity,
+            heading: self.heading,
+        }
+    }
+}
+
+lazy_static! {
+    /// Recent positions of the vehicle, oldest first
+    static ref POSITION_HISTORY: Mutex<VecDeque<PositionSample>> = Mutex::new(VecDeque::new());
+}
+
+/// Returns the telemetry of the vehicle, with the position, velocity and heading at the specified
+/// host time. Positions between updates are interpolated, and positions after the latest update
+/// are extrapolated for up to `MAX_EXTRAPOLATION_SECS`.
+pub fn get_telemetry_at(time: f64) -> Telemetry {
+    let mut telemetry = get_telemetry();
+
+    if let Some(sample) = sample_at(&POSITION_HISTORY.lock().unwrap(), time) {
+        telemetry.position = sample.position;
+        telemetry.relative_alt = sample.relative_alt;
+        telemetry.velocity = sample.velocity;
+        telemetry.heading = sample.heading;
+        telemetry.time = Some(sample.time);
+    }
+
+    telemetry
+}
+
+/// Finds the position at the specified time from the history. Times before the history use the
+/// oldest sample, since nothing is known about the position before then.
+fn sample_at(history: &VecDeque<PositionSample>, time: f64) -> Option<PositionSample> {
+    match history.iter().position(|sample| sample.time >= time) {
+        Some(0) => history.front().cloned(),
+        Some(i) => Some(history[i - 1].lerp(&history[i], time)),
+        None => history.back().map(|sample| sample.extrapolate(time)),
+    }
+}
+
+/// Positions in the history are relative to the origin, so the history is cleared whenever the
+/// origin is changed.
+fn clear_history() {
+    POSITION_HISTORY.lock().unwrap().clear();
+}
+
+fn flight_mode(heartbeat: Heartbeat) -> FlightMode {
+    let name = match heartbeat.autopilot {
+        MAV_AUTOPILOT_PX4 => flight_mode::px4_mode_name(heartbeat.custom_mode).map(String::from),
+        _ => None,
+    };
+
+    FlightMode {
+        name: name,
+        armed: heartbeat.base_mode & MAV_MODE_FLAG_SAFETY_ARMED != 0,
+        base_mode: heartbeat.base_mode,
+        custom_mode: heartbeat.custom_mode,
+        system_status: heartbeat.system_status,
+    }
+}
+
+/// Time without a HEARTBEAT before the link is considered stale
+pub const STALE_TIMEOUT_SECS: f32 = 3.0;
+
+/// Time without any message before the link is considered lost
+const LOST_TIMEOUT_SECS: f32 = 10.0;
+
+fn age(now: Instant, time: Option<Instant>) -> Option<f32> {
+    time.map(|time| {
+        let elapsed = now.duration_since(time);
+        elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1e9
+    })
+}
+
+fn link_status(data: &SharedData, now: Instant) -> LinkStatus {
+    let last_message_age = age(now, data.last_message);
+    let last_heartbeat_age = age(now, data.last_heartbeat);
+
+    let state = match (last_heartbeat_age, last_message_age) {
+        (Some(heartbeat), _) if heartbeat <= STALE_TIMEOUT_SECS => LinkState::Connected,
+        (_, Some(message)) if message <= LOST_TIMEOUT_SECS => LinkState::Stale,
+        _ => LinkState::Lost,
+    };
+
+    LinkStatus {
+        state: state,
+        last_message_age: last_message_age,
+        last_heartbeat_age: last_heartbeat_age,
+        position_age: age(now, data.last_position),
+        error_count: data.error_count,
+    }
+}
+
+/// Returns the altitude of the vehicle's home position above mean sea level, as implied by the
+/// latest position and its altitude relative to home. Returns `None` if no position has been
+/// received.
+pub fn home_altitude() -> Option<f32> {
+    let mavlink_data = MAVLINK_DATA.lock().unwrap();
+    mavlink_data.last_position.map(|_| mavlink_data.position[2] - mavlink_data.relative_alt)
+}
+
+/// Converts a position in the local frame to a global coordinate, returning `None` if the origin
+/// has not been set yet.
+pub fn local_to_global(x: f32, y: f32, alt: f32) -> Option<Coordinate> {
+    MAVLINK_DATA.lock().unwrap().gps_base.invert(x, y, alt)
+}
+
+/// Converts a global coordinate to a [x, y, alt] position in the local frame, returning `None` if
+/// the origin has not been set yet.
+pub fn global_to_local(coordinate: Coordinate) -> Option<[f32; 3]> {
+    MAVLINK_DATA.lock().unwrap().gps_base.to_local(coordinate)
+        .map(|position| [position[0], position[1], coordinate.alt as f32])
+}
+
+/// The result of a reposition request
+#[derive(Debug, Clone, Serialize)]
+pub struct Reposition {
+    pub command: CommandState,
+    /// The target that was sent to the vehicle
+    pub target: Location,
+    /// The reason the target was moved, if it was clamped to the geofence
+    pub clamped: Option<String>,
+}
+
+/// Sends a DO_REPOSITION command to move the vehicle to the target location. If the target
+/// altitude is zero, the current altitude of the vehicle is used. Targets outside of the geofence
+/// are rejected or clamped.
+pub fn do_reposition(target: Location) -> Result<Reposition, String> {
+    let mavlink_data = MAVLINK_DATA.lock().unwrap();
+    let alt = if target.alt != 0.0 { target.alt } else { mavlink_data.position[2] };
+
+    let check = try!(geofence::check(Location { x: target.x, y: target.y, alt: alt })
+        .map_err(|e| format!("Target rejected by the geofence: {}", e)));
+    let target = check.target;
+
+    let dest = match mavlink_data.gps_base.invert(target.x, target.y, target.alt) {
+        Some(dest) => dest,
+        None => return Err("Unable to reposition: the origin has not been set yet".into()),
+    };
+    drop(mavlink_data);
+
+    let command = generate_navigation_command(dest.lon as f32, dest.lat as f32, target.alt);
+    Ok(Reposition {
+        command: commands::submit(command),
+        target: target,
+        clamped: check.clamped,
+    })
+}
+
+/// Returns the origin of the local coordinate frame, if it has been set
+pub fn get_origin() -> Option<Origin> {
+    MAVLINK_DATA.lock().unwrap().gps_base.origin
+}
+
+/// Pins the origin of the local coordinate frame to the specified coordinate
+pub fn set_origin(coordinate: Coordinate) {
+    let mut mavlink_data = MAVLINK_DATA.lock().unwrap();
+    mavlink_data.gps_base.mode = OriginMode::Fixed;
+    mavlink_data.gps_base.set_origin(coordinate, OriginSource::Api);
+    clear_history();
+}
+
+/// Clears the current origin and takes the new origin from the vehicle's next HOME_POSITION
+pub fn use_home_origin() {
+    MAVLINK_DATA.lock().unwrap().gps_base.reset(OriginMode::HomePosition);
+    clear_history();
+}
+
+/// Clears the current origin and takes the new origin from the vehicle's next position
+pub fn use_first_fix_origin() {
+    MAVLINK_DATA.lock().unwrap().gps_base.reset(OriginMode::FirstFix);
+    clear_history();
 }
 
 static STOPPED: AtomicBool = ATOMIC_BOOL_INIT;
@@ -55,121 +482,510 @@ impl Drop for MavlinkHandle {
 }
 
 impl MavlinkHandle {
-    pub fn new() -> MavlinkHandle {
+    pub fn new(address: String, origin: &OriginConfig) -> MavlinkHandle {
         STOPPED.store(false, Ordering::Relaxed);
-        *MAVLINK_DATA.lock().unwrap() = SharedData::default();
-        thread::spawn(|| mavlink_background_process());
+
+        let mut data = SharedData::default();
+        data.gps_base.reset(origin.mode);
+        if let Some(coordinate) = origin.fixed {
+            data.gps_base.set_origin(coordinate, OriginSource::Config);
+        }
+        *MAVLINK_DATA.lock().unwrap() = data;
+
+        thread::spawn(move || mavlink_background_process(&address));
+        thread::spawn(|| command_supervisor());
         MavlinkHandle { }
     }
 }
 
-const EARTH_RADIUS_METERS: f64 = 6371e3;
-
-#[derive(Default)]
-struct GpsBase {
-    base: Option<Coordinate>,
+/// Tracks the local tangent plane that positions are reported in.
+#[derive(Copy, Clone, Default)]
+pub struct GpsBase {
+    mode: OriginMode,
+    origin: Option<Origin>,
+    frame: Option<LocalFrame>,
 }
 
 impl GpsBase {
-    fn next(&mut self, lon: i32, lat: i32) -> Option<[f32; 2]> {
-        let new = Coordinate { lat: lat as f64 / 1e7, lon: lon as f64 / 1e7 };
-
-        match self.base {
-            Some(base) => {
-                let x_offset = (new.lon - base.lon).to_radians() * EARTH_RADIUS_METERS;
-                let y_offset = (new.lat - base.lat).to_radians() * EARTH_RADIUS_METERS;
-                Some([x_offset as f32, y_offset as f32])
-            },
-            None => {
-                self.base = Some(new);
-                None
-            }
+    fn set_origin(&mut self, coordinate: Coordinate, source: OriginSource) {
+        println!("Setting origin ({:?}): {:?}", source, coordinate);
+
+        self.origin = Some(Origin {
+            lat: coordinate.lat,
+            lon: coordinate.lon,
+            alt: coordinate.alt,
+            source: source,
+        });
+        self.frame = Some(LocalFrame::new(coordinate));
+    }
+
+    fn reset(&mut self, mode: OriginMode) {
+        self.mode = mode;
+        self.origin = None;
+        self.frame = None;
+    }
+
+    /// Returns true if the origin should be taken from the vehicle's HOME_POSITION but it has not
+    /// been received yet.
+    fn waiting_for_home(&self) -> bool {
+        self.mode == OriginMode::HomePosition && self.frame.is_none()
+    }
+
+    /// Handles a HOME_POSITION message from the vehicle
+    fn home_position(&mut self, coordinate: Coordinate) {
+        if self.waiting_for_home() {
+            self.set_origin(coordinate, OriginSource::HomePosition);
         }
     }
 
-    fn invert(&self, x: f32, y: f32) -> Coordinate {
-        let base = self.base.expect("Tried to invert offset without base");
+    /// Converts a global position into a [x, y] offset (east, north) from the origin, returning
+    /// `None` if there is no origin yet.
+    fn next(&mut self, coordinate: Coordinate) -> Option<[f32; 2]> {
+        if self.frame.is_none() && self.mode == OriginMode::FirstFix {
+            self.set_origin(coordinate, OriginSource::FirstFix);
+        }
+
+        self.to_local(coordinate)
+    }
+
+    /// Converts a global position into a [x, y] offset (east, north) from the origin
+    fn to_local(&self, coordinate: Coordinate) -> Option<[f32; 2]> {
+        self.frame.map(|frame| {
+            let enu = frame.to_enu(coordinate);
+            [enu[0] as f32, enu[1] as f32]
+        })
+    }
+
+    /// Converts a local [x, y] offset at the specified altitude back into a global position,
+    /// returning `None` if there is no origin yet.
+    fn invert(&self, x: f32, y: f32, alt: f32) -> Option<Coordinate> {
+        self.frame.map(|frame| {
+            let up = alt as f64 - frame.origin().alt;
+            frame.from_enu([x as f64, y as f64, up])
+        })
+    }
+}
+
+/// The number of consecutive read errors tolerated before the connection is reopened
+const MAX_CONSECUTIVE_ERRORS: u32 = 20;
+
+const RECONNECT_DELAY_SECS: u64 = 1;
 
-        Coordinate {
-            lat: (y as f64 / EARTH_RADIUS_METERS).to_degrees() + base.lat,
-            lon: (x as f64 / EARTH_RADIUS_METERS).to_degrees() + base.lon
+fn mavlink_background_process(address: &str) {
+    while STOPPED.load(Ordering::Relaxed) == false {
+        match mavlink::connect(address) {
+            Ok(connection) => {
+                println!("Connected to Mavlink stream: {}", address);
+                let connection = Arc::new(connection);
+                *CONNECTION.lock().unwrap() = Some(connection.clone());
+
+                if let Err(e) = read_messages(&***connection) {
+                    println!("Mavlink connection lost: {}", e);
+                }
+                *CONNECTION.lock().unwrap() = None;
+            },
+            Err(e) => println!("Failed to connect to Mavlink stream `{}`: {}", address, e),
         }
+
+        thread::sleep(Duration::from_secs(RECONNECT_DELAY_SECS));
     }
 }
 
-#[derive(Copy, Clone)]
-struct Coordinate {
-    lat: f64,
-    lon: f64,
+const COMMAND_CHECK_INTERVAL_MS: u64 = 100;
+
+/// Periodically resends commands that have not been acknowledged
+fn command_supervisor() {
+    while STOPPED.load(Ordering::Relaxed) == false {
+        commands::check_retries();
+        thread::sleep(Duration::from_millis(COMMAND_CHECK_INTERVAL_MS));
+    }
+}
+
+/// Returns true if the error indicates that the connection itself has failed, rather than a single
+/// bad message being received.
+fn is_connection_error(error: &io::Error) -> bool {
+    match error.kind() {
+        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::NotConnected |
+        ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof => true,
+        _ => false,
+    }
+}
+
+/// The (autopilot, type) pair identifying the vehicle that heartbeats are accepted from
+type VehicleIdentity = (u8, u8);
+
+/// Checks whether a heartbeat came from the vehicle's flight controller. Heartbeats from ground
+/// stations and from components without an autopilot (gimbals, cameras, companion computers) are
+/// ignored. The Mavlink library doesn't expose the system and component IDs of received messages,
+/// so the connection is locked to the autopilot and type of the first vehicle heartbeat instead,
+/// and heartbeats from any other system are ignored.
+fn is_vehicle_heartbeat(data: &HEARTBEAT_DATA, vehicle: &mut Option<VehicleIdentity>) -> bool {
+    if data.mavtype == MAV_TYPE_GCS || data.autopilot == MAV_AUTOPILOT_INVALID {
+        return false;
+    }
+
+    let identity = (data.autopilot, data.mavtype);
+    match *vehicle {
+        Some(vehicle) => vehicle == identity,
+        None => {
+            println!("Receiving heartbeats from autopilot {} (type {})", identity.0, identity.1);
+            *vehicle = Some(identity);
+            true
+        }
+    }
 }
 
-fn mavlink_background_process() {
-    let connection = mavlink::connect("udpin:127.0.0.1:14552")
-        .expect("Failed to connect to Mavlink stream");
+/// Reads messages from the connection until the handle is stopped or the connection fails.
+fn read_messages(connection: &mavlink::MavConnection) -> io::Result<()> {
+    let mut consecutive_errors = 0;
+    let mut vehicle = None;
 
-    let mut gps_base = GpsBase::default();
     while STOPPED.load(Ordering::Relaxed) == false {
-        match connection.recv().unwrap() {
-            MavMessage::GLOBAL_POSITION_INT(data) => {
-                if let Some(message) = handle_gps_data(&mut gps_base, data) {
-                    println!("Sending message: {:?}", message);
+        let message = match connection.recv() {
+            Ok(message) => message,
+            Err(e) => {
+                println!("Failed to read Mavlink message: {}", e);
+                MAVLINK_DATA.lock().unwrap().error_count += 1;
+
+                consecutive_errors += 1;
+                if is_connection_error(&e) || consecutive_errors >= MAX_CONSECUTIVE_ERRORS {
+                    return Err(e);
+                }
+                continue;
+            }
+        };
+        consecutive_errors = 0;
+
+        let now = Instant::now();
+        let receive_time = clock::now();
+        MAVLINK_DATA.lock().unwrap().last_message = Some(now);
+
+        if is_mission_message(&message) {
+            forward_mission_message(message);
+            continue;
+        }
+
+        let from_vehicle = match message {
+            MavMessage::HEARTBEAT(ref data) => is_vehicle_heartbeat(data, &mut vehicle),
+            _ => true,
+        };
+
+        match message {
+            // Ignore heartbeats from ground stations and other components
+            MavMessage::HEARTBEAT(_) if !from_vehicle => {},
+
+            MavMessage::HEARTBEAT(data) => {
+                let mut mavlink_data = MAVLINK_DATA.lock().unwrap();
+                mavlink_data.last_heartbeat = Some(now);
+                mavlink_data.heartbeat = Some(Heartbeat {
+                    autopilot: data.autopilot,
+                    base_mode: data.base_mode,
+                    custom_mode: data.custom_mode,
+                    system_status: data.system_status,
+                });
+
+                let waiting_for_home = mavlink_data.gps_base.waiting_for_home();
+                drop(mavlink_data);
+
+                if waiting_for_home {
+                    let message = generate_get_home_command().to_message(0);
                     if let Err(e) = connection.send(&message) {
-                        println!("Failed to send message: {}", e);
+                        println!("Failed to request home position: {}", e);
                     }
                 }
+
+                if let Err(e) = connection.send(&generate_timesync_request()) {
+                    println!("Failed to send time sync request: {}", e);
+                }
             },
 
+            MavMessage::TIMESYNC(data) => handle_timesync(connection, data, receive_time),
+
+            MavMessage::SYSTEM_TIME(data) => {
+                let unix_time = match data.time_unix_usec {
+                    0 => None,
+                    usec => Some(usec as f64 / 1e6),
+                };
+                clock::autopilot_system_time(receive_time, data.time_boot_ms as f64 / 1e3,
+                    unix_time);
+            },
+
+            MavMessage::HOME_POSITION(data) => {
+                let home = Coordinate {
+                    lat: data.latitude as f64 / 1e7,
+                    lon: data.longitude as f64 / 1e7,
+                    alt: data.altitude as f64 / 1e3,
+                };
+                MAVLINK_DATA.lock().unwrap().gps_base.home_position(home);
+            },
+
+            MavMessage::ATTITUDE(data) => {
+                MAVLINK_DATA.lock().unwrap().attitude = Some(Attitude {
+                    roll: data.roll.to_degrees(),
+                    pitch: data.pitch.to_degrees(),
+                    yaw: data.yaw.to_degrees(),
+                });
+            },
+
+            MavMessage::SYS_STATUS(data) => handle_sys_status(data),
+            MavMessage::BATTERY_STATUS(data) => handle_battery_status(data),
+
+            MavMessage::GPS_RAW_INT(data) => {
+                MAVLINK_DATA.lock().unwrap().gps = Some(GpsStatus {
+                    fix_type: data.fix_type,
+                    satellites_visible: data.satellites_visible,
+                    hdop: dilution(data.eph),
+                    vdop: dilution(data.epv),
+                });
+            },
+
+            MavMessage::VFR_HUD(data) => {
+                MAVLINK_DATA.lock().unwrap().hud = Some(VfrHud {
+                    airspeed: data.airspeed,
+                    groundspeed: data.groundspeed,
+                    climb: data.climb,
+                    throttle: data.throttle,
+                    alt: data.alt,
+                });
+            },
+
+            MavMessage::GLOBAL_POSITION_INT(data) => handle_gps_data(data, receive_time),
+
+            MavMessage::COMMAND_ACK(data) => commands::handle_ack(data),
+
             _ => {}
         }
     }
+
+    Ok(())
 }
 
-fn handle_gps_data(gps_base: &mut GpsBase, data: GLOBAL_POSITION_INT_DATA) -> Option<MavMessage> {
-    let (dx, dy) = match gps_base.next(data.lon, data.lat) {
-        Some(position) => (position[0], position[1]),
-        None => return None,
-    };
+/// Converts a dilution of precision value (scaled by 100) from GPS_RAW_INT
+fn dilution(value: u16) -> Option<f32> {
+    if value == u16::MAX { None } else { Some(value as f32 / 100.0) }
+}
 
-    let alt_meters = data.alt as f32 / 1e3;
-    let new_position = [dx, dy, alt_meters];
-    let velocity = [data.vx as f32 / 100.0, data.vy as f32 / 100.0, data.vz as f32 / 100.0];
+fn handle_sys_status(data: SYS_STATUS_DATA) {
+    let mut mavlink_data = MAVLINK_DATA.lock().unwrap();
 
-    let mut mavlink_data_lock = MAVLINK_DATA.lock().unwrap();
-    mavlink_data_lock.position = new_position;
-    mavlink_data_lock.velocity = velocity;
-    mavlink_data_lock.heading = data.hdg as f32 / 100.0;
-    // mavlink_data_lock.orientation = orientation;
+    // BATTERY_STATUS is more detailed, so prefer it if we have received it.
+    if mavlink_data.has_battery_status {
+        return;
+    }
+
+    mavlink_data.battery = Some(Battery {
+        voltage: data.voltage_battery as f32 / 1000.0,
+        current: battery_current(data.current_battery),
+        remaining: battery_remaining(data.battery_remaining),
+        consumed: None,
+    });
+}
 
-    let target = mavlink_data_lock.next_target.take();
-    drop(mavlink_data_lock);
+fn handle_battery_status(data: BATTERY_STATUS_DATA) {
+    // Unused cells are reported as u16::MAX
+    let voltage = data.voltages.iter()
+        .filter(|&&cell| cell != u16::MAX)
+        .fold(0.0, |total, &cell| total + cell as f32 / 1000.0);
+
+    let mut mavlink_data = MAVLINK_DATA.lock().unwrap();
+    mavlink_data.has_battery_status = true;
+    mavlink_data.battery = Some(Battery {
+        voltage: voltage,
+        current: battery_current(data.current_battery),
+        remaining: battery_remaining(data.battery_remaining),
+        consumed: if data.current_consumed < 0 { None } else { Some(data.current_consumed as f32) },
+    });
+}
 
-    if let Some(target) = target {
-        println!("Attempting to set new target");
+/// Converts a battery current in centiamps (-1 if unknown) to amps
+fn battery_current(value: i16) -> Option<f32> {
+    if value < 0 { None } else { Some(value as f32 / 100.0) }
+}
 
-        let dest_coordinate = gps_base.invert(target[0], target[1]);
-        let alt = if target[2] != 0.0 { target[2] } else { alt_meters };
+/// Converts a remaining battery percentage (-1 if unknown)
+fn battery_remaining(value: i8) -> Option<u8> {
+    if value < 0 { None } else { Some(value as u8) }
+}
 
-        return Some(generate_navigation_message(dest_coordinate.lon as f32,
-            dest_coordinate.lat as f32, alt));
+/// The oldest time sync response that is accepted, older responses are assumed to be responses to
+/// another ground station's requests.
+const MAX_TIMESYNC_AGE_SECS: f64 = 10.0;
+
+fn handle_timesync(connection: &mavlink::MavConnection, data: TIMESYNC_DATA, receive_time: f64) {
+    if data.tc1 == 0 {
+        // A request from the autopilot, respond with our time
+        let response = MavMessage::TIMESYNC(TIMESYNC_DATA {
+            tc1: (receive_time * 1e9) as i64,
+            ts1: data.ts1,
+        });
+        if let Err(e) = connection.send(&response) {
+            println!("Failed to respond to time sync request: {}", e);
+        }
+        return;
     }
 
-    None
+    // A response to one of our requests, `ts1` is the host time the request was sent
+    let send_time = data.ts1 as f64 / 1e9;
+    if send_time <= receive_time && receive_time - send_time <= MAX_TIMESYNC_AGE_SECS {
+        clock::autopilot_time_sync(send_time, data.tc1 as f64 / 1e9, receive_time);
+    }
+}
+
+fn handle_gps_data(data: GLOBAL_POSITION_INT_DATA, receive_time: f64) {
+    let alt_meters = data.alt as f32 / 1e3;
+    let coordinate = Coordinate {
+        lat: data.lat as f64 / 1e7,
+        lon: data.lon as f64 / 1e7,
+        alt: alt_meters as f64,
+    };
+
+    let mut mavlink_data_lock = MAVLINK_DATA.lock().unwrap();
+    let (dx, dy) = match mavlink_data_lock.gps_base.next(coordinate) {
+        Some(position) => (position[0], position[1]),
+        None => return,
+    };
+
+    let sample = PositionSample {
+        time: clock::observe_autopilot(receive_time, data.time_boot_ms as f64 / 1e3),
+        position: [dx, dy, alt_meters],
+        relative_alt: data.relative_alt as f32 / 1e3,
+        velocity: [data.vx as f32 / 100.0, data.vy as f32 / 100.0, data.vz as f32 / 100.0],
+        heading: data.hdg as f32 / 100.0,
+    };
+
+    mavlink_data_lock.position = sample.position;
+    mavlink_data_lock.relative_alt = sample.relative_alt;
+    mavlink_data_lock.velocity = sample.velocity;
+    mavlink_data_lock.heading = sample.heading;
+    mavlink_data_lock.last_position = Some(Instant::now());
+    mavlink_data_lock.position_time = Some(sample.time);
+
+    // Hold the data lock while updating the history, so that the history can't be cleared by an
+    // origin change in between.
+    let mut history = POSITION_HISTORY.lock().unwrap();
+    while history.back().map_or(false, |latest| latest.time >= sample.time) {
+        // The clock offset estimate has changed, keep the history in order
+        history.pop_back();
+    }
+    while history.front().map_or(false, |oldest| oldest.time < sample.time - HISTORY_SECS) {
+        history.pop_front();
+    }
+    history.push_back(sample);
 }
 
 const MAV_CMD_DO_REPOSITION: u16 = 192;
 
-fn generate_navigation_message(lon: f32, lat: f32, alt: f32) -> MavMessage {
-    MavMessage::COMMAND_LONG(COMMAND_LONG_DATA {
-        param1: -1.0,
-        param2: 1.0,
-        param3: 0.0,
-        param4: -f32::NAN,
-        param5: lat,
-        param6: lon,
-        param7: alt,
+fn generate_navigation_command(lon: f32, lat: f32, alt: f32) -> CommandLong {
+    CommandLong {
         command: MAV_CMD_DO_REPOSITION,
-        target_system: 1,
-        target_component: 0,
-        confirmation: 0,
+        params: [-1.0, 1.0, 0.0, -f32::NAN, lat, lon, alt],
+    }
+}
+
+fn generate_timesync_request() -> MavMessage {
+    MavMessage::TIMESYNC(TIMESYNC_DATA {
+        tc1: 0,
+        ts1: (clock::now() * 1e9) as i64,
     })
-}
\ No newline at end of file
+}
+
+const MAV_CMD_GET_HOME_POSITION: u16 = 410;
+
+fn generate_get_home_command() -> CommandLong {
+    CommandLong {
+        command: MAV_CMD_GET_HOME_POSITION,
+        params: [0.0; 7],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::{MAX_EXTRAPOLATION_SECS, PositionSample, sample_at};
+
+    fn sample(time: f64, position: [f32; 3], velocity: [f32; 3], heading: f32) -> PositionSample {
+        PositionSample {
+            time: time,
+            position: position,
+            relative_alt: position[2] - 10.0,
+            velocity: velocity,
+            heading: heading,
+        }
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+    }
+
+    fn assert_position(sample: &PositionSample, position: [f32; 3]) {
+        for i in 0..3 {
+            assert_close(sample.position[i], position[i]);
+        }
+        assert_close(sample.relative_alt, position[2] - 10.0);
+    }
+
+    fn history() -> VecDeque<PositionSample> {
+        let mut history = VecDeque::new();
+        history.push_back(sample(10.0, [0.0, 0.0, 50.0], [0.0, 0.0, 0.0], 10.0));
+        history.push_back(sample(12.0, [10.0, 20.0, 54.0], [4.0, 2.0, -2.0], 30.0));
+        history
+    }
+
+    #[test]
+    fn interpolation() {
+        let history = history();
+
+        let middle = sample_at(&history, 11.0).unwrap();
+        assert_eq!(middle.time, 11.0);
+        assert_position(&middle, [5.0, 10.0, 52.0]);
+        assert_close(middle.velocity[0], 2.0);
+        assert_close(middle.velocity[2], -1.0);
+        assert_close(middle.heading, 20.0);
+
+        let end = sample_at(&history, 12.0).unwrap();
+        assert_position(&end, [10.0, 20.0, 54.0]);
+        assert_close(end.heading, 30.0);
+    }
+
+    #[test]
+    fn heading_wraparound() {
+        let north_west = sample(0.0, [0.0; 3], [0.0; 3], 350.0);
+        let north_east = sample(1.0, [0.0; 3], [0.0; 3], 10.0);
+
+        // The heading turns through north rather than the long way around
+        assert_close(north_west.lerp(&north_east, 0.5).heading, 0.0);
+        assert_close(north_west.lerp(&north_east, 0.25).heading, 355.0);
+        assert_close(north_west.lerp(&north_east, 0.75).heading, 5.0);
+        assert_close(north_east.lerp(&north_west, 0.75).heading, 355.0);
+    }
+
+    #[test]
+    fn extrapolation() {
+        let history = history();
+
+        // Velocity is [north, east, down] while positions are [east, north, up]
+        let after = sample_at(&history, 12.5).unwrap();
+        assert_eq!(after.time, 12.5);
+        assert_position(&after, [11.0, 22.0, 55.0]);
+        assert_close(after.heading, 30.0);
+
+        // The position is only extrapolated for a limited time
+        let late = sample_at(&history, 20.0).unwrap();
+        assert_eq!(late.time, 20.0);
+        let dt = MAX_EXTRAPOLATION_SECS as f32;
+        assert_position(&late, [10.0 + 2.0 * dt, 20.0 + 4.0 * dt, 54.0 + 2.0 * dt]);
+    }
+
+    #[test]
+    fn before_history() {
+        let before = sample_at(&history(), 5.0).unwrap();
+        assert_eq!(before.time, 10.0);
+        assert_position(&before, [0.0, 0.0, 50.0]);
+    }
+
+    #[test]
+    fn empty_history() {
+        assert!(sample_at(&VecDeque::new(), 5.0).is_none());
+    }
+}
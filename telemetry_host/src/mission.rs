@@ -0,0 +1,783 @@
+//! Upload, download and clearing of waypoint missions using the Mavlink mission protocol.
+//!
+//! The protocol itself is implemented on top of the `MissionLink` trait so that it can be run
+//! against something other than the real vehicle (e.g. a scripted autopilot). `VehicleLink`
+//! implements the trait using the shared Mavlink connection.
+
+use std::io;
+use std::sync::Mutex;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use mavlink::common::*;
+
+use geodetic::Coordinate;
+use geofence::{self, Geofence};
+use mavlink_handler::{self, Location};
+
+/// Time to wait for a response from the vehicle before resending the last message
+const RESPONSE_TIMEOUT_MS: u64 = 1500;
+
+/// The number of times a message is resent before the transaction fails
+const MAX_RETRIES: u32 = 5;
+
+pub const MAV_CMD_NAV_WAYPOINT: u16 = 16;
+const MAV_CMD_NAV_LOITER_UNLIM: u16 = 17;
+const MAV_CMD_NAV_LOITER_TURNS: u16 = 18;
+const MAV_CMD_NAV_LOITER_TIME: u16 = 19;
+const MAV_CMD_NAV_LAND: u16 = 21;
+const MAV_CMD_NAV_TAKEOFF: u16 = 22;
+const MAV_CMD_NAV_LOITER_TO_ALT: u16 = 31;
+const MAV_CMD_NAV_SPLINE_WAYPOINT: u16 = 82;
+const MAV_CMD_NAV_VTOL_TAKEOFF: u16 = 84;
+const MAV_CMD_NAV_VTOL_LAND: u16 = 85;
+
+/// Commands whose x, y and altitude are a position that the vehicle flies to
+const POSITIONAL_COMMANDS: &'static [u16] = &[MAV_CMD_NAV_WAYPOINT, MAV_CMD_NAV_LOITER_UNLIM,
+    MAV_CMD_NAV_LOITER_TURNS, MAV_CMD_NAV_LOITER_TIME, MAV_CMD_NAV_LAND, MAV_CMD_NAV_TAKEOFF,
+    MAV_CMD_NAV_LOITER_TO_ALT, MAV_CMD_NAV_SPLINE_WAYPOINT, MAV_CMD_NAV_VTOL_TAKEOFF,
+    MAV_CMD_NAV_VTOL_LAND];
+
+/// Landing commands, where the altitude is the ground rather than a target to fly at
+const LANDING_COMMANDS: &'static [u16] = &[MAV_CMD_NAV_LAND, MAV_CMD_NAV_VTOL_LAND];
+
+const MAV_FRAME_GLOBAL: u8 = 0;
+const MAV_FRAME_GLOBAL_RELATIVE_ALT: u8 = 3;
+pub const MAV_FRAME_GLOBAL_INT: u8 = 5;
+const MAV_FRAME_GLOBAL_RELATIVE_ALT_INT: u8 = 6;
+const MAV_MISSION_ACCEPTED: u8 = 0;
+
+const TARGET_SYSTEM: u8 = 1;
+const TARGET_COMPONENT: u8 = 0;
+
+/// A connection to an autopilot that supports the mission protocol
+pub trait MissionLink {
+    fn send(&mut self, message: &MavMessage) -> io::Result<()>;
+
+    /// Waits for the next mission protocol message from the autopilot
+    fn recv_timeout(&mut self, timeout: Duration) -> Option<MavMessage>;
+}
+
+/// A mission link to the vehicle, using the connection owned by the Mavlink background thread
+pub struct VehicleLink {
+    receiver: Receiver<MavMessage>,
+}
+
+impl VehicleLink {
+    pub fn new() -> VehicleLink {
+        VehicleLink { receiver: mavlink_handler::subscribe_mission_messages() }
+    }
+}
+
+impl Drop for VehicleLink {
+    fn drop(&mut self) {
+        mavlink_handler::unsubscribe_mission_messages();
+    }
+}
+
+impl MissionLink for VehicleLink {
+    fn send(&mut self, message: &MavMessage) -> io::Result<()> {
+        mavlink_handler::send(message)
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> Option<MavMessage> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(message) => Some(message),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+}
+
+fn default_command() -> u16 { MAV_CMD_NAV_WAYPOINT }
+fn default_frame() -> u8 { MAV_FRAME_GLOBAL_INT }
+
+/// A mission item in the same local frame as `Location`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MissionItem {
+    pub x: f32,
+    pub y: f32,
+    pub alt: f32,
+
+    /// The Mavlink command for the item (default: MAV_CMD_NAV_WAYPOINT)
+    #[serde(default = "default_command")]
+    pub command: u16,
+
+    /// The Mavlink frame of the item (default: MAV_FRAME_GLOBAL_INT, i.e. altitude above mean sea
+    /// level). MAV_FRAME_GLOBAL and the relative altitude frames (altitude above home) are also
+    /// supported.
+    #[serde(default = "default_frame")]
+    pub frame: u8,
+
+    /// Command specific parameters 1-4. For waypoints these are the hold time, acceptance radius,
+    /// pass radius and yaw.
+    #[serde(default)]
+    pub params: [f32; 4],
+}
+
+/// A mission item in the global frame, as sent over the mission protocol
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GlobalMissionItem {
+    pub lat: i32,
+    pub lon: i32,
+    pub alt: f32,
+    pub command: u16,
+    pub frame: u8,
+    pub params: [f32; 4],
+}
+
+impl GlobalMissionItem {
+    fn to_message(&self, seq: u16) -> MavMessage {
+        MavMessage::MISSION_ITEM_INT(MISSION_ITEM_INT_DATA {
+            param1: self.params[0],
+            param2: self.params[1],
+            param3: self.params[2],
+            param4: self.params[3],
+            x: self.lat,
+            y: self.lon,
+            z: self.alt,
+            seq: seq,
+            command: self.command,
+            target_system: TARGET_SYSTEM,
+            target_component: TARGET_COMPONENT,
+            frame: self.frame,
+            current: if seq == 0 { 1 } else { 0 },
+            autocontinue: 1,
+        })
+    }
+
+    /// Converts the item to a MISSION_ITEM, for autopilots that request items with the legacy
+    /// MISSION_REQUEST message. The coordinates are sent as float degrees.
+    fn to_legacy_message(&self, seq: u16) -> MavMessage {
+        MavMessage::MISSION_ITEM(MISSION_ITEM_DATA {
+            param1: self.params[0],
+            param2: self.params[1],
+            param3: self.params[2],
+            param4: self.params[3],
+            x: (self.lat as f64 / 1e7) as f32,
+            y: (self.lon as f64 / 1e7) as f32,
+            z: self.alt,
+            seq: seq,
+            command: self.command,
+            target_system: TARGET_SYSTEM,
+            target_component: TARGET_COMPONENT,
+            frame: self.frame,
+            current: if seq == 0 { 1 } else { 0 },
+            autocontinue: 1,
+        })
+    }
+}
+
+lazy_static! {
+    /// Only one mission transaction can be in progress at a time
+    static ref TRANSACTION_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Converts the altitude of a mission item to the altitude above mean sea level used by the local
+/// frame and the geofence. `home_alt` is the altitude of the vehicle's home position, if known.
+fn amsl_altitude(item: &MissionItem, home_alt: Option<f32>) -> Result<f32, String> {
+    match item.frame {
+        MAV_FRAME_GLOBAL | MAV_FRAME_GLOBAL_INT => Ok(item.alt),
+        MAV_FRAME_GLOBAL_RELATIVE_ALT | MAV_FRAME_GLOBAL_RELATIVE_ALT_INT => {
+            match home_alt {
+                Some(home_alt) => Ok(home_alt + item.alt),
+                None => Err("relative altitudes require a position from the vehicle".into()),
+            }
+        },
+        frame => Err(format!("unsupported frame {}", frame)),
+    }
+}
+
+/// Checks the position of a mission item against the geofence, returning the reason it is
+/// rejected. Only the horizontal position of landings is checked, since they are expected to be
+/// below the minimum altitude.
+fn fence_violation(fence: &Geofence, item: &MissionItem, alt: f32) -> Option<String> {
+    if !POSITIONAL_COMMANDS.contains(&item.command) {
+        None
+    }
+    else if LANDING_COMMANDS.contains(&item.command) {
+        fence.horizontal_violation(item.x, item.y)
+    }
+    else {
+        fence.violation(&Location { x: item.x, y: item.y, alt: alt })
+    }
+}
+
+/// Uploads a mission specified in the local frame to the vehicle. Items that fly to a position
+/// outside of the geofence are rejected, regardless of the fence action.
+pub fn upload_mission(items: Vec<MissionItem>) -> Result<(), String> {
+    let home_alt = mavlink_handler::home_altitude();
+    let fence = geofence::get();
+
+    let mut global_items = vec![];
+    for (i, item) in items.into_iter().enumerate() {
+        let alt = try!(amsl_altitude(&item, home_alt)
+            .map_err(|e| format!("Mission item {} rejected: {}", i, e)));
+
+        if let Some(reason) = fence_violation(&fence, &item, alt) {
+            return Err(format!("Mission item {} rejected by the geofence: {}", i, reason));
+        }
+
+        let coordinate = match mavlink_handler::local_to_global(item.x, item.y, alt) {
+            Some(coordinate) => coordinate,
+            None => return Err("Unable to upload mission: the origin has not been set yet".into()),
+        };
+
+        global_items.push(GlobalMissionItem {
+            lat: (coordinate.lat * 1e7).round() as i32,
+            lon: (coordinate.lon * 1e7).round() as i32,
+            alt: item.alt,
+            command: item.command,
+            frame: item.frame,
+            params: item.params,
+        });
+    }
+
+    let _lock = TRANSACTION_LOCK.lock().unwrap();
+    if global_items.is_empty() {
+        clear(&mut VehicleLink::new())
+    }
+    else {
+        upload(&mut VehicleLink::new(), &global_items)
+    }
+}
+
+/// Downloads the current mission from the vehicle, converted to the local frame
+pub fn download_mission() -> Result<Vec<MissionItem>, String> {
+    let global_items = {
+        let _lock = TRANSACTION_LOCK.lock().unwrap();
+        try!(download(&mut VehicleLink::new()))
+    };
+
+    let mut items = vec![];
+    for item in global_items {
+        let coordinate = Coordinate {
+            lat: item.lat as f64 / 1e7,
+            lon: item.lon as f64 / 1e7,
+            alt: item.alt as f64,
+        };
+
+        let position = match mavlink_handler::global_to_local(coordinate) {
+            Some(position) => position,
+            None => {
+                return Err("Unable to download mission: the origin has not been set yet".into())
+            },
+        };
+
+        items.push(MissionItem {
+            x: position[0],
+            y: position[1],
+            alt: item.alt,
+            command: item.command,
+            frame: item.frame,
+            params: item.params,
+        });
+    }
+
+    Ok(items)
+}
+
+/// Clears the mission on the vehicle
+pub fn clear_mission() -> Result<(), String> {
+    let _lock = TRANSACTION_LOCK.lock().unwrap();
+    clear(&mut VehicleLink::new())
+}
+
+/// Sends `message` and waits for a response accepted by `handler`, resending the message if no
+/// response is received in time. Responses that `handler` does not accept are ignored.
+fn request<L, F, T>(link: &mut L, message: &MavMessage, mut handler: F) -> Result<T, String>
+    where L: MissionLink, F: FnMut(MavMessage) -> Option<Result<T, String>>
+{
+    let timeout = Duration::from_millis(RESPONSE_TIMEOUT_MS);
+
+    for _ in 0..(MAX_RETRIES + 1) {
+        try!(link.send(message).map_err(|e| format!("Failed to send mission message: {}", e)));
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+
+            match link.recv_timeout(deadline - now) {
+                Some(response) => {
+                    if let Some(result) = handler(response) {
+                        return result;
+                    }
+                },
+                None => break,
+            }
+        }
+    }
+
+    Err("Timed out waiting for a response from the vehicle".into())
+}
+
+fn check_ack(result: u8) -> Result<(), String> {
+    if result == MAV_MISSION_ACCEPTED {
+        Ok(())
+    }
+    else {
+        Err(format!("Mission rejected by the vehicle (MAV_MISSION_RESULT: {})", result))
+    }
+}
+
+/// An item requested by the vehicle during an upload
+enum ItemRequest {
+    /// MISSION_REQUEST_INT, answered with a MISSION_ITEM_INT
+    Int(u16),
+    /// The legacy MISSION_REQUEST, answered with a MISSION_ITEM
+    Legacy(u16),
+}
+
+/// Runs the mission upload protocol:
+///
+/// MISSION_COUNT -> (MISSION_REQUEST_INT(seq) -> MISSION_ITEM_INT(seq))* -> MISSION_ACK
+///
+/// Items may be requested in any order and more than once. Items requested with the legacy
+/// MISSION_REQUEST are sent as MISSION_ITEM.
+pub fn upload<L: MissionLink>(link: &mut L, items: &[GlobalMissionItem]) -> Result<(), String> {
+    let mut message = MavMessage::MISSION_COUNT(MISSION_COUNT_DATA {
+        count: items.len() as u16,
+        target_system: TARGET_SYSTEM,
+        target_component: TARGET_COMPONENT,
+    });
+
+    loop {
+        let next = try!(request(link, &message, |response| {
+            match response {
+                MavMessage::MISSION_REQUEST_INT(data) => Some(Ok(Some(ItemRequest::Int(data.seq)))),
+                MavMessage::MISSION_REQUEST(data) => Some(Ok(Some(ItemRequest::Legacy(data.seq)))),
+                MavMessage::MISSION_ACK(data) => Some(check_ack(data.mavtype).map(|_| None)),
+                _ => None,
+            }
+        }));
+
+        let (seq, legacy) = match next {
+            Some(ItemRequest::Int(seq)) => (seq, false),
+            Some(ItemRequest::Legacy(seq)) => (seq, true),
+            None => return Ok(()),
+        };
+
+        let item = match items.get(seq as usize) {
+            Some(item) => item,
+            None => return Err(format!("Vehicle requested invalid mission item: {}", seq)),
+        };
+
+        message = if legacy { item.to_legacy_message(seq) } else { item.to_message(seq) };
+    }
+}
+
+/// Runs the mission download protocol:
+///
+/// MISSION_REQUEST_LIST -> MISSION_COUNT -> (MISSION_REQUEST_INT(seq) -> MISSION_ITEM_INT(seq))*
+/// -> MISSION_ACK
+pub fn download<L: MissionLink>(link: &mut L) -> Result<Vec<GlobalMissionItem>, String> {
+    let request_list = MavMessage::MISSION_REQUEST_LIST(MISSION_REQUEST_LIST_DATA {
+        target_system: TARGET_SYSTEM,
+        target_component: TARGET_COMPONENT,
+    });
+
+    let count = try!(request(link, &request_list, |response| {
+        match response {
+            MavMessage::MISSION_COUNT(data) => Some(Ok(data.count)),
+            _ => None,
+        }
+    }));
+
+    let mut items = vec![];
+    for seq in 0..count {
+        let request_item = MavMessage::MISSION_REQUEST_INT(MISSION_REQUEST_INT_DATA {
+            seq: seq,
+            target_system: TARGET_SYSTEM,
+            target_component: TARGET_COMPONENT,
+        });
+
+        let item = try!(request(link, &request_item, |response| {
+            match response {
+                MavMessage::MISSION_ITEM_INT(ref data) if data.seq == seq => {
+                    Some(Ok(GlobalMissionItem {
+                        lat: data.x,
+                        lon: data.y,
+                        alt: data.z,
+                        command: data.command,
+                        frame: data.frame,
+                        params: [data.param1, data.param2, data.param3, data.param4],
+                    }))
+                },
+
+                // Older autopilots may respond with float coordinates
+                MavMessage::MISSION_ITEM(ref data) if data.seq == seq => {
+                    Some(Ok(GlobalMissionItem {
+                        lat: (data.x as f64 * 1e7).round() as i32,
+                        lon: (data.y as f64 * 1e7).round() as i32,
+                        alt: data.z,
+                        command: data.command,
+                        frame: data.frame,
+                        params: [data.param1, data.param2, data.param3, data.param4],
+                    }))
+                },
+
+                MavMessage::MISSION_ACK(data) => {
+                    Some(Err(format!("Mission download aborted by the vehicle \
+                        (MAV_MISSION_RESULT: {})", data.mavtype)))
+                },
+
+                _ => None,
+            }
+        }));
+
+        items.push(item);
+    }
+
+    let ack = MavMessage::MISSION_ACK(MISSION_ACK_DATA {
+        target_system: TARGET_SYSTEM,
+        target_component: TARGET_COMPONENT,
+        mavtype: MAV_MISSION_ACCEPTED,
+    });
+    try!(link.send(&ack).map_err(|e| format!("Failed to send mission message: {}", e)));
+
+    Ok(items)
+}
+
+/// Runs the mission clear protocol: MISSION_CLEAR_ALL -> MISSION_ACK
+pub fn clear<L: MissionLink>(link: &mut L) -> Result<(), String> {
+    let clear_all = MavMessage::MISSION_CLEAR_ALL(MISSION_CLEAR_ALL_DATA {
+        target_system: TARGET_SYSTEM,
+        target_component: TARGET_COMPONENT,
+    });
+
+    request(link, &clear_all, |response| {
+        match response {
+            MavMessage::MISSION_ACK(data) => Some(check_ack(data.mavtype)),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io;
+    use std::time::Duration;
+
+    use mavlink::common::*;
+
+    use geofence::Geofence;
+
+    use super::{GlobalMissionItem, MissionItem, MissionLink, MAX_RETRIES, MAV_CMD_NAV_LAND,
+        MAV_CMD_NAV_WAYPOINT, MAV_FRAME_GLOBAL, MAV_FRAME_GLOBAL_INT,
+        MAV_FRAME_GLOBAL_RELATIVE_ALT_INT, amsl_altitude, clear, download, fence_violation,
+        upload};
+
+    /// A fake autopilot that answers each message it is sent with the next set of scripted
+    /// responses, and records everything that was sent to it.
+    struct ScriptedLink {
+        script: VecDeque<Vec<MavMessage>>,
+        pending: VecDeque<MavMessage>,
+        sent: Vec<MavMessage>,
+    }
+
+    impl ScriptedLink {
+        fn new(script: Vec<Vec<MavMessage>>) -> ScriptedLink {
+            ScriptedLink {
+                script: script.into_iter().collect(),
+                pending: VecDeque::new(),
+                sent: vec![],
+            }
+        }
+    }
+
+    impl MissionLink for ScriptedLink {
+        fn send(&mut self, message: &MavMessage) -> io::Result<()> {
+            self.sent.push(message.clone());
+            if let Some(responses) = self.script.pop_front() {
+                self.pending.extend(responses);
+            }
+            Ok(())
+        }
+
+        fn recv_timeout(&mut self, _timeout: Duration) -> Option<MavMessage> {
+            self.pending.pop_front()
+        }
+    }
+
+    fn items() -> Vec<GlobalMissionItem> {
+        (0..3).map(|i| {
+            GlobalMissionItem {
+                lat: -274700000 + i * 1000,
+                lon: 1530250000 - i * 1000,
+                alt: 30.0 + i as f32,
+                command: MAV_CMD_NAV_WAYPOINT,
+                frame: MAV_FRAME_GLOBAL_INT,
+                params: [0.0, 2.0, 0.0, 0.0],
+            }
+        }).collect()
+    }
+
+    fn request_int(seq: u16) -> MavMessage {
+        MavMessage::MISSION_REQUEST_INT(MISSION_REQUEST_INT_DATA {
+            seq: seq,
+            target_system: 255,
+            target_component: 0,
+        })
+    }
+
+    fn request(seq: u16) -> MavMessage {
+        MavMessage::MISSION_REQUEST(MISSION_REQUEST_DATA {
+            seq: seq,
+            target_system: 255,
+            target_component: 0,
+        })
+    }
+
+    fn ack(result: u8) -> MavMessage {
+        MavMessage::MISSION_ACK(MISSION_ACK_DATA {
+            target_system: 255,
+            target_component: 0,
+            mavtype: result,
+        })
+    }
+
+    fn count(count: u16) -> MavMessage {
+        MavMessage::MISSION_COUNT(MISSION_COUNT_DATA {
+            count: count,
+            target_system: 255,
+            target_component: 0,
+        })
+    }
+
+    /// Gets the sequence numbers of the MISSION_ITEM_INT messages that were sent
+    fn sent_items(link: &ScriptedLink) -> Vec<u16> {
+        link.sent.iter().filter_map(|message| {
+            match *message {
+                MavMessage::MISSION_ITEM_INT(ref data) => Some(data.seq),
+                _ => None,
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn upload_in_order() {
+        let items = items();
+        let mut link = ScriptedLink::new(vec![
+            vec![request_int(0)],
+            vec![request_int(1)],
+            vec![request_int(2)],
+            vec![ack(0)],
+        ]);
+
+        upload(&mut link, &items).unwrap();
+
+        match link.sent[0] {
+            MavMessage::MISSION_COUNT(ref data) => assert_eq!(data.count, 3),
+            _ => panic!("Expected MISSION_COUNT"),
+        }
+        assert_eq!(sent_items(&link), vec![0, 1, 2]);
+
+        for (message, item) in link.sent[1..].iter().zip(items.iter()) {
+            match *message {
+                MavMessage::MISSION_ITEM_INT(ref data) => {
+                    assert_eq!((data.x, data.y, data.z), (item.lat, item.lon, item.alt));
+                    assert_eq!(data.current, if data.seq == 0 { 1 } else { 0 });
+                },
+                _ => panic!("Expected MISSION_ITEM_INT"),
+            }
+        }
+    }
+
+    #[test]
+    fn upload_out_of_order_and_repeated_requests() {
+        let mut link = ScriptedLink::new(vec![
+            vec![request_int(2)],
+            vec![request_int(0)],
+            // A lost item is requested again
+            vec![request_int(0)],
+            vec![request_int(1)],
+            vec![request_int(1)],
+            vec![ack(0)],
+        ]);
+
+        upload(&mut link, &items()).unwrap();
+        assert_eq!(sent_items(&link), vec![2, 0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn upload_legacy_requests() {
+        let items = items();
+        let mut link = ScriptedLink::new(vec![
+            vec![request(0)],
+            vec![request_int(1)],
+            vec![request(2)],
+            vec![ack(0)],
+        ]);
+
+        upload(&mut link, &items).unwrap();
+        assert_eq!(sent_items(&link), vec![1]);
+
+        match link.sent[3] {
+            MavMessage::MISSION_ITEM(ref data) => {
+                assert_eq!(data.seq, 2);
+                assert!((data.x as f64 - items[2].lat as f64 / 1e7).abs() < 1e-5);
+                assert!((data.y as f64 - items[2].lon as f64 / 1e7).abs() < 1e-5);
+                assert_eq!(data.z, items[2].alt);
+            },
+            _ => panic!("Expected MISSION_ITEM"),
+        }
+    }
+
+    #[test]
+    fn upload_rejected() {
+        let mut link = ScriptedLink::new(vec![vec![request_int(0)], vec![ack(1)]]);
+
+        let error = upload(&mut link, &items()).unwrap_err();
+        assert!(error.contains("MAV_MISSION_RESULT: 1"), "{}", error);
+    }
+
+    #[test]
+    fn upload_invalid_request() {
+        let mut link = ScriptedLink::new(vec![vec![request_int(3)]]);
+        assert!(upload(&mut link, &items()).is_err());
+    }
+
+    #[test]
+    fn upload_timeout() {
+        // The count is resent until the retries run out
+        let mut link = ScriptedLink::new(vec![]);
+
+        let error = upload(&mut link, &items()).unwrap_err();
+        assert!(error.contains("Timed out"), "{}", error);
+        assert_eq!(link.sent.len(), MAX_RETRIES as usize + 1);
+    }
+
+    #[test]
+    fn upload_retry_after_timeout() {
+        // The first item is lost, so the vehicle doesn't respond until it is resent
+        let mut link = ScriptedLink::new(vec![
+            vec![request_int(0)],
+            vec![],
+            vec![ack(0)],
+        ]);
+
+        upload(&mut link, &items()[..1]).unwrap();
+        assert_eq!(sent_items(&link), vec![0, 0]);
+    }
+
+    #[test]
+    fn download_mission() {
+        let items = items();
+        let mut link = ScriptedLink::new(vec![
+            vec![count(3)],
+            vec![items[0].to_message(0)],
+            // A stale item is ignored while waiting for the requested one
+            vec![items[0].to_message(0), items[1].to_message(1)],
+            vec![items[2].to_legacy_message(2)],
+        ]);
+
+        let downloaded = download(&mut link).unwrap();
+        assert_eq!(&downloaded[..2], &items[..2]);
+        assert_eq!(downloaded[2].alt, items[2].alt);
+        assert!((downloaded[2].lat - items[2].lat).abs() < 200);
+        assert!((downloaded[2].lon - items[2].lon).abs() < 200);
+
+        match *link.sent.last().unwrap() {
+            MavMessage::MISSION_ACK(ref data) => assert_eq!(data.mavtype, 0),
+            _ => panic!("Expected MISSION_ACK"),
+        }
+    }
+
+    #[test]
+    fn download_empty() {
+        let mut link = ScriptedLink::new(vec![vec![count(0)]]);
+        assert!(download(&mut link).unwrap().is_empty());
+    }
+
+    #[test]
+    fn download_aborted() {
+        let mut link = ScriptedLink::new(vec![vec![count(2)], vec![ack(1)]]);
+        assert!(download(&mut link).is_err());
+    }
+
+    #[test]
+    fn download_timeout() {
+        let mut link = ScriptedLink::new(vec![vec![count(1)]]);
+
+        let error = download(&mut link).unwrap_err();
+        assert!(error.contains("Timed out"), "{}", error);
+    }
+
+    #[test]
+    fn clear_mission() {
+        let mut link = ScriptedLink::new(vec![vec![ack(0)]]);
+        clear(&mut link).unwrap();
+
+        match link.sent[0] {
+            MavMessage::MISSION_CLEAR_ALL(_) => {},
+            _ => panic!("Expected MISSION_CLEAR_ALL"),
+        }
+    }
+
+    #[test]
+    fn clear_rejected() {
+        let mut link = ScriptedLink::new(vec![vec![ack(1)]]);
+        assert!(clear(&mut link).is_err());
+    }
+
+    fn item(command: u16, frame: u8, alt: f32) -> MissionItem {
+        MissionItem {
+            x: 10.0,
+            y: 20.0,
+            alt: alt,
+            command: command,
+            frame: frame,
+            params: [0.0; 4],
+        }
+    }
+
+    #[test]
+    fn item_altitudes() {
+        let absolute = item(MAV_CMD_NAV_WAYPOINT, MAV_FRAME_GLOBAL_INT, 130.0);
+        assert_eq!(amsl_altitude(&absolute, None), Ok(130.0));
+        let legacy = item(MAV_CMD_NAV_WAYPOINT, MAV_FRAME_GLOBAL, 130.0);
+        assert_eq!(amsl_altitude(&legacy, Some(100.0)), Ok(130.0));
+
+        // Relative altitudes are above the home position
+        let relative = item(MAV_CMD_NAV_WAYPOINT, MAV_FRAME_GLOBAL_RELATIVE_ALT_INT, 30.0);
+        assert_eq!(amsl_altitude(&relative, Some(100.0)), Ok(130.0));
+        assert!(amsl_altitude(&relative, None).is_err());
+
+        // MAV_FRAME_LOCAL_NED
+        assert!(amsl_altitude(&item(MAV_CMD_NAV_WAYPOINT, 1, 30.0), Some(100.0)).is_err());
+    }
+
+    #[test]
+    fn item_fence_checks() {
+        let fence = Geofence {
+            inclusion: vec![[0.0, 0.0], [100.0, 0.0], [100.0, 100.0], [0.0, 100.0]],
+            min_alt: Some(110.0),
+            max_alt: Some(200.0),
+            ..Geofence::default()
+        };
+        let home_alt = Some(100.0);
+        let check = |item: &MissionItem| {
+            let alt = amsl_altitude(item, home_alt).unwrap();
+            fence_violation(&fence, item, alt)
+        };
+
+        // 5 m above home is below the fence, but 30 m is inside it
+        let low = item(MAV_CMD_NAV_WAYPOINT, MAV_FRAME_GLOBAL_RELATIVE_ALT_INT, 5.0);
+        assert!(check(&low).is_some());
+        let high = item(MAV_CMD_NAV_WAYPOINT, MAV_FRAME_GLOBAL_RELATIVE_ALT_INT, 30.0);
+        assert!(check(&high).is_none());
+
+        // Landings are only checked horizontally
+        let land = item(MAV_CMD_NAV_LAND, MAV_FRAME_GLOBAL_RELATIVE_ALT_INT, 0.0);
+        assert!(check(&land).is_none());
+        let outside = MissionItem { x: 150.0, ..land.clone() };
+        assert!(check(&outside).is_some());
+
+        // MAV_CMD_DO_CHANGE_SPEED doesn't fly to a position
+        let speed = MissionItem { x: 150.0, ..item(178, MAV_FRAME_GLOBAL_INT, 0.0) };
+        assert!(check(&speed).is_none());
+    }
+}
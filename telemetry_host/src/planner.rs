@@ -0,0 +1,404 @@
+//! Generation of coverage search patterns for radio tracking surveys. All coordinates are in the
+//! local frame used by `GpsBase` ([x, y] = [east, north] in meters).
+
+use std::f32;
+use std::f64::consts::PI;
+
+use mavlink_handler::Location;
+
+/// The maximum number of waypoints that a plan can contain
+const MAX_WAYPOINTS: usize = 2000;
+
+/// The maximum number of steps along a spiral. Most of the steps can fall outside of a long, thin
+/// search area, so the number of waypoints alone doesn't limit the time taken to plan it.
+const MAX_SPIRAL_STEPS: usize = MAX_WAYPOINTS * 100;
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SearchPattern {
+    /// Parallel sweep lines across the area, alternating direction
+    Lawnmower,
+    /// Square legs of increasing length around the center of the area
+    ExpandingSquare,
+    /// An Archimedean spiral out from the center of the area
+    Spiral,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchArea {
+    /// The vertices of the search area polygon as [x, y]
+    pub polygon: Vec<[f32; 2]>,
+    /// The altitude to fly the search at
+    pub alt: f32,
+    /// The distance between adjacent sweeps in meters
+    pub spacing: f32,
+    /// The direction of the sweeps (or first leg) in degrees clockwise from north
+    #[serde(default)]
+    pub heading: f32,
+    pub pattern: SearchPattern,
+}
+
+/// Generates the waypoints for a search of the area
+pub fn plan(area: &SearchArea) -> Result<Vec<Location>, String> {
+    if area.polygon.len() < 3 {
+        return Err("The search area must have at least 3 vertices".into());
+    }
+    if area.polygon.iter().any(|p| !p[0].is_finite() || !p[1].is_finite()) {
+        return Err("The search area contains an invalid vertex".into());
+    }
+    if !(area.spacing > 0.0) {
+        return Err("The sweep spacing must be greater than 0".into());
+    }
+    if !area.alt.is_finite() || !area.heading.is_finite() {
+        return Err("Invalid altitude or heading".into());
+    }
+
+    let points = match area.pattern {
+        SearchPattern::Lawnmower => lawnmower(area),
+        SearchPattern::ExpandingSquare => expanding_square(area),
+        SearchPattern::Spiral => try!(spiral(area)),
+    };
+
+    if points.is_empty() {
+        return Err("The search area is too small for the sweep spacing".into());
+    }
+    if points.len() > MAX_WAYPOINTS {
+        return Err(format!("The search requires {} waypoints (maximum: {}), increase the spacing",
+            points.len(), MAX_WAYPOINTS));
+    }
+
+    Ok(points.into_iter().map(|p| Location { x: p[0], y: p[1], alt: area.alt }).collect())
+}
+
+/// Returns the unit vector pointing along a heading (in degrees clockwise from north)
+fn direction(heading: f32) -> [f32; 2] {
+    let heading = heading.to_radians();
+    [heading.sin(), heading.cos()]
+}
+
+fn dot(a: [f32; 2], b: [f32; 2]) -> f32 {
+    a[0] * b[0] + a[1] * b[1]
+}
+
+fn centroid(polygon: &[[f32; 2]]) -> [f32; 2] {
+    let n = polygon.len() as f32;
+    let sum = polygon.iter().fold([0.0, 0.0], |acc, p| [acc[0] + p[0], acc[1] + p[1]]);
+    [sum[0] / n, sum[1] / n]
+}
+
+/// Returns true if the point is inside the polygon (using the even-odd rule)
+pub fn contains(polygon: &[[f32; 2]], point: [f32; 2]) -> bool {
+    let mut inside = false;
+    let mut j = polygon.len() - 1;
+
+    for i in 0..polygon.len() {
+        let (a, b) = (polygon[i], polygon[j]);
+        if (a[1] > point[1]) != (b[1] > point[1]) {
+            let x = a[0] + (point[1] - a[1]) / (b[1] - a[1]) * (b[0] - a[0]);
+            if point[0] < x {
+                inside = !inside;
+            }
+        }
+        j = i;
+    }
+
+    inside
+}
+
+/// Sweeps lines parallel to the heading across the polygon. Each line is clipped to the polygon,
+/// and consecutive lines are flown in opposite directions.
+fn lawnmower(area: &SearchArea) -> Vec<[f32; 2]> {
+    let along = direction(area.heading);
+    let across = [along[1], -along[0]];
+
+    // Project the polygon into (across, along) coordinates
+    let projected: Vec<[f32; 2]> = area.polygon.iter()
+        .map(|&p| [dot(p, across), dot(p, along)])
+        .collect();
+
+    let min_u = projected.iter().fold(f32::INFINITY, |min, p| min.min(p[0]));
+    let max_u = projected.iter().fold(f32::NEG_INFINITY, |max, p| max.max(p[0]));
+
+    let mut points = vec![];
+    let mut reverse = false;
+    let mut u = min_u + area.spacing / 2.0;
+
+    while u < max_u && points.len() <= MAX_WAYPOINTS {
+        // Find where the sweep line crosses each edge of the polygon
+        let mut crossings = vec![];
+        let mut j = projected.len() - 1;
+        for i in 0..projected.len() {
+            let (a, b) = (projected[i], projected[j]);
+            if (a[0] > u) != (b[0] > u) {
+                crossings.push(a[1] + (u - a[0]) / (b[0] - a[0]) * (b[1] - a[1]));
+            }
+            j = i;
+        }
+        crossings.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        // Pairs of crossings are the segments of the line inside the polygon
+        let mut segments: Vec<(f32, f32)> = crossings.chunks(2)
+            .filter(|pair| pair.len() == 2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect();
+
+        if reverse {
+            segments.reverse();
+        }
+
+        for (start, end) in segments {
+            let (start, end) = if reverse { (end, start) } else { (start, end) };
+            for &v in &[start, end] {
+                points.push([u * across[0] + v * along[0], u * across[1] + v * along[1]]);
+            }
+        }
+
+        reverse = !reverse;
+        u += area.spacing;
+    }
+
+    points
+}
+
+/// Flies square legs of increasing length (1, 1, 2, 2, 3, 3, ... times the spacing) out from the
+/// center of the polygon. Waypoints outside of the polygon are skipped.
+fn expanding_square(area: &SearchArea) -> Vec<[f32; 2]> {
+    let center = centroid(&area.polygon);
+    let radius = max_radius(&area.polygon, center);
+
+    let mut heading = area.heading;
+    let mut position = center;
+    let mut points = vec![];
+    if contains(&area.polygon, position) {
+        points.push(position);
+    }
+
+    let mut leg = 0;
+    loop {
+        let length = (leg / 2 + 1) as f32 * area.spacing;
+        if length / 2.0 > radius || points.len() > MAX_WAYPOINTS {
+            break;
+        }
+
+        let d = direction(heading);
+        position = [position[0] + d[0] * length, position[1] + d[1] * length];
+        if contains(&area.polygon, position) {
+            points.push(position);
+        }
+
+        heading += 90.0;
+        leg += 1;
+    }
+
+    points
+}
+
+/// Flies an Archimedean spiral out from the center of the polygon, with adjacent turns separated
+/// by the spacing. Waypoints outside of the polygon are skipped.
+fn spiral(area: &SearchArea) -> Result<Vec<[f32; 2]>, String> {
+    let center = centroid(&area.polygon);
+    let radius = max_radius(&area.polygon, center) as f64;
+    let spacing = area.spacing as f64;
+    let start = (area.heading as f64).to_radians();
+
+    // The angle is an f64 since the steps become smaller than the precision of an f32 after a few
+    // thousand turns, after which it would stop increasing
+    let mut points = vec![];
+    let mut theta: f64 = 0.0;
+    for _ in 0..MAX_SPIRAL_STEPS {
+        let r = spacing * theta / (2.0 * PI);
+        if r > radius || points.len() > MAX_WAYPOINTS {
+            return Ok(points);
+        }
+
+        // Angles are measured clockwise from north, matching headings
+        let angle = start + theta;
+        let point = [center[0] + (r * angle.sin()) as f32, center[1] + (r * angle.cos()) as f32];
+        if contains(&area.polygon, point) {
+            points.push(point);
+        }
+
+        // Space waypoints roughly one sweep spacing apart along the spiral
+        theta += spacing / r.max(spacing);
+    }
+
+    Err(format!("The spiral search requires more than {} steps, increase the spacing",
+        MAX_SPIRAL_STEPS))
+}
+
+/// The distance from `center` to the furthest vertex of the polygon
+fn max_radius(polygon: &[[f32; 2]], center: [f32; 2]) -> f32 {
+    polygon.iter()
+        .map(|p| ((p[0] - center[0]).powi(2) + (p[1] - center[1]).powi(2)).sqrt())
+        .fold(0.0, |max: f32, r| max.max(r))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SearchArea, SearchPattern, contains, plan};
+
+    const SQUARE: [[f32; 2]; 4] =
+        [[-100.0, -100.0], [100.0, -100.0], [100.0, 100.0], [-100.0, 100.0]];
+
+    const SMALL_SQUARE: [[f32; 2]; 4] = [[0.0, 0.0], [100.0, 0.0], [100.0, 100.0], [0.0, 100.0]];
+
+    /// An L shape with the top right corner (x > 40, y > 40) cut out
+    const L_SHAPE: [[f32; 2]; 6] =
+        [[0.0, 0.0], [100.0, 0.0], [100.0, 40.0], [40.0, 40.0], [40.0, 100.0], [0.0, 100.0]];
+
+    fn area(polygon: &[[f32; 2]], pattern: SearchPattern, heading: f32) -> SearchArea {
+        SearchArea {
+            polygon: polygon.to_vec(),
+            alt: 30.0,
+            spacing: 10.0,
+            heading: heading,
+            pattern: pattern,
+        }
+    }
+
+    fn points(area: &SearchArea) -> Vec<[f32; 2]> {
+        plan(area).unwrap().iter().map(|location| {
+            assert_eq!(location.alt, area.alt);
+            [location.x, location.y]
+        }).collect()
+    }
+
+    fn distance(a: [f32; 2], b: [f32; 2]) -> f32 {
+        ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2)).sqrt()
+    }
+
+    fn assert_close(a: [f32; 2], b: [f32; 2]) {
+        assert!(distance(a, b) < 1e-3, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn polygon_containment() {
+        assert!(contains(&SQUARE, [0.0, 0.0]));
+        assert!(contains(&SQUARE, [99.0, -99.0]));
+        assert!(!contains(&SQUARE, [101.0, 0.0]));
+        assert!(!contains(&SQUARE, [0.0, -101.0]));
+
+        assert!(contains(&L_SHAPE, [20.0, 90.0]));
+        assert!(contains(&L_SHAPE, [90.0, 20.0]));
+        assert!(!contains(&L_SHAPE, [60.0, 60.0]));
+        assert!(!contains(&L_SHAPE, [-1.0, 50.0]));
+    }
+
+    #[test]
+    fn lawnmower_sweeps() {
+        let points = points(&area(&SMALL_SQUARE, SearchPattern::Lawnmower, 0.0));
+
+        // 10 northward sweeps, 5 m in from the edges and 10 m apart, alternating direction
+        assert_eq!(points.len(), 20);
+        for (i, sweep) in points.chunks(2).enumerate() {
+            let x = 5.0 + 10.0 * i as f32;
+            let (start, end) = if i % 2 == 0 { (0.0, 100.0) } else { (100.0, 0.0) };
+            assert_close(sweep[0], [x, start]);
+            assert_close(sweep[1], [x, end]);
+        }
+    }
+
+    #[test]
+    fn lawnmower_heading() {
+        let points = points(&area(&SMALL_SQUARE, SearchPattern::Lawnmower, 90.0));
+
+        // Sweeps run east-west and step south
+        assert_eq!(points.len(), 20);
+        for (i, sweep) in points.chunks(2).enumerate() {
+            assert!((sweep[0][1] - sweep[1][1]).abs() < 1e-3, "{:?}", sweep);
+            assert!((sweep[0][1] - (95.0 - 10.0 * i as f32)).abs() < 1e-3, "{:?}", sweep);
+            assert!((sweep[0][0] - sweep[1][0]).abs() > 99.0, "{:?}", sweep);
+        }
+    }
+
+    #[test]
+    fn lawnmower_concave() {
+        let points = points(&area(&L_SHAPE, SearchPattern::Lawnmower, 0.0));
+        assert_eq!(points.len(), 20);
+
+        for sweep in points.chunks(2) {
+            let middle = [(sweep[0][0] + sweep[1][0]) / 2.0, (sweep[0][1] + sweep[1][1]) / 2.0];
+            assert!(contains(&L_SHAPE, middle), "{:?}", sweep);
+
+            // Sweeps beside the notch stop at its edge
+            let max_y = if middle[0] > 40.0 { 40.0 } else { 100.0 };
+            assert!(sweep.iter().all(|p| p[1] <= max_y + 1e-3), "{:?}", sweep);
+        }
+    }
+
+    #[test]
+    fn expanding_square_legs() {
+        let points = points(&area(&SQUARE, SearchPattern::ExpandingSquare, 0.0));
+
+        // Legs of 10, 10, 20, 20, 30, ... meters turning clockwise from north
+        assert_close(points[0], [0.0, 0.0]);
+        assert_close(points[1], [0.0, 10.0]);
+        assert_close(points[2], [10.0, 10.0]);
+        assert_close(points[3], [10.0, -10.0]);
+        assert_close(points[4], [-10.0, -10.0]);
+        assert_close(points[5], [-10.0, 20.0]);
+
+        assert!(points.len() > 20);
+        assert!(points.iter().all(|&p| contains(&SQUARE, p)));
+    }
+
+    #[test]
+    fn spiral_turns() {
+        let points = points(&area(&SQUARE, SearchPattern::Spiral, 0.0));
+        assert_close(points[0], [0.0, 0.0]);
+        assert!(points.iter().all(|&p| contains(&SQUARE, p)));
+
+        // The spiral moves steadily outwards with waypoints about one spacing apart
+        let center = [0.0, 0.0];
+        let inner: Vec<[f32; 2]> = points.iter()
+            .cloned()
+            .take_while(|&p| distance(p, center) < 90.0)
+            .collect();
+        assert!(inner.len() > 50);
+
+        for pair in inner.windows(2) {
+            assert!(distance(pair[1], center) >= distance(pair[0], center));
+            assert!(distance(pair[0], pair[1]) < 15.0, "{:?}", pair);
+        }
+    }
+
+    #[test]
+    fn patterns_stay_inside_concave_area() {
+        for &pattern in &[SearchPattern::ExpandingSquare, SearchPattern::Spiral] {
+            let points = points(&area(&L_SHAPE, pattern, 30.0));
+            assert!(!points.is_empty());
+            assert!(points.iter().all(|&p| contains(&L_SHAPE, p)), "{:?}", pattern);
+        }
+    }
+
+    #[test]
+    fn invalid_areas() {
+        let triangle: [[f32; 2]; 3] = [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]];
+        assert!(plan(&area(&SQUARE[..2], SearchPattern::Lawnmower, 0.0)).is_err());
+        assert!(plan(&area(&triangle, SearchPattern::Lawnmower, 0.0)).is_err());
+
+        let mut zero_spacing = area(&SQUARE, SearchPattern::Lawnmower, 0.0);
+        zero_spacing.spacing = 0.0;
+        assert!(plan(&zero_spacing).is_err());
+
+        let mut too_many = area(&SQUARE, SearchPattern::Lawnmower, 0.0);
+        too_many.spacing = 0.05;
+        assert!(plan(&too_many).is_err());
+    }
+
+    #[test]
+    fn spiral_thin_strip() {
+        // Almost all of the spiral is outside of the strip, so the search is rejected for the
+        // number of steps rather than waypoints
+        let strip: [[f32; 2]; 4] = [[0.0, 0.0], [2000.0, 0.0], [2000.0, 1.0], [0.0, 1.0]];
+        let mut thin = area(&strip, SearchPattern::Spiral, 0.0);
+        thin.spacing = 0.5;
+        let error = plan(&thin).unwrap_err();
+        assert!(error.contains("steps"), "{}", error);
+
+        // The same strip is fine with a wider spacing
+        thin.spacing = 20.0;
+        let points = points(&thin);
+        assert!(!points.is_empty());
+        assert!(points.iter().all(|&p| contains(&strip, p)));
+    }
+}
@@ -0,0 +1,221 @@
+//! Changes to the pulse server's detector config: replacing the pulse targets, changing the gain
+//! settings of the HackRF, and checking that the result is usable before it is sent.
+
+use common::{Config, PulseTarget};
+
+/// The gain settings of the HackRF that can be changed remotely. Fields that are not set are left
+/// unchanged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GainSettings {
+    #[serde(default)]
+    pub auto_gain: Option<bool>,
+    /// The LNA (IF) gain in dB, 0 to 40 in steps of 8
+    #[serde(default)]
+    pub lna_gain: Option<u32>,
+    /// The VGA (baseband) gain in dB, 0 to 62 in steps of 2
+    #[serde(default)]
+    pub vga_gain: Option<u32>,
+    #[serde(default)]
+    pub amp_enable: Option<bool>,
+}
+
+impl GainSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(gain) = self.lna_gain {
+            if gain > 40 || gain % 8 != 0 {
+                return Err(format!("Invalid LNA gain `{}`: expected 0 to 40 in steps of 8", gain));
+            }
+        }
+        if let Some(gain) = self.vga_gain {
+            if gain > 62 || gain % 2 != 0 {
+                return Err(format!("Invalid VGA gain `{}`: expected 0 to 62 in steps of 2", gain));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the config with its pulse targets replaced
+pub fn with_targets(config: &Config, targets: Vec<PulseTarget>) -> Result<Config, String> {
+    if targets.is_empty() {
+        return Err("Expected a non-empty list of pulse targets".into());
+    }
+
+    let mut config = config.clone();
+    config.pulse_targets = targets;
+    try!(validate(&config));
+    Ok(config)
+}
+
+/// Returns the config with the gain settings applied
+pub fn with_gain(config: &Config, gain: &GainSettings) -> Result<Config, String> {
+    try!(gain.validate());
+
+    let mut config = config.clone();
+    {
+        let hackrf_config = &mut config.hackrf_config;
+        if let Some(auto_gain) = gain.auto_gain {
+            hackrf_config.auto_gain = auto_gain;
+        }
+        if let Some(lna_gain) = gain.lna_gain {
+            hackrf_config.lna_gain = lna_gain;
+        }
+        if let Some(vga_gain) = gain.vga_gain {
+            hackrf_config.vga_gain = vga_gain;
+        }
+        if let Some(amp_enable) = gain.amp_enable {
+            hackrf_config.amp_enable = amp_enable;
+        }
+    }
+
+    Ok(config)
+}
+
+/// Checks that the pulse targets of a config can be detected: each target must have a positive
+/// duration and threshold, and a frequency within the bandwidth of the receiver.
+pub fn validate(config: &Config) -> Result<(), String> {
+    let center_freq = config.hackrf_config.center_freq as f64;
+    let samp_rate = config.hackrf_config.samp_rate as f64;
+
+    for (i, target) in config.pulse_targets.iter().enumerate() {
+        let freq = target.freq as f64;
+        if !(freq > 0.0) {
+            return Err(format!("Pulse target {}: invalid frequency `{}`", i, freq));
+        }
+
+        if (freq - center_freq).abs() > samp_rate / 2.0 {
+            return Err(format!("Pulse target {}: {} Hz is outside of the receiver's band \
+                ({} Hz +/- {} Hz)", i, freq, center_freq, samp_rate / 2.0));
+        }
+
+        let values = [("duration", target.duration as f64), ("threshold", target.threshold as f64)];
+        for &(key, x) in &values {
+            if !(x > 0.0 && x.is_finite()) {
+                return Err(format!("Pulse target {}: invalid {} `{}`", i, key, x));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64;
+
+    use common::{Config, PulseTarget};
+    use serde_json;
+
+    use super::{GainSettings, validate, with_gain, with_targets};
+
+    fn gain() -> GainSettings {
+        GainSettings { auto_gain: None, lna_gain: None, vga_gain: None, amp_enable: None }
+    }
+
+    /// Returns a pulse target offset from the center frequency of the default config
+    fn target(offset: f64) -> PulseTarget {
+        let mut target: PulseTarget = serde_json::from_str(r#"{ "freq": 150130000.0,
+            "duration": 0.0185, "duration_variance": 0.0002, "threshold": 0.0005,
+            "edge_length": 10, "peak_lookahead": 5 }"#).unwrap();
+        target.freq = (Config::default().hackrf_config.center_freq as f64 + offset) as _;
+        target
+    }
+
+    fn half_band() -> f64 {
+        Config::default().hackrf_config.samp_rate as f64 / 2.0
+    }
+
+    #[test]
+    fn gain_steps() {
+        for &lna_gain in &[0, 8, 16, 40] {
+            assert!(GainSettings { lna_gain: Some(lna_gain), ..gain() }.validate().is_ok());
+        }
+        for &lna_gain in &[4, 41, 48] {
+            assert!(GainSettings { lna_gain: Some(lna_gain), ..gain() }.validate().is_err());
+        }
+
+        for &vga_gain in &[0, 2, 20, 62] {
+            assert!(GainSettings { vga_gain: Some(vga_gain), ..gain() }.validate().is_ok());
+        }
+        for &vga_gain in &[3, 63, 64] {
+            assert!(GainSettings { vga_gain: Some(vga_gain), ..gain() }.validate().is_err());
+        }
+
+        assert!(gain().validate().is_ok());
+    }
+
+    #[test]
+    fn gain_changes() {
+        let config = Config::default();
+
+        let changed = with_gain(&config, &GainSettings {
+            lna_gain: Some(16),
+            amp_enable: Some(!config.hackrf_config.amp_enable),
+            ..gain()
+        }).unwrap();
+        assert_eq!(changed.hackrf_config.lna_gain, 16);
+        assert_eq!(changed.hackrf_config.amp_enable, !config.hackrf_config.amp_enable);
+        // Fields that are not set are unchanged
+        assert_eq!(changed.hackrf_config.vga_gain, config.hackrf_config.vga_gain);
+        assert_eq!(changed.hackrf_config.auto_gain, config.hackrf_config.auto_gain);
+        assert_eq!(changed.pulse_targets.len(), config.pulse_targets.len());
+
+        assert!(with_gain(&config, &GainSettings { lna_gain: Some(12), ..gain() }).is_err());
+        assert!(with_gain(&config, &GainSettings { vga_gain: Some(70), ..gain() }).is_err());
+    }
+
+    #[test]
+    fn target_changes() {
+        let config = Config::default();
+
+        let changed = with_targets(&config, vec![target(0.0), target(1000.0)]).unwrap();
+        assert_eq!(changed.pulse_targets.len(), 2);
+        let freq = changed.pulse_targets[1].freq as f64;
+        assert!((freq - config.hackrf_config.center_freq as f64 - 1000.0).abs() < 100.0);
+        assert_eq!(changed.hackrf_config.lna_gain, config.hackrf_config.lna_gain);
+
+        assert!(with_targets(&config, vec![]).is_err());
+        assert!(with_targets(&config, vec![target(0.0), target(half_band() * 2.0)]).is_err());
+    }
+
+    #[test]
+    fn receiver_band() {
+        let mut config = Config::default();
+
+        for &offset in &[-half_band() * 0.9, 0.0, half_band() * 0.9] {
+            config.pulse_targets = vec![target(offset)];
+            assert!(validate(&config).is_ok(), "{}", offset);
+        }
+        for &offset in &[-half_band() * 1.1, half_band() * 1.1] {
+            config.pulse_targets = vec![target(offset)];
+            assert!(validate(&config).is_err(), "{}", offset);
+        }
+
+        // Frequencies must be positive
+        let mut zero = target(0.0);
+        zero.freq = 0.0;
+        config.pulse_targets = vec![zero];
+        assert!(validate(&config).is_err());
+
+        // The error identifies the target
+        config.pulse_targets = vec![target(0.0), target(half_band() * 1.1)];
+        assert!(validate(&config).unwrap_err().contains("Pulse target 1"));
+    }
+
+    #[test]
+    fn target_values() {
+        let mut config = Config::default();
+
+        for &value in &[0.0, -0.01, f64::NAN, f64::INFINITY] {
+            let mut invalid = target(0.0);
+            invalid.duration = value as _;
+            config.pulse_targets = vec![invalid];
+            assert!(validate(&config).unwrap_err().contains("duration"), "{}", value);
+
+            let mut invalid = target(0.0);
+            invalid.threshold = value as _;
+            config.pulse_targets = vec![invalid];
+            assert!(validate(&config).unwrap_err().contains("threshold"), "{}", value);
+        }
+    }
+}
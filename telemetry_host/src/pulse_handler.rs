@@ -1,80 +1,571 @@
+use std::cmp;
 use std::io::prelude::*;
+use std::io::{self, ErrorKind};
 use std::net::TcpStream;
 use std::str;
 use std::sync::Mutex;
+use std::sync::atomic::{ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT, AtomicBool, AtomicUsize, Ordering};
 use std::thread;
+use std::time::Duration;
 
 use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
-use common::Command;
+use common::{Command, Config};
 use common::signal::Pulse;
+use pulse_protocol::{self, ClientMessage, Encoding, Envelope, PulseFilter, Role, ServerMessage,
+    ServerStatus};
 use serde::{Serialize, Deserialize};
-use serde_json;
 
+use clock;
 use mavlink_handler::{self, Telemetry};
+use session;
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PulseWithTelemetry {
+    /// The session the pulse was recorded in
+    pub session: String,
+    /// The telemetry at the time the pulse was detected
     pub telemetry: Telemetry,
     pub pulse: Pulse,
+    /// The host time the pulse was detected, in seconds since the Unix epoch. `None` for pulses
+    /// recorded before detection times were stored, in which case the telemetry is from the time
+    /// the pulse was received.
+    #[serde(default)]
+    pub time: Option<f64>,
+}
+
+/// The state of the connection to the pulse server
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PulseClientStatus {
+    pub address: String,
+    pub state: ConnectionState,
+    pub connect_count: u64,
+    pub last_error: Option<String>,
+    /// False if detection has been stopped with `stop_detection`
+    pub detecting: bool,
+    /// The status most recently reported by the pulse server while connected
+    pub server: Option<ServerStatus>,
+    /// The protocol version negotiated with the pulse server
+    pub protocol_version: Option<u32>,
+    /// The encoding chosen by the pulse server for messages after the handshake
+    pub encoding: Option<Encoding>,
+    /// The subscription that the pulse server has confirmed for this connection
+    pub subscription: Option<PulseFilter>,
+    /// The role that the pulse server has given the host. Commands are only accepted from the
+    /// controller, or from any client if there is no controller.
+    pub role: Option<Role>,
+    /// The reason the pulse server rejected the requested role, which is requested again until it
+    /// is accepted
+    pub role_error: Option<String>,
+    /// The number of messages from the pulse server that were missing from the sequence
+    pub missed_messages: u64,
 }
 
 lazy_static! {
-    pub static ref PULSE_DATA: Mutex<Vec<PulseWithTelemetry>> = Mutex::new(vec![]);
+    static ref CLIENT_STATUS: Mutex<PulseClientStatus> = Mutex::new(PulseClientStatus {
+        address: String::new(),
+        state: ConnectionState::Disconnected,
+        connect_count: 0,
+        last_error: None,
+        detecting: true,
+        server: None,
+        protocol_version: None,
+        encoding: None,
+        subscription: None,
+        role: None,
+        role_error: None,
+        missed_messages: 0,
+    });
+}
+
+/// Returns the current status of the connection to the pulse server
+pub fn get_status() -> PulseClientStatus {
+    CLIENT_STATUS.lock().unwrap().clone()
 }
 
-/// Returns the number of pulses that have occured since the specified index
-pub fn get_pulses_since(index: usize) -> Vec<PulseWithTelemetry> {
-    let pulse_data = PULSE_DATA.lock().unwrap();
+lazy_static! {
+    /// The config sent to the pulse server whenever the stream is started. If `None` the server
+    /// uses its own config.
+    static ref PULSE_CONFIG: Mutex<Option<Config>> = Mutex::new(None);
+
+    /// The config reported by the pulse server when it was last connected
+    static ref SERVER_CONFIG: Mutex<Option<Config>> = Mutex::new(None);
+
+    /// Selects the pulses that the pulse server sends to the host, sent whenever it connects
+    static ref SUBSCRIPTION: Mutex<PulseFilter> = Mutex::new(PulseFilter::default());
+
+    /// The role requested from the pulse server whenever it connects
+    static ref ROLE: Mutex<Role> = Mutex::new(Role::Controller);
+
+    /// The connection used to send messages to the pulse server
+    static ref WRITER: Mutex<Option<Writer>> = Mutex::new(None);
+}
 
-    if index < pulse_data.len() {
-        pulse_data[index..].into()
+struct Writer {
+    connection: TcpStream,
+    /// The protocol version used to encode messages
+    version: u32,
+    encoding: Encoding,
+    /// The sequence number of the next message
+    next_seq: u64,
+    buffer: Vec<u8>,
+}
+
+/// Returns the config sent to the pulse server when the stream is started
+pub fn get_config() -> Option<Config> {
+    PULSE_CONFIG.lock().unwrap().clone()
+}
+
+/// Returns the config that the pulse server is using: the config sent to the server if there is
+/// one, otherwise the server's own config. `None` if the server has not been connected yet.
+pub fn active_config() -> Option<Config> {
+    get_config().or_else(|| SERVER_CONFIG.lock().unwrap().clone())
+}
+
+/// Returns the config that changes to the pulse server's config are made to: the config in the
+/// server's latest status (including any changes made by its automatic gain control) while it is
+/// connected, otherwise the active config.
+pub fn current_config() -> Option<Config> {
+    let reported = CLIENT_STATUS.lock().unwrap().server.as_ref().map(|s| s.config.clone());
+    reported.or_else(active_config)
+}
+
+/// Sets the config sent to the pulse server, restarting the stream with the new config if the
+/// server is connected. The config is not kept if the pulse server won't accept commands from the
+/// host, so that it isn't reported as the server's config.
+pub fn set_config(config: Option<Config>) -> io::Result<()> {
+    try!(check_control());
+    *PULSE_CONFIG.lock().unwrap() = config.clone();
+    if !CLIENT_STATUS.lock().unwrap().detecting {
+        // The config is sent when detection is restarted
+        return Ok(());
+    }
+    send_command(Command::Start(config))
+}
+
+/// Stops pulse detection on the pulse server. Detection stays stopped (even if the server
+/// reconnects) until `start_detection` is called.
+pub fn stop_detection() -> io::Result<()> {
+    try!(check_control());
+    CLIENT_STATUS.lock().unwrap().detecting = false;
+    send_command(Command::Stop)
+}
+
+/// Starts (or restarts) pulse detection on the pulse server
+pub fn start_detection() -> io::Result<()> {
+    try!(check_control());
+    CLIENT_STATUS.lock().unwrap().detecting = true;
+    send_command(Command::Start(get_config()))
+}
+
+/// Returns the filter applied to the pulses sent by the pulse server
+pub fn get_subscription() -> PulseFilter {
+    SUBSCRIPTION.lock().unwrap().clone()
+}
+
+/// Changes the filter applied to the pulses sent by the pulse server. The filter is kept if the
+/// server is not connected, and is sent when it reconnects.
+pub fn set_subscription(filter: PulseFilter) -> io::Result<()> {
+    *SUBSCRIPTION.lock().unwrap() = filter.clone();
+    send_message(&ClientMessage::Subscribe(filter))
+}
+
+/// Returns an error if the pulse server won't accept commands from the host: either the host is an
+/// observer, or the pulse server rejected it as the controller
+fn check_control() -> io::Result<()> {
+    if *ROLE.lock().unwrap() == Role::Observer {
+        return Err(io::Error::new(ErrorKind::PermissionDenied,
+            "The host is an observer, so it can't send commands to the pulse server"));
+    }
+    if let Some(ref error) = CLIENT_STATUS.lock().unwrap().role_error {
+        return Err(io::Error::new(ErrorKind::PermissionDenied,
+            format!("The pulse server rejected the host as the controller: {}", error)));
+    }
+    Ok(())
+}
+
+/// Sends a command to the pulse server
+fn send_command(command: Command) -> io::Result<()> {
+    try!(check_control());
+    send_message(&ClientMessage::Command(command))
+}
+
+fn send_message(message: &ClientMessage) -> io::Result<()> {
+    let mut writer = WRITER.lock().unwrap();
+    match *writer {
+        Some(ref mut writer) => {
+            let envelope = Envelope::new(writer.version, writer.next_seq, message);
+            writer.next_seq += 1;
+            write_message(&mut writer.connection, &mut writer.buffer, writer.encoding, &envelope)
+        },
+        None => Err(io::Error::new(ErrorKind::NotConnected, "Not connected to the pulse server")),
+    }
+}
+
+/// True while a `SetRole` is waiting for a response. The pulse server answers messages in order,
+/// so the next `Role` or `Error` is the response.
+static ROLE_PENDING: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Requests the role in `ROLE` from the pulse server
+fn request_role() -> io::Result<()> {
+    ROLE_PENDING.store(true, Ordering::SeqCst);
+    let result = send_message(&ClientMessage::SetRole(*ROLE.lock().unwrap()));
+    if result.is_err() {
+        ROLE_PENDING.store(false, Ordering::SeqCst);
+    }
+    result
+}
+
+/// Requests the role again if the pulse server rejected it (e.g. because another client was the
+/// controller)
+fn retry_role() -> io::Result<()> {
+    let rejected = CLIENT_STATUS.lock().unwrap().role_error.is_some();
+    if rejected && !ROLE_PENDING.load(Ordering::SeqCst) {
+        return request_role();
+    }
+    Ok(())
+}
+
+/// Starts or stops detection on the pulse server, depending on whether detection has been stopped
+/// with `stop_detection`
+fn send_detection_command() -> io::Result<()> {
+    if CLIENT_STATUS.lock().unwrap().detecting {
+        send_command(Command::Start(get_config()))
     }
     else {
-        vec![]
+        send_command(Command::Stop)
+    }
+}
+
+/// How often the pulse server's clock and status are requested while connected (and a rejected
+/// role is requested again)
+const POLL_INTERVAL_MS: u64 = 5_000;
+
+static NEXT_TIME_REQUEST_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Sends a request for the pulse server's time
+fn send_time_request() -> io::Result<()> {
+    send_message(&ClientMessage::TimeRequest {
+        id: NEXT_TIME_REQUEST_ID.fetch_add(1, Ordering::SeqCst) as u64,
+        client_time: clock::now(),
+    })
+}
+
+/// Periodically samples the pulse server's clock, so that the offset between the clocks is known
+/// even when no pulses are being received, and requests the server's status.
+fn poll_server() {
+    loop {
+        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        // Failures are expected while the server is disconnected
+        let _ = send_time_request()
+            .and_then(|_| send_message(&ClientMessage::GetStatus))
+            .and_then(|_| retry_role());
+    }
+}
+
+fn set_state(state: ConnectionState, error: Option<String>) {
+    let mut status = CLIENT_STATUS.lock().unwrap();
+    if state == ConnectionState::Connected && status.state != ConnectionState::Connected {
+        status.connect_count += 1;
+    }
+    status.state = state;
+    if state != ConnectionState::Connected {
+        status.server = None;
+        status.protocol_version = None;
+        status.encoding = None;
+        status.subscription = None;
+        status.role = None;
+        status.role_error = None;
+        ROLE_PENDING.store(false, Ordering::SeqCst);
+    }
+    if error.is_some() {
+        status.last_error = error;
     }
 }
 
 pub struct PulseHandle {}
 
 impl PulseHandle {
-    pub fn new() -> PulseHandle {
-        thread::spawn(|| run_pulse_client());
+    pub fn new(address: String, encoding: Encoding, filter: PulseFilter, role: Role)
+        -> PulseHandle
+    {
+        CLIENT_STATUS.lock().unwrap().address = address.clone();
+        *SUBSCRIPTION.lock().unwrap() = filter;
+        *ROLE.lock().unwrap() = role;
+        thread::spawn(move || run_pulse_client(&address, encoding));
+        thread::spawn(poll_server);
         PulseHandle {}
     }
 }
 
-fn read_json<R: Read, T: Deserialize>(reader: &mut R, buffer: &mut Vec<u8>) -> T {
-    let size = reader.read_u64::<LittleEndian>().unwrap() as usize;
+/// The maximum size of a message that will be accepted from the pulse server
+const MAX_MESSAGE_SIZE: usize = 1 << 20;
+
+/// The time to wait for the pulse server to respond to `Hello`
+const HANDSHAKE_TIMEOUT_SECS: u64 = 5;
+
+/// The number of heartbeat intervals without any messages before the connection is assumed to be
+/// lost
+const MISSED_HEARTBEATS: u64 = 3;
+
+/// The time without any messages from the pulse server before the connection is assumed to be lost
+const READ_TIMEOUT_SECS: u64 = pulse_protocol::HEARTBEAT_INTERVAL_SECS * MISSED_HEARTBEATS;
+
+/// The time to wait for the pulse server to accept a message, so that a lost connection can't
+/// block the threads sending messages
+const WRITE_TIMEOUT_SECS: u64 = 5;
+
+const MIN_RECONNECT_DELAY_MS: u64 = 500;
+const MAX_RECONNECT_DELAY_MS: u64 = 30_000;
+
+fn read_message<R: Read, T: Deserialize>(reader: &mut R, buffer: &mut Vec<u8>,
+    encoding: Encoding) -> io::Result<T>
+{
+    let size = try!(reader.read_u64::<LittleEndian>()) as usize;
+    if size > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(ErrorKind::InvalidData,
+            format!("Message too large ({} bytes)", size)));
+    }
 
     buffer.clear();
     buffer.resize(size, 0);
 
-    reader.read_exact(buffer).unwrap();
-    serde_json::from_slice(buffer).unwrap()
+    try!(reader.read_exact(buffer));
+    pulse_protocol::decode(encoding, buffer).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
 }
 
-fn write_json<W: Write, T: Serialize>(writer: &mut W, buffer: &mut Vec<u8>, value: &T) {
+fn write_message<W: Write, T: Serialize>(writer: &mut W, buffer: &mut Vec<u8>,
+    encoding: Encoding, value: &T) -> io::Result<()>
+{
     buffer.clear();
-    serde_json::to_writer(buffer, value).unwrap();
-    writer.write_u64::<LittleEndian>(buffer.len() as u64).unwrap();
-    writer.write(buffer).unwrap();
+    try!(pulse_protocol::encode(encoding, value, buffer)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e)));
+    try!(writer.write_u64::<LittleEndian>(buffer.len() as u64));
+    writer.write_all(buffer)
 }
 
-fn run_pulse_client() {
-    let mut buffer = vec![];
-    let mut connection = TcpStream::connect("127.0.0.1:11000")
-        .expect("Failed to connect to Pulse Stream");
+/// Keeps a connection open to the pulse server, reconnecting with an exponential backoff whenever
+/// the connection fails.
+fn run_pulse_client(address: &str, encoding: Encoding) {
+    let mut delay_ms = MIN_RECONNECT_DELAY_MS;
 
-    write_json(&mut connection, &mut buffer, &Command::Start(None));
     loop {
-        let pulse: Pulse = read_json(&mut connection, &mut buffer);
-        println!("{:?}", pulse);
+        set_state(ConnectionState::Connecting, None);
+
+        let error = match TcpStream::connect(address) {
+            Ok(connection) => {
+                println!("Connected to pulse server at {}", address);
+                delay_ms = MIN_RECONNECT_DELAY_MS;
+                set_state(ConnectionState::Connected, None);
 
-        let value = PulseWithTelemetry {
-            pulse: pulse,
-            telemetry: mavlink_handler::get_telemetry()
+                let result = set_timeouts(&connection)
+                    .and_then(|_| read_pulses(connection, encoding));
+
+                match result {
+                    Ok(()) => "Connection closed by pulse server".into(),
+                    Err(e) => format!("Connection to pulse server lost: {}", e),
+                }
+            },
+            Err(e) => format!("Failed to connect to pulse server: {}", e),
         };
 
-        PULSE_DATA.lock().unwrap().push(value);
+        println!("{} (retrying in {} ms)", error, delay_ms);
+        set_state(ConnectionState::Disconnected, Some(error));
+
+        thread::sleep(Duration::from_millis(delay_ms));
+        delay_ms = cmp::min(delay_ms * 2, MAX_RECONNECT_DELAY_MS);
     }
 }
+
+/// Sets the timeouts of a new connection, without which a half-open connection would appear to be
+/// connected forever
+fn set_timeouts(connection: &TcpStream) -> io::Result<()> {
+    try!(connection.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECS))));
+    connection.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_SECS)))
+}
+
+/// Returns true if the error is from a read timing out (which is reported differently depending on
+/// the platform)
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut
+}
+
+/// The result of the protocol handshake
+struct Handshake {
+    version: u32,
+    encoding: Encoding,
+    /// The sequence number of the next message expected from the server
+    next_seq: u64,
+}
+
+/// Negotiates the protocol version and encoding with the pulse server. `Hello` is written directly
+/// to the connection (as the first message, always encoded as json), since nothing else can be
+/// sent until the server has responded.
+fn handshake(connection: &mut TcpStream, buffer: &mut Vec<u8>, encoding: Encoding)
+    -> io::Result<Handshake>
+{
+    let mut encodings = vec![encoding];
+    if encoding != Encoding::Json {
+        encodings.push(Encoding::Json);
+    }
+
+    let hello = ClientMessage::Hello {
+        min_version: pulse_protocol::MIN_PROTOCOL_VERSION,
+        max_version: pulse_protocol::PROTOCOL_VERSION,
+        encodings: encodings,
+    };
+    let envelope = Envelope::new(pulse_protocol::PROTOCOL_VERSION, 0, &hello);
+    try!(write_message(connection, buffer, Encoding::Json, &envelope));
+
+    try!(connection.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS))));
+    let envelope: Envelope<ServerMessage> = match read_message(connection, buffer, Encoding::Json) {
+        Ok(envelope) => envelope,
+        Err(ref e) if is_timeout(e) => {
+            return Err(io::Error::new(ErrorKind::TimedOut, "No response to the protocol \
+                handshake, the pulse server may need to be updated"));
+        },
+        Err(e) => return Err(e),
+    };
+
+    match envelope.message {
+        ServerMessage::Welcome { version, encoding } => {
+            {
+                let mut status = CLIENT_STATUS.lock().unwrap();
+                status.protocol_version = Some(version);
+                status.encoding = Some(encoding);
+            }
+            println!("Using pulse server protocol version {} ({:?})", version, encoding);
+            Ok(Handshake { version: version, encoding: encoding, next_seq: envelope.seq + 1 })
+        },
+        ServerMessage::Error { message } => {
+            Err(io::Error::new(ErrorKind::InvalidData, format!("Handshake failed: {}", message)))
+        },
+        message => {
+            Err(io::Error::new(ErrorKind::InvalidData,
+                format!("Unexpected message during handshake: {:?}", message)))
+        },
+    }
+}
+
+/// Starts the pulse stream and reads messages from the connection until it is closed.
+fn read_pulses(mut connection: TcpStream, encoding: Encoding) -> io::Result<()> {
+    let mut buffer = vec![];
+    let handshake = try!(handshake(&mut connection, &mut buffer, encoding));
+
+    // Other threads can only send messages once the handshake is complete, so that nothing is
+    // sent before `Hello` or in the wrong encoding
+    *WRITER.lock().unwrap() = Some(Writer {
+        connection: try!(connection.try_clone()),
+        version: handshake.version,
+        encoding: handshake.encoding,
+        next_seq: 1,
+        buffer: vec![],
+    });
+
+    let result = read_messages(&mut connection, &mut buffer, &handshake);
+    *WRITER.lock().unwrap() = None;
+
+    result
+}
+
+/// Sets up the stream after the handshake, then handles messages from the pulse server until the
+/// connection is closed
+fn read_messages(connection: &mut TcpStream, buffer: &mut Vec<u8>, handshake: &Handshake)
+    -> io::Result<()>
+{
+    let encoding = handshake.encoding;
+    let mut expected_seq = handshake.next_seq;
+
+    // The server sends heartbeats, so a long silence means that the connection has been lost
+    let timeout = READ_TIMEOUT_SECS;
+    try!(connection.set_read_timeout(Some(Duration::from_secs(timeout))));
+
+    // The role is requested before any commands are sent, so that they aren't rejected
+    let role = *ROLE.lock().unwrap();
+    try!(request_role());
+    try!(send_message(&ClientMessage::GetConfig));
+    let filter = get_subscription();
+    if filter != PulseFilter::default() {
+        try!(send_message(&ClientMessage::Subscribe(filter)));
+    }
+    // Observers leave detection to the controller
+    if role != Role::Observer {
+        try!(send_detection_command());
+    }
+    try!(send_time_request());
+    try!(send_message(&ClientMessage::GetStatus));
+    loop {
+        let result = read_message(connection, buffer, encoding);
+        let envelope: Envelope<ServerMessage> = match result {
+            Ok(envelope) => envelope,
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(ref e) if is_timeout(e) => {
+                return Err(io::Error::new(ErrorKind::TimedOut,
+                    format!("No messages received for {} seconds", timeout)));
+            },
+            Err(e) => return Err(e),
+        };
+
+        let receive_time = clock::now();
+        if envelope.seq > expected_seq {
+            let missed = envelope.seq - expected_seq;
+            println!("Missed {} messages from the pulse server", missed);
+            CLIENT_STATUS.lock().unwrap().missed_messages += missed;
+        }
+        expected_seq = envelope.seq + 1;
+
+        match envelope.message {
+            ServerMessage::Pulse(pulse) => handle_pulse(pulse, receive_time),
+            ServerMessage::TimeResponse { client_time, receive_time: server_receive_time,
+                transmit_time, .. } =>
+            {
+                clock::pulse_server_time_sync(client_time, server_receive_time, transmit_time,
+                    receive_time);
+            },
+            ServerMessage::Config(config) => *SERVER_CONFIG.lock().unwrap() = Some(config),
+            ServerMessage::Status(status) => {
+                *SERVER_CONFIG.lock().unwrap() = Some(status.config.clone());
+                CLIENT_STATUS.lock().unwrap().server = Some(status);
+            },
+            ServerMessage::Subscribed(filter) => {
+                CLIENT_STATUS.lock().unwrap().subscription = Some(filter);
+            },
+            ServerMessage::Role(role) => {
+                println!("Pulse server role: {:?}", role);
+                ROLE_PENDING.store(false, Ordering::SeqCst);
+                let retried = {
+                    let mut status = CLIENT_STATUS.lock().unwrap();
+                    status.role = Some(role);
+                    status.role_error.take().is_some()
+                };
+                if retried && role == Role::Controller {
+                    // The detection command sent after the rejection was rejected too
+                    try!(send_detection_command());
+                }
+            },
+            ServerMessage::Error { message } => {
+                println!("Error from pulse server: {}", message);
+                if ROLE_PENDING.swap(false, Ordering::SeqCst) {
+                    CLIENT_STATUS.lock().unwrap().role_error = Some(message);
+                }
+            },
+            ServerMessage::Welcome { .. } | ServerMessage::Heartbeat => {},
+        }
+    }
+}
+
+fn handle_pulse(pulse: Pulse, receive_time: f64) {
+    println!("{:?}", pulse);
+
+    // Look up the telemetry at the time the pulse was detected rather than when it was received,
+    // since the vehicle may have moved a long way in between.
+    let time = clock::observe_pulse(receive_time, clock::pulse_time(&pulse));
+    let telemetry = mavlink_handler::get_telemetry_at(time);
+    session::record_pulse(pulse, telemetry, Some(time));
+}
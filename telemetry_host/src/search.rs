@@ -0,0 +1,205 @@
+//! Execution of planned searches, either by uploading the waypoints as a mission or by sending a
+//! sequence of DO_REPOSITION commands.
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use commands::{self, CommandStatus};
+use mavlink_handler::{self, Location};
+use mission::{self, MissionItem};
+use planner::{self, SearchArea};
+use vehicle::{self, ModeChange};
+
+/// The distance (in meters) from a waypoint at which the next waypoint is sent
+const ACCEPTANCE_RADIUS: f32 = 3.0;
+
+/// The difference in altitude (in meters) from a waypoint at which the next waypoint is sent
+const ACCEPTANCE_ALT: f32 = 2.0;
+
+const SEQUENCE_CHECK_INTERVAL_MS: u64 = 200;
+
+/// The slowest ground speed (in meters per second) expected between waypoints, used to work out
+/// how long to wait for the vehicle to reach each waypoint
+const MIN_GROUND_SPEED: f32 = 1.0;
+
+/// Time allowed to reach a waypoint on top of the time to fly there at `MIN_GROUND_SPEED`
+const WAYPOINT_TIMEOUT_MARGIN_SECS: f32 = 30.0;
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ExecutionMode {
+    /// Upload the waypoints as a mission and switch to AUTO.MISSION
+    Mission,
+    /// Send each waypoint as a DO_REPOSITION command once the previous one has been reached
+    Reposition,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SearchExecution {
+    pub area: SearchArea,
+    pub mode: ExecutionMode,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchStatus {
+    pub active: bool,
+    pub mode: Option<ExecutionMode>,
+    /// The index of the waypoint currently being flown to (for reposition searches)
+    pub current: usize,
+    pub waypoints: Vec<Location>,
+    pub error: Option<String>,
+}
+
+struct SearchState {
+    /// Incremented whenever a search is started or cancelled, so that a running sequence can tell
+    /// that it has been replaced.
+    generation: u64,
+    status: SearchStatus,
+}
+
+lazy_static! {
+    static ref SEARCH: Mutex<SearchState> = Mutex::new(SearchState {
+        generation: 0,
+        status: SearchStatus {
+            active: false,
+            mode: None,
+            current: 0,
+            waypoints: vec![],
+            error: None,
+        },
+    });
+}
+
+pub fn get_status() -> SearchStatus {
+    SEARCH.lock().unwrap().status.clone()
+}
+
+/// Plans and starts a search, replacing any search that is already running
+pub fn execute(execution: SearchExecution) -> Result<SearchStatus, String> {
+    let waypoints = try!(planner::plan(&execution.area));
+    let generation = {
+        let mut search = SEARCH.lock().unwrap();
+        search.generation += 1;
+        search.status = SearchStatus {
+            active: execution.mode == ExecutionMode::Reposition,
+            mode: Some(execution.mode),
+            current: 0,
+            waypoints: waypoints.clone(),
+            error: None,
+        };
+        search.generation
+    };
+
+    match execution.mode {
+        ExecutionMode::Mission => {
+            let items = waypoints.iter().map(|waypoint| MissionItem {
+                x: waypoint.x,
+                y: waypoint.y,
+                alt: waypoint.alt,
+                command: mission::MAV_CMD_NAV_WAYPOINT,
+                frame: mission::MAV_FRAME_GLOBAL_INT,
+                params: [0.0; 4],
+            }).collect();
+
+            try!(mission::upload_mission(items));
+            try!(vehicle::set_mode(ModeChange { mode: "AUTO.MISSION".into() }));
+        },
+
+        ExecutionMode::Reposition => {
+            thread::spawn(move || run_sequence(generation, waypoints));
+        },
+    }
+
+    Ok(get_status())
+}
+
+/// Stops any reposition search that is in progress. The vehicle holds its current target.
+pub fn cancel() -> SearchStatus {
+    let mut search = SEARCH.lock().unwrap();
+    search.generation += 1;
+    search.status.active = false;
+    search.status.clone()
+}
+
+/// Updates the status of the search, returning false if the search has been replaced
+fn update<F: FnOnce(&mut SearchStatus)>(generation: u64, f: F) -> bool {
+    let mut search = SEARCH.lock().unwrap();
+    if search.generation != generation {
+        return false;
+    }
+
+    f(&mut search.status);
+    true
+}
+
+/// The horizontal distance from the vehicle to the target
+fn distance(position: [f32; 3], target: &Location) -> f32 {
+    let dx = position[0] - target.x;
+    let dy = position[1] - target.y;
+    (dx * dx + dy * dy).sqrt()
+}
+
+fn reached(target: &Location) -> bool {
+    let position = mavlink_handler::get_telemetry().position;
+    distance(position, target) <= ACCEPTANCE_RADIUS &&
+        (position[2] - target.alt).abs() <= ACCEPTANCE_ALT
+}
+
+/// Waits for the vehicle to reach the target of a reposition command. Returns `Ok(false)` if the
+/// search is replaced while waiting, or an error if the command fails or the waypoint is not
+/// reached in time.
+fn wait_for_waypoint(generation: u64, command_id: u64, target: &Location) -> Result<bool, String> {
+    let position = mavlink_handler::get_telemetry().position;
+    let timeout = WAYPOINT_TIMEOUT_MARGIN_SECS + distance(position, target) / MIN_GROUND_SPEED;
+    let deadline = Instant::now() + Duration::from_millis((timeout * 1000.0) as u64);
+
+    while !reached(target) {
+        match commands::get(command_id).map(|command| command.status) {
+            Some(CommandStatus::Pending) | Some(CommandStatus::InProgress) |
+            Some(CommandStatus::Accepted) | None => {},
+            Some(status) => return Err(format!("The reposition command failed: {:?}", status)),
+        }
+
+        if Instant::now() >= deadline {
+            return Err(format!("The waypoint was not reached within {:.0} s", timeout));
+        }
+
+        thread::sleep(Duration::from_millis(SEQUENCE_CHECK_INTERVAL_MS));
+        if !update(generation, |_| {}) {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Flies to each waypoint in turn, sending the next waypoint once the vehicle is within the
+/// acceptance radius of the current one. The search stops with an error if a reposition command
+/// is not accepted, or if a waypoint isn't reached in time.
+fn run_sequence(generation: u64, waypoints: Vec<Location>) {
+    for (i, waypoint) in waypoints.into_iter().enumerate() {
+        if !update(generation, |status| status.current = i) {
+            return;
+        }
+
+        // The target may have been clamped to the geofence, so wait for the vehicle to reach the
+        // target that was actually sent.
+        let result = mavlink_handler::do_reposition(waypoint).and_then(|reposition| {
+            wait_for_waypoint(generation, reposition.command.id, &reposition.target)
+        });
+
+        match result {
+            Ok(true) => {},
+            Ok(false) => return,
+            Err(e) => {
+                update(generation, |status| {
+                    status.active = false;
+                    status.error = Some(format!("Waypoint {}: {}", i, e));
+                });
+                return;
+            },
+        }
+    }
+
+    update(generation, |status| status.active = false);
+}
@@ -0,0 +1,763 @@
+//! Recording sessions (e.g. one per flight or survey).
+//!
+//! Each session is stored in its own directory under `<storage_path>/sessions`, containing the
+//! pulses received during the session, a track of the vehicle's telemetry, and a `session.json`
+//! file describing the session. Only one session records at a time, but any session can be queried
+//! while another one is recording.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use common::Config;
+use common::signal::Pulse;
+use serde::Deserialize;
+use serde_json;
+
+use mavlink_handler::{self, Origin, Telemetry};
+use pulse_handler::{self, PulseWithTelemetry};
+use storage::{self, RecordLog};
+use stream;
+
+/// The maximum number of records returned by a single query
+pub const MAX_RECORDS_PER_REQUEST: usize = 1000;
+
+/// The number of recent pulses kept in memory, so that clients polling for new pulses do not need
+/// to read from disk.
+const RECENT_PULSES: usize = 256;
+
+/// The interval that the vehicle's telemetry is recorded at
+const TRACK_INTERVAL_MS: u64 = 1000;
+
+const SESSION_DIR: &'static str = "sessions";
+const INFO_FILE: &'static str = "session.json";
+const PULSE_LOG: &'static str = "pulses";
+const TRACK_LOG: &'static str = "track";
+
+/// The maximum length of a session name
+const MAX_NAME_LENGTH: usize = 64;
+
+/// The default distance (in Hz) from the queried frequency that a pulse can be and still match
+const DEFAULT_FREQ_TOLERANCE: f32 = 1000.0;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub name: String,
+    /// The time the session was started, in seconds since the Unix epoch
+    pub started: u64,
+    /// The time the session was stopped, `None` if the session is still recording (or the telemetry
+    /// host exited while it was recording)
+    pub stopped: Option<u64>,
+    /// The origin of the local frame that the session's positions are relative to
+    pub origin: Option<Origin>,
+    /// The config sent to the pulse server for the session, `None` if the server's own config was
+    /// used
+    pub pulse_config: Option<Config>,
+    #[serde(default)]
+    pub pulse_count: u64,
+    #[serde(default)]
+    pub track_count: u64,
+}
+
+/// A request to start a new session
+#[derive(Clone, Deserialize)]
+pub struct SessionStart {
+    /// The name of the session (default: `session-<time>`)
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The config to send to the pulse server, if not set then the current config is kept
+    #[serde(default)]
+    pub pulse_config: Option<Config>,
+}
+
+/// The telemetry of the vehicle at a point in time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackPoint {
+    /// Seconds since the Unix epoch
+    pub time: f64,
+    pub telemetry: Telemetry,
+}
+
+/// Filters and pagination for querying the pulses of a session
+#[derive(Debug, Clone, FromForm)]
+pub struct PulseQuery {
+    /// The session to query (default: the active session)
+    pub session: Option<String>,
+    /// Only include pulses detected at or after this time (seconds since the Unix epoch)
+    pub start: Option<f64>,
+    /// Only include pulses detected before this time (seconds since the Unix epoch)
+    pub end: Option<f64>,
+    /// Only include pulses from the target with this frequency (in Hz)
+    pub freq: Option<f32>,
+    /// The maximum distance (in Hz) from `freq` (default: `DEFAULT_FREQ_TOLERANCE`)
+    pub freq_tolerance: Option<f32>,
+    pub min_signal_strength: Option<f32>,
+    /// The maximum number of pulses to return (default and maximum: `MAX_RECORDS_PER_REQUEST`)
+    pub limit: Option<usize>,
+    /// The index in the session's pulse log to continue from, from the `next_cursor` of the
+    /// previous response
+    pub cursor: Option<u64>,
+}
+
+impl PulseQuery {
+    pub fn validate(&self) -> Result<(), String> {
+        let times = [self.start, self.end];
+        if times.iter().filter_map(|&x| x).any(|x| !x.is_finite()) {
+            return Err("Invalid time range".into());
+        }
+        if let (Some(start), Some(end)) = (self.start, self.end) {
+            if start > end {
+                return Err(format!("Start time `{}` is after the end time `{}`", start, end));
+            }
+        }
+
+        let values = [self.freq, self.freq_tolerance, self.min_signal_strength];
+        if values.iter().filter_map(|&x| x).any(|x| !x.is_finite()) {
+            return Err("Invalid frequency or signal strength".into());
+        }
+        if self.freq_tolerance.map_or(false, |x| x < 0.0) {
+            return Err("Frequency tolerance must not be negative".into());
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if the query excludes any pulses
+    fn is_filtered(&self) -> bool {
+        self.start.is_some() || self.end.is_some() || self.freq.is_some() ||
+            self.min_signal_strength.is_some()
+    }
+
+    fn matches(&self, pulse: &PulseWithTelemetry) -> bool {
+        if self.start.is_some() || self.end.is_some() {
+            // Pulses recorded without a detection time use the time of the attached telemetry
+            let time = match pulse.time.or(pulse.telemetry.time) {
+                Some(time) => time,
+                None => return false,
+            };
+            if self.start.map_or(false, |start| time < start) ||
+                self.end.map_or(false, |end| time >= end)
+            {
+                return false;
+            }
+        }
+
+        if let Some(freq) = self.freq {
+            let tolerance = self.freq_tolerance.unwrap_or(DEFAULT_FREQ_TOLERANCE);
+            if (pulse.pulse.freq - freq).abs() > tolerance {
+                return false;
+            }
+        }
+
+        self.min_signal_strength.map_or(true, |min| pulse.pulse.signal_strength >= min)
+    }
+}
+
+/// A page of the pulses matching a `PulseQuery`
+#[derive(Debug, Clone, Serialize)]
+pub struct PulsePage {
+    pub session: String,
+    pub pulses: Vec<PulseWithTelemetry>,
+    /// The cursor to request the next page from. Pulses recorded after this response will be
+    /// returned from this cursor, so clients polling for new pulses should keep using it even when
+    /// the page is empty.
+    pub next_cursor: u64,
+    /// The total number of pulses in the session that match the filters
+    pub total: u64,
+}
+
+struct ActiveSession {
+    info: SessionInfo,
+    dir: PathBuf,
+    pulses: RecordLog,
+    track: RecordLog,
+    recent: VecDeque<PulseWithTelemetry>,
+}
+
+impl ActiveSession {
+    fn save_info(&mut self) {
+        self.info.pulse_count = self.pulses.len();
+        self.info.track_count = self.track.len();
+        if let Err(e) = write_info(&self.dir, &self.info) {
+            println!("Failed to save session `{}`: {}", self.info.name, e);
+        }
+    }
+
+    fn pulses_since(&mut self, index: u64) -> Result<Vec<PulseWithTelemetry>, String> {
+        let len = self.pulses.len();
+        let cached_start = len - self.recent.len() as u64;
+
+        if index >= cached_start {
+            let skip = (index - cached_start) as usize;
+            let pulses = self.recent.iter().skip(skip).take(MAX_RECORDS_PER_REQUEST);
+            return Ok(pulses.cloned().collect());
+        }
+
+        self.pulses.read(index, MAX_RECORDS_PER_REQUEST)
+            .map_err(|e| format!("Failed to read pulses: {}", e))
+    }
+}
+
+struct SessionStore {
+    root: PathBuf,
+    active: Option<ActiveSession>,
+}
+
+lazy_static! {
+    static ref SESSIONS: Mutex<SessionStore> = Mutex::new(SessionStore {
+        root: PathBuf::new(),
+        active: None,
+    });
+}
+
+fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0))
+}
+
+/// Sets the directory that sessions are stored in, and starts a new session for the data recorded
+/// from now on.
+pub fn init(storage_path: &str) -> Result<SessionInfo, String> {
+    let root = Path::new(storage_path).join(SESSION_DIR);
+    try!(fs::create_dir_all(&root)
+        .map_err(|e| format!("Failed to create `{}`: {}", root.display(), e)));
+
+    SESSIONS.lock().unwrap().root = root;
+    thread::spawn(|| track_recorder());
+
+    start(SessionStart { name: None, pulse_config: None })
+}
+
+/// Starts recording a new session, stopping the current session
+pub fn start(request: SessionStart) -> Result<SessionInfo, String> {
+    let started = now().as_secs();
+    let name = request.name.unwrap_or_else(|| format!("session-{}", started));
+    try!(validate_name(&name));
+
+    // The directory is created while holding the lock, so that two sessions can't be started with
+    // the same name
+    let (dir, pulses, track) = {
+        let sessions = SESSIONS.lock().unwrap();
+        let dir = sessions.root.join(&name);
+        if dir.exists() {
+            return Err(format!("Session `{}` already exists", name));
+        }
+
+        try!(fs::create_dir_all(&dir).map_err(|e| format!("Failed to create session: {}", e)));
+        let pulses = try!(RecordLog::open(&dir, PULSE_LOG)
+            .map_err(|e| format!("Failed to create pulse log: {}", e)));
+        let track = try!(RecordLog::open(&dir, TRACK_LOG)
+            .map_err(|e| format!("Failed to create track log: {}", e)));
+        (dir, pulses, track)
+    };
+
+    // Sending the config blocks on the pulse server connection, so it is done without holding the
+    // lock. The new config is kept even if it can't be sent yet, since it is sent to the pulse
+    // server whenever the stream is (re)started.
+    if let Some(ref config) = request.pulse_config {
+        if let Err(e) = pulse_handler::set_config(Some(config.clone())) {
+            println!("Failed to send config to the pulse server: {}", e);
+        }
+    }
+
+    let mut session = ActiveSession {
+        info: SessionInfo {
+            name: name,
+            started: started,
+            stopped: None,
+            origin: mavlink_handler::get_origin(),
+            pulse_config: pulse_handler::get_config(),
+            pulse_count: 0,
+            track_count: 0,
+        },
+        dir: dir,
+        pulses: pulses,
+        track: track,
+        recent: VecDeque::new(),
+    };
+    session.save_info();
+
+    let mut sessions = SESSIONS.lock().unwrap();
+    if let Some(mut previous) = sessions.active.take() {
+        previous.info.stopped = Some(started);
+        previous.save_info();
+    }
+
+    println!("Started session `{}`", session.info.name);
+    let info = session.info.clone();
+    sessions.active = Some(session);
+    Ok(info)
+}
+
+/// Stops recording the current session. Pulses are not recorded until a new session is started.
+pub fn stop() -> Result<SessionInfo, String> {
+    let mut sessions = SESSIONS.lock().unwrap();
+    match sessions.active.take() {
+        Some(mut session) => {
+            session.info.stopped = Some(now().as_secs());
+            session.save_info();
+            println!("Stopped session `{}`", session.info.name);
+            Ok(session.info)
+        },
+        None => Err("No session is recording".into()),
+    }
+}
+
+/// Records a change to the pulse server's config in the active session
+pub fn set_pulse_config(config: Option<Config>) {
+    if let Some(ref mut session) = SESSIONS.lock().unwrap().active {
+        session.info.pulse_config = config;
+        session.save_info();
+    }
+}
+
+/// Returns the session that is currently recording
+pub fn active() -> Option<SessionInfo> {
+    SESSIONS.lock().unwrap().active.as_mut().map(|session| {
+        session.info.pulse_count = session.pulses.len();
+        session.info.track_count = session.track.len();
+        session.info.clone()
+    })
+}
+
+/// Returns all of the recorded sessions, oldest first
+pub fn list() -> Result<Vec<SessionInfo>, String> {
+    let root = SESSIONS.lock().unwrap().root.clone();
+    let entries = try!(fs::read_dir(&root)
+        .map_err(|e| format!("Failed to read `{}`: {}", root.display(), e)));
+
+    let mut sessions = vec![];
+    for entry in entries {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(e) => return Err(format!("Failed to read `{}`: {}", root.display(), e)),
+        };
+
+        match read_info(&path) {
+            Ok(info) => sessions.push(info),
+            Err(e) => println!("Skipping invalid session `{}`: {}", path.display(), e),
+        }
+    }
+
+    sessions.sort_by(|a, b| (a.started, &a.name).cmp(&(b.started, &b.name)));
+    Ok(sessions)
+}
+
+/// Returns the details of a session
+pub fn get(name: &str) -> Result<SessionInfo, String> {
+    if let Some(info) = active() {
+        if info.name == name {
+            return Ok(info);
+        }
+    }
+
+    try!(validate_name(name));
+    let dir = SESSIONS.lock().unwrap().root.join(name);
+    read_info(&dir).map_err(|_| format!("Unknown session `{}`", name))
+}
+
+/// Returns the pulses recorded in a session since the specified index, up to
+/// `MAX_RECORDS_PER_REQUEST` at a time.
+pub fn get_pulses(name: &str, index: u64) -> Result<Vec<PulseWithTelemetry>, String> {
+    let dir = {
+        let mut sessions = SESSIONS.lock().unwrap();
+        if let Some(ref mut session) = sessions.active {
+            if session.info.name == name {
+                return session.pulses_since(index);
+            }
+        }
+        sessions.root.join(name)
+    };
+
+    read_log(name, &dir, PULSE_LOG, index)
+}
+
+/// Returns the track recorded in a session since the specified index, up to
+/// `MAX_RECORDS_PER_REQUEST` points at a time.
+pub fn get_track(name: &str, index: u64) -> Result<Vec<TrackPoint>, String> {
+    let dir = {
+        let mut sessions = SESSIONS.lock().unwrap();
+        if let Some(ref mut session) = sessions.active {
+            if session.info.name == name {
+                return session.track.read(index, MAX_RECORDS_PER_REQUEST)
+                    .map_err(|e| format!("Failed to read track: {}", e));
+            }
+        }
+        sessions.root.join(name)
+    };
+
+    read_log(name, &dir, TRACK_LOG, index)
+}
+
+/// Returns the pulses recorded in the active session since the specified index
+pub fn get_active_pulses(index: u64) -> Vec<PulseWithTelemetry> {
+    let mut sessions = SESSIONS.lock().unwrap();
+    let result = match sessions.active {
+        Some(ref mut session) => session.pulses_since(index),
+        None => Ok(vec![]),
+    };
+
+    result.unwrap_or_else(|e| {
+        println!("{}", e);
+        vec![]
+    })
+}
+
+/// Returns the page of pulses matching the query.
+///
+/// The log is always read through a separate read only handle without holding the session lock,
+/// so that a query of the active session doesn't hold up recording. Queries of the active session
+/// only include the pulses that had been recorded when the query started.
+pub fn query_pulses(query: &PulseQuery) -> Result<PulsePage, String> {
+    let (name, dir, recorded) = {
+        let sessions = SESSIONS.lock().unwrap();
+        let name = match (query.session.clone(), sessions.active.as_ref()) {
+            (Some(name), _) => name,
+            (None, Some(session)) => session.info.name.clone(),
+            (None, None) => return Err("No session is recording".into()),
+        };
+
+        let recorded = match sessions.active {
+            Some(ref session) if session.info.name == name => Some(session.pulses.len()),
+            _ => None,
+        };
+        let dir = sessions.root.join(&name);
+        (name, dir, recorded)
+    };
+
+    let mut log = try!(open_log(&name, &dir, PULSE_LOG));
+    let len = recorded.unwrap_or(log.len());
+    query_log(&mut log, len, name, query)
+}
+
+/// Reads the page of pulses matching the query from the first `len` records of a pulse log.
+/// Finding the total number of matching pulses requires reading the whole log when the query has
+/// any filters.
+fn query_log(log: &mut RecordLog, len: u64, session: String, query: &PulseQuery)
+    -> Result<PulsePage, String>
+{
+    let cursor = query.cursor.unwrap_or(0);
+    let limit = cmp::min(query.limit.unwrap_or(MAX_RECORDS_PER_REQUEST), MAX_RECORDS_PER_REQUEST);
+    let filtered = query.is_filtered();
+
+    let mut page = PulsePage {
+        session: session,
+        pulses: vec![],
+        next_cursor: cursor,
+        total: 0,
+    };
+    let mut full = limit == 0;
+
+    let mut index = if filtered { 0 } else { cursor };
+    while index < len && !(full && !filtered) {
+        let count = cmp::min(len - index, MAX_RECORDS_PER_REQUEST as u64) as usize;
+        let pulses: Vec<PulseWithTelemetry> = try!(log.read(index, count)
+            .map_err(|e| format!("Failed to read pulses: {}", e)));
+        if pulses.is_empty() {
+            // Stopping here would skip the pulses that couldn't be read
+            return Err(format!("Failed to read pulses: the log ended at {} of {} pulses", index,
+                len));
+        }
+
+        for pulse in pulses {
+            if query.matches(&pulse) {
+                page.total += 1;
+                if index >= cursor && !full {
+                    page.pulses.push(pulse);
+                    page.next_cursor = index + 1;
+                    full = page.pulses.len() >= limit;
+                }
+            }
+            index += 1;
+        }
+    }
+
+    // Every pulse up to the end of the log has been checked if the page is not full
+    if !full {
+        page.next_cursor = cmp::max(cursor, len);
+    }
+    if !filtered {
+        page.total = len;
+    }
+
+    Ok(page)
+}
+
+/// Records a pulse in the active session, along with the telemetry at the time of the pulse
+pub fn record_pulse(pulse: Pulse, telemetry: Telemetry, time: Option<f64>) {
+    let mut sessions = SESSIONS.lock().unwrap();
+    let session = match sessions.active {
+        Some(ref mut session) => session,
+        None => {
+            println!("No session is recording, pulse discarded: {:?}", pulse);
+            return;
+        },
+    };
+
+    let value = PulseWithTelemetry {
+        session: session.info.name.clone(),
+        telemetry: telemetry,
+        pulse: pulse,
+        time: time,
+    };
+
+    if let Err(e) = session.pulses.append(&value) {
+        println!("Failed to store pulse: {}", e);
+        return;
+    }
+    stream::publish_pulse(&value);
+
+    if session.recent.len() >= RECENT_PULSES {
+        session.recent.pop_front();
+    }
+    session.recent.push_back(value);
+}
+
+/// Periodically records the telemetry of the vehicle in the active session
+fn track_recorder() {
+    loop {
+        thread::sleep(Duration::from_millis(TRACK_INTERVAL_MS));
+
+        let telemetry = mavlink_handler::get_telemetry();
+        let time = now();
+
+        let mut sessions = SESSIONS.lock().unwrap();
+        let session = match sessions.active {
+            Some(ref mut session) => session,
+            None => continue,
+        };
+
+        // The origin may not be known when the session starts, or may be changed during the
+        // session.
+        if telemetry.origin != session.info.origin {
+            session.info.origin = telemetry.origin;
+            session.save_info();
+        }
+
+        if telemetry.link.position_age.is_none() {
+            continue;
+        }
+
+        let point = TrackPoint {
+            time: time.as_secs() as f64 + time.subsec_nanos() as f64 / 1e9,
+            telemetry: telemetry,
+        };
+        if let Err(e) = session.track.append(&point) {
+            println!("Failed to store track: {}", e);
+        }
+    }
+}
+
+/// Session names are used as directory names, so only allow a safe set of characters
+fn validate_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty() && name.len() <= MAX_NAME_LENGTH && name.chars().all(|c| {
+        match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '_' => true,
+            _ => false,
+        }
+    });
+
+    if valid {
+        Ok(())
+    }
+    else {
+        Err(format!("Invalid session name `{}`: names must be 1 to {} characters long, and contain \
+            only letters, numbers, `-` and `_`", name, MAX_NAME_LENGTH))
+    }
+}
+
+fn read_info(dir: &Path) -> Result<SessionInfo, String> {
+    let path = dir.join(INFO_FILE);
+    let result = File::open(&path).map(|mut r| serde_json::from_reader(&mut r));
+    let mut info: SessionInfo = match result {
+        Ok(Ok(info)) => info,
+        Ok(Err(e)) => return Err(format!("Failed to parse `{}`: {}", path.display(), e)),
+        Err(e) => return Err(format!("Unable to access `{}`: {}", path.display(), e)),
+    };
+
+    info.pulse_count = storage::count(dir, PULSE_LOG).unwrap_or(0);
+    info.track_count = storage::count(dir, TRACK_LOG).unwrap_or(0);
+    Ok(info)
+}
+
+/// Writes the session info, replacing the existing file only once the new file has been written
+fn write_info(dir: &Path, info: &SessionInfo) -> Result<(), String> {
+    let path = dir.join(INFO_FILE);
+    let temp_path = dir.join(format!("{}.tmp", INFO_FILE));
+
+    try!(File::create(&temp_path)
+        .map_err(|e| e.to_string())
+        .and_then(|mut w| serde_json::to_writer_pretty(&mut w, info).map_err(|e| e.to_string())));
+    fs::rename(&temp_path, &path).map_err(|e| e.to_string())
+}
+
+/// Opens a log of a session for reading. The log is opened read only, so reading a session never
+/// creates or modifies its files, and can be done while the session is recording.
+fn open_log(name: &str, dir: &Path, log: &str) -> Result<RecordLog, String> {
+    try!(validate_name(name));
+    if !dir.join(INFO_FILE).exists() {
+        return Err(format!("Unknown session `{}`", name));
+    }
+
+    RecordLog::open_read_only(dir, log)
+        .map_err(|e| format!("Failed to open session `{}`: {}", name, e))
+}
+
+/// Reads records from the log of a session that is not recording
+fn read_log<T: Deserialize>(name: &str, dir: &Path, log: &str, index: u64)
+    -> Result<Vec<T>, String>
+{
+    let mut log = try!(open_log(name, dir, log));
+    log.read(index, MAX_RECORDS_PER_REQUEST)
+        .map_err(|e| format!("Failed to read session `{}`: {}", name, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use common::signal::{Pulse, Timestamp};
+
+    use mavlink_handler;
+    use pulse_handler::PulseWithTelemetry;
+    use storage::RecordLog;
+
+    use super::{PulseQuery, query_log};
+
+    const FREQ_A: f32 = 150000000.0;
+    const FREQ_B: f32 = 151000000.0;
+
+    /// Creates a pulse log of 10 pulses detected at times 0..10, alternating between `FREQ_A` and
+    /// `FREQ_B`. Pulse 3 was recorded without a detection time.
+    fn create_log(name: &str) -> RecordLog {
+        let dir: PathBuf = env::temp_dir().join(format!("telemetry_host_session_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut log = RecordLog::open(&dir, "pulses").unwrap();
+        for i in 0..10 {
+            let mut telemetry = mavlink_handler::get_telemetry();
+            telemetry.time = Some(i as f64);
+            let pulse = PulseWithTelemetry {
+                session: "test".into(),
+                telemetry: telemetry,
+                pulse: Pulse {
+                    freq: if i % 2 == 0 { FREQ_A } else { FREQ_B },
+                    signal_strength: 0.5,
+                    gain: 16,
+                    timestamp: Timestamp::now(),
+                },
+                time: if i == 3 { None } else { Some(i as f64) },
+            };
+            log.append(&pulse).unwrap();
+        }
+        log
+    }
+
+    fn query() -> PulseQuery {
+        PulseQuery {
+            session: None,
+            start: None,
+            end: None,
+            freq: None,
+            freq_tolerance: None,
+            min_signal_strength: None,
+            limit: None,
+            cursor: None,
+        }
+    }
+
+    /// Returns the detection times of the pulses in the page, the next cursor and the total
+    fn run(log: &mut RecordLog, query: &PulseQuery) -> (Vec<f64>, u64, u64) {
+        let page = query_log(log, 10, "test".into(), query).unwrap();
+        let times = page.pulses.iter().map(|p| p.telemetry.time.unwrap()).collect();
+        (times, page.next_cursor, page.total)
+    }
+
+    #[test]
+    fn unfiltered_pages() {
+        let mut log = create_log("unfiltered_pages");
+
+        let first = PulseQuery { limit: Some(4), ..query() };
+        assert_eq!(run(&mut log, &first), (vec![0.0, 1.0, 2.0, 3.0], 4, 10));
+
+        // The last page isn't full, so the cursor moves to the end of the log
+        let last = PulseQuery { limit: Some(4), cursor: Some(8), ..query() };
+        assert_eq!(run(&mut log, &last), (vec![8.0, 9.0], 10, 10));
+
+        let past_end = PulseQuery { cursor: Some(12), ..query() };
+        assert_eq!(run(&mut log, &past_end), (vec![], 12, 10));
+    }
+
+    #[test]
+    fn filtered_pages() {
+        let mut log = create_log("filtered_pages");
+
+        // A full page continues from the pulse after the last one returned
+        let first = PulseQuery { freq: Some(FREQ_A), limit: Some(2), ..query() };
+        assert_eq!(run(&mut log, &first), (vec![0.0, 2.0], 3, 5));
+
+        let rest = PulseQuery { freq: Some(FREQ_A), cursor: Some(3), ..query() };
+        assert_eq!(run(&mut log, &rest), (vec![4.0, 6.0, 8.0], 10, 5));
+    }
+
+    #[test]
+    fn zero_limit() {
+        let mut log = create_log("zero_limit");
+
+        let unfiltered = PulseQuery { limit: Some(0), cursor: Some(2), ..query() };
+        assert_eq!(run(&mut log, &unfiltered), (vec![], 2, 10));
+
+        let filtered = PulseQuery { freq: Some(FREQ_B), limit: Some(0), ..query() };
+        assert_eq!(run(&mut log, &filtered), (vec![], 0, 5));
+    }
+
+    #[test]
+    fn time_range() {
+        let mut log = create_log("time_range");
+
+        // The end time is exclusive, and pulse 3 is matched by the time of its telemetry
+        let range = PulseQuery { start: Some(2.0), end: Some(5.0), ..query() };
+        assert_eq!(run(&mut log, &range), (vec![2.0, 3.0, 4.0], 10, 3));
+
+        let empty = PulseQuery { start: Some(4.0), end: Some(4.0), ..query() };
+        assert_eq!(run(&mut log, &empty), (vec![], 10, 0));
+    }
+
+    #[test]
+    fn freq_tolerance() {
+        let mut log = create_log("freq_tolerance");
+
+        let default = PulseQuery { freq: Some(FREQ_A + 500.0), ..query() };
+        assert_eq!(run(&mut log, &default).2, 5);
+
+        let narrow = PulseQuery { freq: Some(FREQ_A + 500.0), freq_tolerance: Some(100.0),
+            ..query() };
+        assert_eq!(run(&mut log, &narrow).2, 0);
+
+        let wide = PulseQuery { freq: Some(FREQ_A + 500.0), freq_tolerance: Some(2000000.0),
+            ..query() };
+        assert_eq!(run(&mut log, &wide).2, 10);
+    }
+
+    #[test]
+    fn short_log() {
+        let mut log = create_log("short_log");
+        assert!(query_log(&mut log, 12, "test".into(), &query()).is_err());
+        assert!(query_log(&mut log, 12, "test".into(), &PulseQuery { freq: Some(FREQ_A),
+            ..query() }).is_err());
+    }
+
+    #[test]
+    fn invalid_queries() {
+        assert!(query().validate().is_ok());
+        assert!(PulseQuery { start: Some(5.0), end: Some(2.0), ..query() }.validate().is_err());
+        assert!(PulseQuery { start: Some(::std::f64::NAN), ..query() }.validate().is_err());
+        assert!(PulseQuery { freq: Some(::std::f32::INFINITY), ..query() }.validate().is_err());
+        assert!(PulseQuery { freq_tolerance: Some(-1.0), ..query() }.validate().is_err());
+    }
+}
@@ -0,0 +1,412 @@
+//! Append-only storage for records that need to survive a restart of the telemetry host.
+//!
+//! Each log is stored as two files: `<name>.dat` contains the records as length prefixed json
+//! (using the same framing as the pulse server connection), and `<name>.idx` contains the offset
+//! of each record in the data file so that records can be read from any index without scanning the
+//! whole log.
+
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, BufReader, ErrorKind, SeekFrom};
+use std::path::Path;
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use serde::{Serialize, Deserialize};
+use serde_json;
+
+/// The maximum size of a single record
+const MAX_RECORD_SIZE: u64 = 1 << 20;
+
+/// The size of the length prefix of each record, and of each entry in the index
+const HEADER_SIZE: u64 = 8;
+
+pub struct RecordLog {
+    data: File,
+    index: File,
+    /// The number of records in the log
+    count: u64,
+    /// The offset of the end of the last complete record in the data file
+    data_len: u64,
+    /// The offsets of complete records at the end of the data file that are missing from the
+    /// index. Only read only logs have these, other logs add them to the index when opened.
+    unindexed: Vec<u64>,
+    read_only: bool,
+}
+
+/// The complete records found in a log by `scan`
+struct Scan {
+    /// The number of records in the index that are complete
+    indexed: u64,
+    /// The offsets of the complete records after the last indexed record
+    unindexed: Vec<u64>,
+    /// The offset of the end of the last complete record
+    end: u64,
+}
+
+impl RecordLog {
+    /// Opens (or creates) the log `name` in the directory `dir`. Any partially written records
+    /// at the end of the log (e.g. from a crash while writing) are discarded, and records missing
+    /// from the index are added back.
+    pub fn open(dir: &Path, name: &str) -> io::Result<RecordLog> {
+        try!(fs::create_dir_all(dir));
+
+        let open = |extension: &str| {
+            OpenOptions::new().read(true).append(true).create(true)
+                .open(dir.join(format!("{}.{}", name, extension)))
+        };
+
+        let mut log = RecordLog {
+            data: try!(open("dat")),
+            index: try!(open("idx")),
+            count: 0,
+            data_len: 0,
+            unindexed: vec![],
+            read_only: false,
+        };
+        try!(log.recover());
+
+        Ok(log)
+    }
+
+    /// Opens the existing log `name` in the directory `dir` for reading. Nothing is created,
+    /// truncated or recovered: partially written records are ignored, and records missing from the
+    /// index are found without adding them to it.
+    pub fn open_read_only(dir: &Path, name: &str) -> io::Result<RecordLog> {
+        let open = |extension: &str| File::open(dir.join(format!("{}.{}", name, extension)));
+
+        let mut data = try!(open("dat"));
+        let mut index = try!(open("idx"));
+        let scan = try!(scan(&mut data, &mut index));
+
+        Ok(RecordLog {
+            data: data,
+            index: index,
+            count: scan.indexed + scan.unindexed.len() as u64,
+            data_len: scan.end,
+            unindexed: scan.unindexed,
+            read_only: true,
+        })
+    }
+
+    /// The number of records in the log
+    pub fn len(&self) -> u64 {
+        self.count
+    }
+
+    /// Appends a record to the log, returning its index
+    pub fn append<T: Serialize>(&mut self, value: &T) -> io::Result<u64> {
+        if self.read_only {
+            return Err(io::Error::new(ErrorKind::PermissionDenied, "The record log is read only"));
+        }
+
+        let bytes = try!(serde_json::to_vec(value)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e)));
+
+        let mut record = Vec::with_capacity(HEADER_SIZE as usize + bytes.len());
+        try!(record.write_u64::<LittleEndian>(bytes.len() as u64));
+        record.extend_from_slice(&bytes);
+
+        // The data is written before the index, so that a crash can at worst leave a record that
+        // is missing from the index (which is fixed by `recover`).
+        try!(self.data.write_all(&record));
+        try!(self.index.write_u64::<LittleEndian>(self.data_len));
+
+        let index = self.count;
+        self.data_len += record.len() as u64;
+        self.count += 1;
+        Ok(index)
+    }
+
+    /// Reads up to `max` records starting from the record at `start`
+    pub fn read<T: Deserialize>(&mut self, start: u64, max: usize) -> io::Result<Vec<T>> {
+        if start >= self.count || max == 0 {
+            return Ok(vec![]);
+        }
+        let count = cmp::min(self.count - start, max as u64);
+
+        let indexed = self.count - self.unindexed.len() as u64;
+        let offset = if start < indexed {
+            try!(self.index.seek(SeekFrom::Start(start * HEADER_SIZE)));
+            try!(self.index.read_u64::<LittleEndian>())
+        }
+        else {
+            self.unindexed[(start - indexed) as usize]
+        };
+        try!(self.data.seek(SeekFrom::Start(offset)));
+
+        let mut reader = BufReader::new(&mut self.data);
+        let mut buffer = vec![];
+        let mut records = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            try!(read_record(&mut reader, &mut buffer));
+            records.push(try!(serde_json::from_slice(&buffer)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))));
+        }
+
+        Ok(records)
+    }
+
+    /// Reconciles the index with the data file after opening the log
+    fn recover(&mut self) -> io::Result<()> {
+        let index_len = try!(self.index.metadata()).len();
+        let data_len = try!(self.data.metadata()).len();
+        let scan = try!(scan(&mut self.data, &mut self.index));
+
+        let mut count = scan.indexed;
+        let end = scan.end;
+        if count * HEADER_SIZE != index_len || end != data_len || !scan.unindexed.is_empty() {
+            println!("Recovering record log: {} indexed records, {} unindexed records, {} bytes \
+                discarded", count, scan.unindexed.len(), data_len - end);
+        }
+
+        // Index any complete records that were written after the last indexed record
+        try!(self.data.set_len(end));
+        try!(self.index.set_len(count * HEADER_SIZE));
+        for offset in scan.unindexed {
+            try!(self.index.write_u64::<LittleEndian>(offset));
+            count += 1;
+        }
+
+        self.count = count;
+        self.data_len = end;
+        Ok(())
+    }
+}
+
+/// Finds the complete records in a log without modifying it
+fn scan(data: &mut File, index: &mut File) -> io::Result<Scan> {
+    let index_len = try!(index.metadata()).len();
+    let data_len = try!(data.metadata()).len();
+    let mut buffer = vec![];
+
+    // Find the last indexed record that is complete
+    let mut count = index_len / HEADER_SIZE;
+    let mut end = 0;
+    while count > 0 {
+        try!(index.seek(SeekFrom::Start((count - 1) * HEADER_SIZE)));
+        let offset = try!(index.read_u64::<LittleEndian>());
+
+        if offset < data_len {
+            try!(data.seek(SeekFrom::Start(offset)));
+            if read_record(data, &mut buffer).is_ok() {
+                end = offset + HEADER_SIZE + buffer.len() as u64;
+                break;
+            }
+        }
+        count -= 1;
+    }
+
+    // Find any complete records that were written after the last indexed record
+    let mut unindexed = vec![];
+    try!(data.seek(SeekFrom::Start(end)));
+    {
+        let mut reader = BufReader::new(&mut *data);
+        while read_record(&mut reader, &mut buffer).is_ok() {
+            unindexed.push(end);
+            end += HEADER_SIZE + buffer.len() as u64;
+        }
+    }
+
+    Ok(Scan { indexed: count, unindexed: unindexed, end: end })
+}
+
+/// Returns the number of records in the log `name` in the directory `dir`, without opening it
+pub fn count(dir: &Path, name: &str) -> io::Result<u64> {
+    let metadata = try!(fs::metadata(dir.join(format!("{}.idx", name))));
+    Ok(metadata.len() / HEADER_SIZE)
+}
+
+fn read_record<R: Read>(reader: &mut R, buffer: &mut Vec<u8>) -> io::Result<()> {
+    let size = try!(reader.read_u64::<LittleEndian>());
+    if size > MAX_RECORD_SIZE {
+        return Err(io::Error::new(ErrorKind::InvalidData,
+            format!("Record too large ({} bytes)", size)));
+    }
+
+    buffer.clear();
+    buffer.resize(size as usize, 0);
+    reader.read_exact(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::prelude::*;
+    use std::path::PathBuf;
+
+    use byteorder::{WriteBytesExt, LittleEndian};
+
+    use super::{RecordLog, HEADER_SIZE, count};
+
+    /// Returns an empty directory for a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("telemetry_host_storage_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn file_len(dir: &PathBuf, file: &str) -> u64 {
+        fs::metadata(dir.join(file)).unwrap().len()
+    }
+
+    /// Creates a log containing the records 0..n, returning the length of the data file
+    fn create_log(dir: &PathBuf, n: u64) -> u64 {
+        let mut log = RecordLog::open(dir, "log").unwrap();
+        for i in 0..n {
+            assert_eq!(log.append(&(i * 100)).unwrap(), i);
+        }
+        file_len(dir, "log.dat")
+    }
+
+    fn read_all(log: &mut RecordLog) -> Vec<u64> {
+        let len = log.len() as usize;
+        log.read(0, len + 1).unwrap()
+    }
+
+    #[test]
+    fn append_and_reopen() {
+        let dir = test_dir("append_and_reopen");
+        let data_len = create_log(&dir, 5);
+
+        let mut log = RecordLog::open(&dir, "log").unwrap();
+        assert_eq!(log.len(), 5);
+        assert_eq!(count(&dir, "log").unwrap(), 5);
+        assert_eq!(read_all(&mut log), vec![0, 100, 200, 300, 400]);
+        assert_eq!(log.read::<u64>(3, 10).unwrap(), vec![300, 400]);
+        assert!(log.read::<u64>(5, 10).unwrap().is_empty());
+
+        // Nothing is changed by a clean reopen
+        assert_eq!(file_len(&dir, "log.dat"), data_len);
+        assert_eq!(file_len(&dir, "log.idx"), 5 * HEADER_SIZE);
+    }
+
+    #[test]
+    fn torn_data_tail() {
+        let dir = test_dir("torn_data_tail");
+        let data_len = create_log(&dir, 3);
+
+        // A crash while writing a record leaves a length prefix with only part of the record
+        {
+            let mut data = OpenOptions::new().append(true).open(dir.join("log.dat")).unwrap();
+            data.write_u64::<LittleEndian>(100).unwrap();
+            data.write_all(b"[1, 2").unwrap();
+        }
+
+        let mut log = RecordLog::open(&dir, "log").unwrap();
+        assert_eq!(log.len(), 3);
+        assert_eq!(file_len(&dir, "log.dat"), data_len);
+        assert_eq!(read_all(&mut log), vec![0, 100, 200]);
+
+        // New records are appended after the last complete record
+        assert_eq!(log.append(&300).unwrap(), 3);
+        assert_eq!(read_all(&mut log), vec![0, 100, 200, 300]);
+    }
+
+    #[test]
+    fn torn_length_prefix() {
+        let dir = test_dir("torn_length_prefix");
+        let data_len = create_log(&dir, 2);
+
+        {
+            let mut data = OpenOptions::new().append(true).open(dir.join("log.dat")).unwrap();
+            data.write_all(&[5, 0, 0]).unwrap();
+        }
+
+        let mut log = RecordLog::open(&dir, "log").unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(file_len(&dir, "log.dat"), data_len);
+        assert_eq!(read_all(&mut log), vec![0, 100]);
+    }
+
+    #[test]
+    fn torn_index() {
+        let dir = test_dir("torn_index");
+        create_log(&dir, 4);
+
+        // A crash between writing the data and the index leaves the last entries missing, with
+        // part of an entry written
+        {
+            let index = OpenOptions::new().write(true).open(dir.join("log.idx")).unwrap();
+            index.set_len(2 * HEADER_SIZE + 3).unwrap();
+        }
+
+        let mut log = RecordLog::open(&dir, "log").unwrap();
+        assert_eq!(log.len(), 4);
+        assert_eq!(file_len(&dir, "log.idx"), 4 * HEADER_SIZE);
+        assert_eq!(read_all(&mut log), vec![0, 100, 200, 300]);
+        assert_eq!(log.read::<u64>(3, 1).unwrap(), vec![300]);
+    }
+
+    #[test]
+    fn missing_index() {
+        let dir = test_dir("missing_index");
+        create_log(&dir, 3);
+        fs::remove_file(dir.join("log.idx")).unwrap();
+
+        let mut log = RecordLog::open(&dir, "log").unwrap();
+        assert_eq!(read_all(&mut log), vec![0, 100, 200]);
+    }
+
+    #[test]
+    fn index_past_data() {
+        let dir = test_dir("index_past_data");
+        let data_len = create_log(&dir, 3);
+
+        // The data file is missing the end of the last indexed record
+        {
+            let data = OpenOptions::new().write(true).open(dir.join("log.dat")).unwrap();
+            data.set_len(data_len - 2).unwrap();
+        }
+
+        let mut log = RecordLog::open(&dir, "log").unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(file_len(&dir, "log.idx"), 2 * HEADER_SIZE);
+        assert_eq!(read_all(&mut log), vec![0, 100]);
+
+        assert_eq!(log.append(&200).unwrap(), 2);
+        assert_eq!(read_all(&mut log), vec![0, 100, 200]);
+    }
+
+    #[test]
+    fn read_only() {
+        let dir = test_dir("read_only");
+        create_log(&dir, 3);
+
+        let mut log = RecordLog::open_read_only(&dir, "log").unwrap();
+        assert_eq!(log.len(), 3);
+        assert_eq!(read_all(&mut log), vec![0, 100, 200]);
+        assert!(log.append(&300).is_err());
+        assert_eq!(file_len(&dir, "log.idx"), 3 * HEADER_SIZE);
+
+        // Nothing is created for a log that doesn't exist
+        assert!(RecordLog::open_read_only(&dir, "missing").is_err());
+        assert!(!dir.join("missing.dat").exists());
+        assert!(!dir.join("missing.idx").exists());
+    }
+
+    #[test]
+    fn read_only_torn_log() {
+        let dir = test_dir("read_only_torn_log");
+        let data_len = create_log(&dir, 4);
+
+        {
+            let index = OpenOptions::new().write(true).open(dir.join("log.idx")).unwrap();
+            index.set_len(HEADER_SIZE + 5).unwrap();
+
+            let mut data = OpenOptions::new().append(true).open(dir.join("log.dat")).unwrap();
+            data.write_u64::<LittleEndian>(100).unwrap();
+        }
+
+        // The unindexed records are readable, and the files are left as they were
+        let mut log = RecordLog::open_read_only(&dir, "log").unwrap();
+        assert_eq!(log.len(), 4);
+        assert_eq!(read_all(&mut log), vec![0, 100, 200, 300]);
+        assert_eq!(log.read::<u64>(2, 10).unwrap(), vec![200, 300]);
+        assert_eq!(log.read::<u64>(0, 2).unwrap(), vec![0, 100]);
+
+        assert_eq!(file_len(&dir, "log.idx"), HEADER_SIZE + 5);
+        assert_eq!(file_len(&dir, "log.dat"), data_len + HEADER_SIZE);
+    }
+}
@@ -0,0 +1,414 @@
+//! Pushes new pulses and telemetry to clients as Server-Sent Events, so that clients do not need to
+//! poll the REST API.
+//!
+//! Rocket buffers streamed responses, so the events are served by a small HTTP server on its own
+//! port (`stream_port`), with a thread for each client. Clients connect with
+//! `GET /stream?channels=pulses,telemetry&rate=<hz>`.
+
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde_json;
+
+use mavlink_handler;
+use pulse_handler::PulseWithTelemetry;
+
+/// The maximum number of clients that can be connected at once
+const MAX_CLIENTS: usize = 16;
+
+/// The default and maximum rates (in Hz) that telemetry is sent at
+const DEFAULT_TELEMETRY_RATE: f64 = 1.0;
+const MAX_TELEMETRY_RATE: f64 = 20.0;
+
+/// How often a comment is sent when there are no events, so that clients (and proxies) can tell
+/// the connection is still alive
+const KEEP_ALIVE_SECS: u64 = 15;
+
+/// The maximum length of the request line and headers of a request
+const MAX_REQUEST_LENGTH: usize = 8192;
+
+/// Time allowed for a client to send its request
+const READ_TIMEOUT_SECS: u64 = 10;
+
+/// Time allowed for a write to a client to complete. Clients that stop reading (or disappear
+/// without closing the connection) are disconnected once their send buffer fills.
+const WRITE_TIMEOUT_SECS: u64 = 10;
+
+/// The number of pulses queued for a client before it is disconnected for not keeping up
+const MAX_QUEUED_PULSES: usize = 256;
+
+/// The events that a client can subscribe to
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Channel {
+    /// Every pulse recorded in the active session, as it is received
+    Pulses,
+    /// The vehicle's telemetry, at the client's requested rate
+    Telemetry,
+}
+
+impl Channel {
+    fn parse(name: &str) -> Result<Channel, String> {
+        match name {
+            "pulses" => Ok(Channel::Pulses),
+            "telemetry" => Ok(Channel::Telemetry),
+            _ => Err(format!("Unknown channel `{}`: expected `pulses` or `telemetry`", name)),
+        }
+    }
+}
+
+struct Subscription {
+    channels: Vec<Channel>,
+    /// The time between telemetry events
+    telemetry_interval: Duration,
+}
+
+lazy_static! {
+    /// The clients subscribed to the pulses channel
+    static ref PULSE_SUBSCRIBERS: Mutex<Vec<SyncSender<PulseWithTelemetry>>> =
+        Mutex::new(vec![]);
+
+    static ref CLIENT_COUNT: Mutex<usize> = Mutex::new(0);
+}
+
+/// One of the `MAX_CLIENTS` client slots, released when the client's thread exits
+struct ClientSlot;
+
+impl ClientSlot {
+    fn acquire() -> Option<ClientSlot> {
+        let mut count = CLIENT_COUNT.lock().unwrap();
+        if *count >= MAX_CLIENTS {
+            return None;
+        }
+        *count += 1;
+        Some(ClientSlot)
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        *CLIENT_COUNT.lock().unwrap() -= 1;
+    }
+}
+
+/// Starts accepting stream clients on the address
+pub fn init(address: &str, port: u16) -> Result<(), String> {
+    let listener = try!(TcpListener::bind((address, port))
+        .map_err(|e| format!("Failed to bind stream to {}:{}: {}", address, port, e)));
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    println!("Failed to accept stream client: {}", e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = set_timeouts(&stream) {
+                println!("Failed to set stream client timeouts: {}", e);
+                continue;
+            }
+
+            // Clients over the limit are turned away without reading their request, so that they
+            // don't tie up a thread
+            match ClientSlot::acquire() {
+                Some(slot) => {
+                    thread::spawn(move || handle_client(stream, slot));
+                },
+                None => {
+                    write_error(&mut stream, "503 Service Unavailable", "Too many stream clients")
+                },
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn set_timeouts(stream: &TcpStream) -> io::Result<()> {
+    try!(stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECS))));
+    stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_SECS)))
+}
+
+/// Sends a pulse to all of the clients subscribed to the pulses channel
+pub fn publish_pulse(pulse: &PulseWithTelemetry) {
+    // Clients that have disconnected are removed once their receiver has been dropped. Clients
+    // that have fallen too far behind are removed too, which closes their connection once they
+    // have sent the pulses that are already queued.
+    PULSE_SUBSCRIBERS.lock().unwrap().retain(|sender| {
+        match sender.try_send(pulse.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                println!("Stream client is not keeping up with pulses, disconnecting");
+                false
+            },
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    });
+}
+
+/// Serves a client, holding one of the client slots until it disconnects
+fn handle_client(mut stream: TcpStream, _slot: ClientSlot) {
+    let subscription = match read_request(&stream) {
+        Ok(subscription) => subscription,
+        Err((status, reason)) => return write_error(&mut stream, status, &reason),
+    };
+
+    let pulses = if subscription.channels.contains(&Channel::Pulses) {
+        let (sender, receiver) = sync_channel(MAX_QUEUED_PULSES);
+        PULSE_SUBSCRIBERS.lock().unwrap().push(sender);
+        Some(receiver)
+    }
+    else {
+        None
+    };
+
+    if let Err(e) = send_events(&mut stream, &subscription, pulses) {
+        if e.kind() != io::ErrorKind::BrokenPipe && e.kind() != io::ErrorKind::ConnectionReset {
+            println!("Stream client disconnected: {}", e);
+        }
+    }
+}
+
+fn write_error(stream: &mut TcpStream, status: &str, reason: &str) {
+    let response = format!("HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\
+        Connection: close\r\n\r\n{}", status, reason.len(), reason);
+    let _ = stream.write_all(response.as_bytes());
+}
+
+/// Parses the request line of the client's request, returning the status and reason if the request
+/// is invalid.
+fn read_request<R: Read>(stream: R) -> Result<Subscription, (&'static str, String)> {
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_LENGTH as u64));
+
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return Err(("400 Bad Request", "Invalid request".into()));
+    }
+
+    // The headers are not used, but must be read before responding
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) => return Err(("400 Bad Request", "Incomplete request".into())),
+            Ok(_) if line.trim().is_empty() => break,
+            Ok(_) => {},
+            Err(_) => return Err(("400 Bad Request", "Invalid request".into())),
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return Err(("400 Bad Request", "Invalid request".into())),
+    };
+
+    if method != "GET" {
+        return Err(("405 Method Not Allowed", format!("Unsupported method `{}`", method)));
+    }
+
+    let mut split = target.splitn(2, '?');
+    if split.next() != Some("/stream") {
+        return Err(("404 Not Found", format!("Unknown path `{}`", target)));
+    }
+
+    parse_query(split.next().unwrap_or("")).map_err(|e| ("400 Bad Request", e))
+}
+
+fn parse_query(query: &str) -> Result<Subscription, String> {
+    let mut channels = vec![Channel::Pulses, Channel::Telemetry];
+    let mut rate = DEFAULT_TELEMETRY_RATE;
+
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let mut split = pair.splitn(2, '=');
+        let key = split.next().unwrap_or("");
+        let value = split.next().unwrap_or("");
+
+        match key {
+            "channels" => {
+                channels = vec![];
+                // Some clients encode the separator
+                let value = value.replace("%2C", ",").replace("%2c", ",");
+                for name in value.split(',').filter(|name| !name.is_empty()) {
+                    let channel = try!(Channel::parse(name));
+                    if !channels.contains(&channel) {
+                        channels.push(channel);
+                    }
+                }
+                if channels.is_empty() {
+                    return Err("At least one channel must be specified".into());
+                }
+            },
+            "rate" => {
+                rate = try!(value.parse().map_err(|_| format!("Invalid rate `{}`", value)));
+                if !(rate > 0.0 && rate <= MAX_TELEMETRY_RATE) {
+                    return Err(format!("Invalid rate `{}`: the rate must be above 0 and at most \
+                        {} Hz", value, MAX_TELEMETRY_RATE));
+                }
+            },
+            _ => return Err(format!("Unknown parameter `{}`", key)),
+        }
+    }
+
+    let interval_ms = (1000.0 / rate) as u64;
+    Ok(Subscription {
+        channels: channels,
+        telemetry_interval: Duration::from_millis(interval_ms),
+    })
+}
+
+/// Sends events to the client until it disconnects
+fn send_events(stream: &mut TcpStream, subscription: &Subscription,
+    pulses: Option<Receiver<PulseWithTelemetry>>) -> io::Result<()>
+{
+    try!(stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+        Cache-Control: no-cache\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n"));
+    try!(stream.flush());
+
+    let telemetry = subscription.channels.contains(&Channel::Telemetry);
+    let keep_alive = Duration::from_secs(KEEP_ALIVE_SECS);
+
+    let mut next_telemetry = Instant::now();
+    let mut last_event = Instant::now();
+    loop {
+        let now = Instant::now();
+        if telemetry && now >= next_telemetry {
+            try!(send_event(stream, "telemetry", &mavlink_handler::get_telemetry()));
+            next_telemetry += subscription.telemetry_interval;
+            if next_telemetry < now {
+                // Skip the updates that were missed rather than sending them all at once
+                next_telemetry = now + subscription.telemetry_interval;
+            }
+            last_event = now;
+        }
+        else if now.duration_since(last_event) >= keep_alive {
+            try!(stream.write_all(b": keep-alive\n\n"));
+            try!(stream.flush());
+            last_event = now;
+        }
+
+        let mut timeout = last_event + keep_alive;
+        if telemetry && next_telemetry < timeout {
+            timeout = next_telemetry;
+        }
+        let wait = if timeout > now { timeout.duration_since(now) } else { Duration::from_secs(0) };
+
+        match pulses {
+            Some(ref receiver) => {
+                match receiver.recv_timeout(wait) {
+                    Ok(pulse) => {
+                        try!(send_event(stream, "pulse", &pulse));
+                        last_event = Instant::now();
+                    },
+                    Err(RecvTimeoutError::Timeout) => {},
+                    Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                }
+            },
+            None => thread::sleep(wait),
+        }
+    }
+}
+
+fn send_event<T: Serialize>(stream: &mut TcpStream, event: &str, value: &T) -> io::Result<()> {
+    let data = try!(serde_json::to_string(value)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)));
+    try!(write!(stream, "event: {}\ndata: {}\n\n", event, data));
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Channel, MAX_TELEMETRY_RATE, parse_query, read_request};
+
+    fn channels(query: &str) -> Vec<Channel> {
+        match parse_query(query) {
+            Ok(subscription) => subscription.channels,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Returns the status of the response to a request
+    fn request_status(request: &str) -> Option<&'static str> {
+        read_request(request.as_bytes()).err().map(|(status, _)| status)
+    }
+
+    #[test]
+    fn default_query() {
+        let subscription = parse_query("").unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(subscription.channels, vec![Channel::Pulses, Channel::Telemetry]);
+        assert_eq!(subscription.telemetry_interval, Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn channel_lists() {
+        assert_eq!(channels("channels=telemetry"), vec![Channel::Telemetry]);
+        assert_eq!(channels("channels=telemetry,pulses"),
+            vec![Channel::Telemetry, Channel::Pulses]);
+
+        // Encoded separators
+        assert_eq!(channels("channels=telemetry%2Cpulses"),
+            vec![Channel::Telemetry, Channel::Pulses]);
+        assert_eq!(channels("channels=pulses%2ctelemetry"),
+            vec![Channel::Pulses, Channel::Telemetry]);
+
+        // Duplicates and empty names are ignored
+        assert_eq!(channels("channels=pulses,pulses,"), vec![Channel::Pulses]);
+
+        assert!(parse_query("channels=").is_err());
+        assert!(parse_query("channels=%2C").is_err());
+        assert!(parse_query("channels=pulses,position").is_err());
+    }
+
+    #[test]
+    fn telemetry_rates() {
+        let fast = parse_query("rate=20").unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(fast.telemetry_interval, Duration::from_millis(50));
+        let slow = parse_query("channels=telemetry&rate=0.5").unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(slow.telemetry_interval, Duration::from_millis(2000));
+
+        let too_fast = format!("rate={}", MAX_TELEMETRY_RATE + 1.0);
+        for query in &["rate=0", "rate=-1", "rate=NaN", "rate=inf", "rate=", "rate=fast",
+            &too_fast[..]]
+        {
+            assert!(parse_query(query).is_err(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn unknown_parameter() {
+        assert!(parse_query("channels=pulses&since=10").is_err());
+        assert!(parse_query("channel=pulses").is_err());
+    }
+
+    #[test]
+    fn requests() {
+        let request = "GET /stream?channels=pulses&rate=2 HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        match read_request(request.as_bytes()) {
+            Ok(subscription) => assert_eq!(subscription.channels, vec![Channel::Pulses]),
+            Err((status, reason)) => panic!("{}: {}", status, reason),
+        }
+        assert_eq!(request_status("GET /stream HTTP/1.1\r\n\r\n"), None);
+
+        assert_eq!(request_status("GET /pulses HTTP/1.1\r\n\r\n"), Some("404 Not Found"));
+        assert_eq!(request_status("GET /streams HTTP/1.1\r\n\r\n"), Some("404 Not Found"));
+        assert_eq!(request_status("POST /stream HTTP/1.1\r\n\r\n"),
+            Some("405 Method Not Allowed"));
+        assert_eq!(request_status("GET /stream?rate=0 HTTP/1.1\r\n\r\n"),
+            Some("400 Bad Request"));
+
+        // The headers must be complete
+        assert_eq!(request_status("GET /stream HTTP/1.1\r\nHost: localhost\r\n"),
+            Some("400 Bad Request"));
+        assert_eq!(request_status(""), Some("400 Bad Request"));
+    }
+}
@@ -0,0 +1,150 @@
+//! High level control of the vehicle. Each action is sent as a tracked COMMAND_LONG after checking
+//! that it is safe to do so given the latest telemetry.
+
+use std::f32;
+
+use commands::{self, CommandLong, CommandState};
+use flight_mode::{self, MAV_MODE_FLAG_CUSTOM_MODE_ENABLED};
+use mavlink_handler::{self, LinkState, Telemetry, STALE_TIMEOUT_SECS};
+
+const MAV_CMD_NAV_RETURN_TO_LAUNCH: u16 = 20;
+const MAV_CMD_NAV_LAND: u16 = 21;
+const MAV_CMD_NAV_TAKEOFF: u16 = 22;
+const MAV_CMD_DO_SET_MODE: u16 = 176;
+const MAV_CMD_COMPONENT_ARM_DISARM: u16 = 400;
+
+/// Magic value for param2 of MAV_CMD_COMPONENT_ARM_DISARM that forces the vehicle to disarm
+const FORCE_DISARM: f32 = 21196.0;
+
+/// GPS_RAW_INT fix type for a 3D fix
+const GPS_FIX_TYPE_3D_FIX: u8 = 3;
+
+/// The maximum altitude above home that the vehicle is considered to be landed at
+const LANDED_ALT_METERS: f32 = 1.0;
+
+/// The maximum height that a takeoff can be requested to
+const MAX_TAKEOFF_HEIGHT_METERS: f32 = 120.0;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Takeoff {
+    /// The height to climb to, in meters above the current position
+    pub height: f32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModeChange {
+    /// The name of the flight mode, e.g. `POSCTL` or `AUTO.LOITER`
+    pub mode: String,
+}
+
+fn check_link(telemetry: &Telemetry) -> Result<(), String> {
+    match telemetry.link.state {
+        LinkState::Connected => Ok(()),
+        state => Err(format!("The Mavlink link is {:?}", state)),
+    }
+}
+
+/// Checks that the position in the telemetry is recent and relative to a known origin, so that the
+/// altitudes used by the safety checks are real rather than defaults.
+fn check_position(telemetry: &Telemetry) -> Result<(), String> {
+    match telemetry.link.position_age {
+        Some(age) if age <= STALE_TIMEOUT_SECS => {},
+        Some(age) => return Err(format!("The last position is {:.1} s old", age)),
+        None => return Err("No position has been received".into()),
+    }
+
+    if telemetry.origin.is_none() {
+        return Err("The origin has not been set".into());
+    }
+    Ok(())
+}
+
+fn is_armed(telemetry: &Telemetry) -> bool {
+    telemetry.mode.as_ref().map_or(false, |mode| mode.armed)
+}
+
+fn send(command: u16, params: [f32; 7]) -> CommandState {
+    commands::submit(CommandLong { command: command, params: params })
+}
+
+pub fn arm() -> Result<CommandState, String> {
+    let telemetry = mavlink_handler::get_telemetry();
+    try!(check_link(&telemetry).map_err(|e| format!("Refusing to arm: {}", e)));
+
+    Ok(send(MAV_CMD_COMPONENT_ARM_DISARM, [1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]))
+}
+
+/// Disarms the vehicle. Unless `force` is set, this is refused if the vehicle is in the air.
+pub fn disarm(force: bool) -> Result<CommandState, String> {
+    let telemetry = mavlink_handler::get_telemetry();
+    try!(check_link(&telemetry).map_err(|e| format!("Refusing to disarm: {}", e)));
+
+    if !force {
+        try!(check_position(&telemetry).map_err(|e| format!("Refusing to disarm: {}", e)));
+    }
+
+    if !force && telemetry.relative_alt > LANDED_ALT_METERS {
+        return Err(format!("Refusing to disarm: the vehicle is {:.1} m above home",
+            telemetry.relative_alt));
+    }
+
+    let magic = if force { FORCE_DISARM } else { 0.0 };
+    Ok(send(MAV_CMD_COMPONENT_ARM_DISARM, [0.0, magic, 0.0, 0.0, 0.0, 0.0, 0.0]))
+}
+
+/// Takes off to the specified height above the current position
+pub fn takeoff(takeoff: Takeoff) -> Result<CommandState, String> {
+    let telemetry = mavlink_handler::get_telemetry();
+    try!(check_link(&telemetry).map_err(|e| format!("Refusing takeoff: {}", e)));
+    try!(check_position(&telemetry).map_err(|e| format!("Refusing takeoff: {}", e)));
+
+    if !(takeoff.height > 0.0 && takeoff.height <= MAX_TAKEOFF_HEIGHT_METERS) {
+        return Err(format!("Refusing takeoff: height must be between 0 and {} m",
+            MAX_TAKEOFF_HEIGHT_METERS));
+    }
+
+    match telemetry.gps {
+        Some(ref gps) if gps.fix_type >= GPS_FIX_TYPE_3D_FIX => {},
+        _ => return Err("Refusing takeoff: no 3D GPS fix".into()),
+    }
+
+    if !is_armed(&telemetry) {
+        return Err("Refusing takeoff: the vehicle is not armed".into());
+    }
+
+    let alt = telemetry.position[2] + takeoff.height;
+    Ok(send(MAV_CMD_NAV_TAKEOFF, [-1.0, 0.0, 0.0, f32::NAN, f32::NAN, f32::NAN, alt]))
+}
+
+/// Lands at the current position
+pub fn land() -> Result<CommandState, String> {
+    let telemetry = mavlink_handler::get_telemetry();
+    try!(check_link(&telemetry).map_err(|e| format!("Refusing to land: {}", e)));
+
+    Ok(send(MAV_CMD_NAV_LAND, [0.0, 0.0, 0.0, f32::NAN, f32::NAN, f32::NAN, f32::NAN]))
+}
+
+pub fn return_to_launch() -> Result<CommandState, String> {
+    let telemetry = mavlink_handler::get_telemetry();
+    try!(check_link(&telemetry).map_err(|e| format!("Refusing to return to launch: {}", e)));
+
+    Ok(send(MAV_CMD_NAV_RETURN_TO_LAUNCH, [0.0; 7]))
+}
+
+/// Holds the current position
+pub fn hold() -> Result<CommandState, String> {
+    set_mode(ModeChange { mode: "AUTO.LOITER".into() })
+}
+
+pub fn set_mode(change: ModeChange) -> Result<CommandState, String> {
+    let telemetry = mavlink_handler::get_telemetry();
+    try!(check_link(&telemetry).map_err(|e| format!("Refusing mode change: {}", e)));
+
+    let (main_mode, sub_mode) = match flight_mode::px4_mode(&change.mode) {
+        Some(mode) => mode,
+        None => return Err(format!("Unknown flight mode: `{}`", change.mode)),
+    };
+
+    Ok(send(MAV_CMD_DO_SET_MODE, [MAV_MODE_FLAG_CUSTOM_MODE_ENABLED as f32, main_mode as f32,
+        sub_mode as f32, 0.0, 0.0, 0.0, 0.0]))
+}